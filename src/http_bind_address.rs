@@ -0,0 +1,91 @@
+
+// Where a server instance listens: a TCP address, or the path of a Unix
+// domain socket for tools running on the same machine. Unix sockets are
+// only available on unix platforms, binding one elsewhere fails.
+//
+// Parsed from "[::]:80" or "unix:/run/game/http.sock"
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HttpBindAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+
+impl HttpBindAddress {
+
+    pub fn unix(path: impl Into<PathBuf>) -> Self {
+        return HttpBindAddress::Unix(path.into());
+    }
+
+}
+
+
+impl From<SocketAddr> for HttpBindAddress {
+    fn from(address: SocketAddr) -> Self {
+        return HttpBindAddress::Tcp(address);
+    }
+}
+
+
+impl From<PathBuf> for HttpBindAddress {
+    fn from(path: PathBuf) -> Self {
+        return HttpBindAddress::Unix(path);
+    }
+}
+
+
+impl FromStr for HttpBindAddress {
+    type Err = std::net::AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => return Ok(HttpBindAddress::unix(path)),
+            None => return Ok(HttpBindAddress::Tcp(s.parse()?)),
+        }
+    }
+}
+
+
+impl std::fmt::Display for HttpBindAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpBindAddress::Tcp(address) => write!(f, "{}", address),
+            HttpBindAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_socket_addr() {
+        let address: SocketAddr = "127.0.0.1:80".parse().unwrap();
+        assert_eq!(HttpBindAddress::from(address), HttpBindAddress::Tcp(address));
+    }
+
+    #[test]
+    fn parse() {
+        let address: SocketAddr = "[::1]:8080".parse().unwrap();
+        assert_eq!("[::1]:8080".parse::<HttpBindAddress>(), Ok(HttpBindAddress::Tcp(address)));
+        assert_eq!("unix:/tmp/game.sock".parse::<HttpBindAddress>(), Ok(HttpBindAddress::unix("/tmp/game.sock")));
+        assert_eq!("/tmp/game.sock".parse::<HttpBindAddress>().is_err(), true);
+    }
+
+    #[test]
+    fn display() {
+        let address: SocketAddr = "127.0.0.1:80".parse().unwrap();
+        assert_eq!(format!("{}", HttpBindAddress::from(address)), "127.0.0.1:80");
+        assert_eq!(format!("{}", HttpBindAddress::unix("/tmp/game.sock")), "unix:/tmp/game.sock");
+    }
+
+}
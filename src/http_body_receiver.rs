@@ -0,0 +1,222 @@
+/*
+An HttpBodyReceiver delivers the body of a large upload, e.g. a save file or a
+mod package, as it arrives instead of holding the request back until all of it
+has been read. HttpConnectionServer attaches one to requests with a body over
+HttpServerSettings::with_stream_body_threshold(), and to all chunked requests
+when a threshold is set. The request body itself is then empty.
+
+The body can be read in a background task and delivered to ECS when complete:
+
+    fn upload(world: &mut World, request: &Request<Bytes>, _params: &HttpPathParams) -> Result<Response<Bytes>, StatusCode> {
+        let body = HttpBodyReceiver::from_request(request).ok_or(StatusCode::LENGTH_REQUIRED)?;
+        let sender = HttpResponseSender::from_request(request).ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+        let task = AsyncComputeTaskPool::get().spawn(body.collect());
+        world.spawn((UploadTask(task), sender)); // Some other system polls the task and answers
+        return Ok(HttpResponseSender::deferred());
+    }
+
+...or consumed incrementally, from a system calling try_recv() every frame or
+from a task awaiting recv(). Only a few chunks are queued, so reading stops
+while the consumer falls behind. The size limits in HttpServerSettings apply
+as the data arrives; a body that is cut short or too large ends with
+Err(status) and the connection is closed after the response.
+
+A response sent early, e.g. 202 Accepted, is written once the body has been
+read. If every receiver is dropped the rest of the body is not read and the
+connection is closed after the response.
+
+See also: HttpBodySender
+*/
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use bevy::prelude::*;
+use smol::channel::{Receiver, Sender, TryRecvError};
+
+use http::{Request, StatusCode};
+use bytes::Bytes;
+
+
+// Sending end, kept by HttpConnectionServer while it reads the body
+pub(crate) struct HttpBodyFeed {
+    sender: Sender<Result<Bytes, StatusCode>>,
+    complete: Arc<AtomicBool>,
+    receivers: Receiver<()>,
+}
+
+
+impl HttpBodyFeed {
+
+    // Returns false if every HttpBodyReceiver has been dropped
+    pub async fn send(&self, chunk: Bytes) -> bool {
+        return self.sender.send(Ok(chunk)).await.is_ok();
+    }
+
+    // Returns once every HttpBodyReceiver has been dropped, even while the
+    // queue is empty
+    pub async fn abandoned(&self) {
+        let _ = self.receivers.recv().await;
+    }
+
+    // The whole body has been sent
+    pub fn finish(self) {
+        self.complete.store(true, Ordering::Release);
+    }
+
+    // The body was cut short. If the queue is full the receivers get
+    // 400 Bad Request once they have caught up instead.
+    pub fn fail(self, status: StatusCode) {
+        let _ = self.sender.try_send(Err(status));
+    }
+
+}
+
+
+#[derive(Component, Clone)]
+pub struct HttpBodyReceiver {
+    receiver: Receiver<Result<Bytes, StatusCode>>,
+    complete: Arc<AtomicBool>,
+    _alive: Sender<()>, // Nothing is sent, the channel closes when the last clone is dropped
+}
+
+
+impl HttpBodyReceiver {
+
+    pub(crate) fn channel(capacity: usize) -> (HttpBodyFeed, Self) {
+        let (sender, receiver) = smol::channel::bounded(capacity);
+        let complete = Arc::new(AtomicBool::new(false));
+        let (alive, receivers) = smol::channel::bounded(1);
+        return (
+            HttpBodyFeed { sender, complete: complete.clone(), receivers },
+            HttpBodyReceiver { receiver, complete, _alive: alive },
+        );
+    }
+
+    // The receiver for a streamed request body, None if the body was read in full.
+    // Clones share the same chunks, so only one of them should be consuming.
+    pub fn from_request(request: &Request<Bytes>) -> Option<Self> {
+        return request.extensions().get::<HttpBodyReceiver>().cloned();
+    }
+
+    pub fn is_streaming(request: &Request<Bytes>) -> bool {
+        return request.extensions().get::<HttpBodyReceiver>().is_some();
+    }
+
+    // The next chunk if one has arrived, None if not yet or if the body is
+    // finished, see is_finished()
+    pub fn try_recv(&self) -> Option<Result<Bytes, StatusCode>> {
+        match self.receiver.try_recv() {
+            Ok(chunk) => return Some(chunk),
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Closed) => return self.end(),
+        }
+    }
+
+    // Wait for the next chunk, None when the body is complete
+    pub async fn recv(&self) -> Option<Result<Bytes, StatusCode>> {
+        match self.receiver.recv().await {
+            Ok(chunk) => return Some(chunk),
+            Err(_) => return self.end(),
+        }
+    }
+
+    // True once every chunk has been received and the body has ended
+    pub fn is_finished(&self) -> bool {
+        return self.receiver.is_closed() && self.receiver.is_empty();
+    }
+
+    // Wait for the whole body
+    pub async fn collect(self) -> Result<Bytes, StatusCode> {
+        let mut body = Vec::new();
+        while let Some(chunk) = self.recv().await {
+            body.extend_from_slice(&chunk?);
+        }
+        return Ok(Bytes::from(body));
+    }
+
+    // Helper function for try_recv() and recv(), after the last chunk
+    fn end(&self) -> Option<Result<Bytes, StatusCode>> {
+        if self.complete.load(Ordering::Acquire) { return None; }
+        return Some(Err(StatusCode::BAD_REQUEST));
+    }
+
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use smol::future;
+
+    use super::*;
+
+    #[test]
+    fn from_request() {
+        let mut request = Request::new(Bytes::new());
+        assert_eq!(HttpBodyReceiver::is_streaming(&request), false);
+        let (_feed, receiver) = HttpBodyReceiver::channel(1);
+        request.extensions_mut().insert(receiver);
+        assert_eq!(HttpBodyReceiver::is_streaming(&request), true);
+        assert_eq!(HttpBodyReceiver::from_request(&request).is_some(), true);
+    }
+
+    #[test]
+    fn try_recv() {
+        let (feed, receiver) = HttpBodyReceiver::channel(2);
+        assert_eq!(receiver.try_recv(), None);
+        assert_eq!(future::block_on(feed.send(Bytes::from_static(b"one"))), true);
+        feed.finish();
+        assert_eq!(receiver.is_finished(), false);
+        assert_eq!(receiver.try_recv(), Some(Ok(Bytes::from_static(b"one"))));
+        assert_eq!(receiver.try_recv(), None);
+        assert_eq!(receiver.is_finished(), true);
+    }
+
+    #[test]
+    fn collect() {
+        let (feed, receiver) = HttpBodyReceiver::channel(1);
+        std::thread::spawn(move || {
+            future::block_on(feed.send(Bytes::from_static(b"hello, ")));
+            future::block_on(feed.send(Bytes::from_static(b"world")));
+            feed.finish();
+        });
+        assert_eq!(future::block_on(receiver.collect()), Ok(Bytes::from_static(b"hello, world")));
+    }
+
+    #[test]
+    fn collect_failed() {
+        let (feed, receiver) = HttpBodyReceiver::channel(2);
+        assert_eq!(future::block_on(feed.send(Bytes::from_static(b"part"))), true);
+        feed.fail(StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(future::block_on(receiver.collect()), Err(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+
+    #[test]
+    fn cut_short() {
+        let (feed, receiver) = HttpBodyReceiver::channel(1);
+        assert_eq!(future::block_on(feed.send(Bytes::from_static(b"part"))), true);
+        feed.fail(StatusCode::REQUEST_TIMEOUT); // Queue is full
+        assert_eq!(receiver.try_recv(), Some(Ok(Bytes::from_static(b"part"))));
+        assert_eq!(receiver.try_recv(), Some(Err(StatusCode::BAD_REQUEST)));
+        assert_eq!(receiver.is_finished(), true);
+    }
+
+    #[test]
+    fn receiver_gone() {
+        let (feed, receiver) = HttpBodyReceiver::channel(1);
+        drop(receiver);
+        assert_eq!(future::block_on(feed.send(Bytes::from_static(b"lost"))), false);
+    }
+
+    #[test]
+    fn abandoned() {
+        let (feed, receiver) = HttpBodyReceiver::channel(1);
+        let clone = receiver.clone();
+        drop(receiver);
+        assert_eq!(future::block_on(future::poll_once(feed.abandoned())), None);
+        drop(clone);
+        assert_eq!(future::block_on(future::poll_once(feed.abandoned())), Some(()));
+    }
+
+}
@@ -0,0 +1,141 @@
+/*
+An HttpBodySender streams the body of a response chunk by chunk, e.g. a large
+export that should not be assembled in memory within one frame. The handler
+returns the response right away and keeps the sender:
+
+    fn export(world: &mut World, _request: &Request<Bytes>, _params: &HttpPathParams) -> Result<Response<Bytes>, StatusCode> {
+        let (response, sender) = HttpBodySender::response(16);
+        world.spawn((sender, ReplayExport::default())); // Some other system calls .try_send() every frame
+        return Ok(response);
+    }
+
+HttpConnectionServer writes the chunks with Transfer-Encoding: chunked (or
+until the connection closes, for HTTP/1.0 clients) and the body ends when
+every HttpBodySender for it has been dropped. Only `capacity` chunks are
+queued: from a system, try_send() returns TrySendError::Full while a slow
+client catches up; from a background task, send().await waits instead.
+
+See also: HttpResponseSender, HttpBodyReceiver
+*/
+
+use bevy::prelude::*;
+use smol::channel::{Receiver, Sender, TrySendError};
+
+use http::Response;
+use bytes::Bytes;
+
+
+// Receiving end, placed in the extensions of a streaming response
+struct HttpBodyStream(Receiver<Bytes>);
+
+
+#[derive(Component, Clone)]
+pub struct HttpBodySender {
+    sender: Sender<Bytes>,
+}
+
+
+impl HttpBodySender {
+
+    // Stream the body of this response, after the bytes already in it
+    pub fn streaming(response: &mut Response<Bytes>, capacity: usize) -> Self {
+        let (sender, receiver) = smol::channel::bounded(capacity);
+        response.extensions_mut().insert(HttpBodyStream(receiver));
+        return HttpBodySender { sender };
+    }
+
+    // A 200 OK response streaming its body
+    pub fn response(capacity: usize) -> (Response<Bytes>, Self) {
+        let mut response = Response::new(Bytes::new());
+        let sender = HttpBodySender::streaming(&mut response, capacity);
+        return (response, sender);
+    }
+
+    pub fn is_streaming(response: &Response<Bytes>) -> bool {
+        return response.extensions().get::<HttpBodyStream>().is_some();
+    }
+
+    // Used by HttpConnectionServer to receive the chunks
+    pub(crate) fn take_stream(response: &mut Response<Bytes>) -> Option<Receiver<Bytes>> {
+        return response.extensions_mut().remove::<HttpBodyStream>().map(|stream| stream.0);
+    }
+
+    // Queue a chunk without waiting. Full means the client is not keeping up,
+    // try again later; Closed means the client is gone and nothing more is sent.
+    // Empty chunks are ignored.
+    pub fn try_send(&self, chunk: Bytes) -> Result<(), TrySendError<Bytes>> {
+        if chunk.is_empty() { return Ok(()); }
+        return self.sender.try_send(chunk);
+    }
+
+    // Queue a chunk, waiting for room. Returns false if the client is gone.
+    pub async fn send(&self, chunk: Bytes) -> bool {
+        if chunk.is_empty() { return true; }
+        return self.sender.send(chunk).await.is_ok();
+    }
+
+    // True if the client is gone, or the response was never sent
+    pub fn is_closed(&self) -> bool {
+        return self.sender.is_closed();
+    }
+
+    // End the body, same as dropping every clone of this sender
+    pub fn finish(self) {
+    }
+
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use smol::future;
+
+    use super::*;
+
+    #[test]
+    fn streaming() {
+        let (mut response, _sender) = HttpBodySender::response(1);
+        assert_eq!(HttpBodySender::is_streaming(&response), true);
+        assert_eq!(HttpBodySender::take_stream(&mut response).is_some(), true);
+        assert_eq!(HttpBodySender::is_streaming(&response), false);
+    }
+
+    #[test]
+    fn backpressure() {
+        let (mut response, sender) = HttpBodySender::response(1);
+        let stream = HttpBodySender::take_stream(&mut response).unwrap();
+        assert_eq!(sender.try_send(Bytes::from_static(b"one")).is_ok(), true);
+        assert_eq!(sender.try_send(Bytes::from_static(b"two")), Err(TrySendError::Full(Bytes::from_static(b"two"))));
+        assert_eq!(future::block_on(stream.recv()).unwrap(), Bytes::from_static(b"one"));
+        assert_eq!(sender.try_send(Bytes::from_static(b"two")).is_ok(), true);
+    }
+
+    #[test]
+    fn empty_chunk_ignored() {
+        let (mut response, sender) = HttpBodySender::response(1);
+        let stream = HttpBodySender::take_stream(&mut response).unwrap();
+        assert_eq!(sender.try_send(Bytes::new()).is_ok(), true);
+        assert_eq!(stream.is_empty(), true);
+    }
+
+    #[test]
+    fn finish() {
+        let (mut response, sender) = HttpBodySender::response(1);
+        let stream = HttpBodySender::take_stream(&mut response).unwrap();
+        let clone = sender.clone();
+        sender.finish();
+        assert_eq!(stream.is_closed(), false);
+        clone.finish();
+        assert_eq!(future::block_on(stream.recv()).is_err(), true);
+    }
+
+    #[test]
+    fn client_gone() {
+        let (mut response, sender) = HttpBodySender::response(1);
+        drop(HttpBodySender::take_stream(&mut response));
+        assert_eq!(sender.is_closed(), true);
+        assert_eq!(future::block_on(sender.send(Bytes::from_static(b"lost"))), false);
+    }
+
+}
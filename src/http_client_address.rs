@@ -1,8 +1,58 @@
 
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 
 use bevy::prelude::*;
 
 
-#[derive(Component)]
-pub struct HttpClientAddress(pub SocketAddr);
+// Peer address of a connection. Unix socket clients are usually unnamed.
+#[derive(Component, Clone, Debug, PartialEq, Eq)]
+pub enum HttpClientAddress {
+    Tcp(SocketAddr),
+    Unix(Option<PathBuf>),
+}
+
+
+impl HttpClientAddress {
+
+    // None for Unix socket clients
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            HttpClientAddress::Tcp(address) => return Some(address.ip()),
+            HttpClientAddress::Unix(_) => return None,
+        }
+    }
+
+}
+
+
+impl std::fmt::Display for HttpClientAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpClientAddress::Tcp(address) => write!(f, "{}", address),
+            HttpClientAddress::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            HttpClientAddress::Unix(None) => write!(f, "unix:(unnamed)"),
+        }
+    }
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ip() {
+        let address: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        assert_eq!(HttpClientAddress::Tcp(address).ip(), Some(address.ip()));
+        assert_eq!(HttpClientAddress::Unix(None).ip(), None);
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(format!("{}", HttpClientAddress::Unix(None)), "unix:(unnamed)");
+        assert_eq!(format!("{}", HttpClientAddress::Unix(Some(PathBuf::from("/tmp/client.sock")))), "unix:/tmp/client.sock");
+    }
+
+}
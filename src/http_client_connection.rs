@@ -1,26 +1,74 @@
 
+// Reads and writes go through smol::Async so an idle connection only costs
+// a registration with the reactor, not a thread blocked in read().
+// The transport is either TCP or a Unix domain socket, HttpConnectionServer
+// only sees the buffered reader and writer. With the "tls" feature these can
+// be wrapped in a rustls session, see start_tls().
+
 use std::net::{TcpStream, TcpListener, SocketAddr, Shutdown};
-use std::io::{BufReader, BufWriter};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use smol::Async;
+use smol::io::{AsyncRead, AsyncWrite, BufReader, BufWriter};
+#[cfg(feature = "tls")]
+use std::pin::Pin;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+#[cfg(feature = "tls")]
+use std::task::{Context, Poll};
+#[cfg(feature = "tls")]
+use futures_rustls::rustls::ServerConfig;
+
+use super::HttpClientAddress;
+
+
+type BoxedReader = Box<dyn AsyncRead + Send + Sync + Unpin>;
+type BoxedWriter = Box<dyn AsyncWrite + Send + Sync + Unpin>;
+
+
+// Kept for shutdown() and local_addr(), reads and writes go through clones
+enum HttpTransport {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
 
 pub struct HttpClientConnection {
-    stream: TcpStream,
-    peer: SocketAddr,
-    reader: BufReader<TcpStream>,
-    writer: BufWriter<TcpStream>,
+    transport: HttpTransport,
+    peer: HttpClientAddress,
+    reader: BufReader<BoxedReader>,
+    writer: BufWriter<BoxedWriter>,
 }
 
 
 impl HttpClientConnection {
 
-    pub fn new(stream: TcpStream, peer: SocketAddr) -> Self {
-        let reader = BufReader::new(stream.try_clone().unwrap());
-        let writer = BufWriter::new(stream.try_clone().unwrap());
-        HttpClientConnection {
-            stream,
+    // Async::new() switches the stream to non-blocking mode. Fails if the
+    // stream can not be cloned, e.g. when out of file descriptors.
+    pub fn new(stream: TcpStream, peer: SocketAddr) -> std::io::Result<Self> {
+        let reader: BoxedReader = Box::new(Async::new(stream.try_clone()?)?);
+        let writer: BoxedWriter = Box::new(Async::new(stream.try_clone()?)?);
+        return Ok(HttpClientConnection {
+            transport: HttpTransport::Tcp(stream),
+            peer: HttpClientAddress::Tcp(peer),
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(writer),
+        });
+    }
+
+    #[cfg(unix)]
+    pub fn from_unix(stream: UnixStream) -> std::io::Result<Self> {
+        let peer = HttpClientAddress::Unix(stream.peer_addr().ok().and_then(|address| address.as_pathname().map(|path| path.to_path_buf())));
+        let reader: BoxedReader = Box::new(Async::new(stream.try_clone()?)?);
+        let writer: BoxedWriter = Box::new(Async::new(stream.try_clone()?)?);
+        return Ok(HttpClientConnection {
+            transport: HttpTransport::Unix(stream),
             peer,
-            reader,
-            writer,
-        }
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(writer),
+        });
     }
 
 
@@ -34,40 +82,108 @@ impl HttpClientConnection {
         // From viewpoint of the server
         let (client, client_addr) = listener.accept()?;
 
-        let conn1 = HttpClientConnection::new(server, server_addr);
-        let conn2 = HttpClientConnection::new(client, client_addr);
+        let conn1 = HttpClientConnection::new(server, server_addr)?;
+        let conn2 = HttpClientConnection::new(client, client_addr)?;
         return Ok((conn1, conn2));
     }
 
-    pub fn this(&self) -> SocketAddr {
-        return self.stream.local_addr().unwrap();
+    // Convenience function for testing, both ends are unnamed
+    #[cfg(unix)]
+    pub fn unix_pair() -> Result<(Self, Self), std::io::Error> {
+        let (server, client) = UnixStream::pair()?;
+        return Ok((HttpClientConnection::from_unix(server)?, HttpClientConnection::from_unix(client)?));
+    }
+
+    pub fn this(&self) -> HttpClientAddress {
+        match &self.transport {
+            HttpTransport::Tcp(stream) => return HttpClientAddress::Tcp(stream.local_addr().unwrap()),
+            #[cfg(unix)]
+            HttpTransport::Unix(stream) => return HttpClientAddress::Unix(stream.local_addr().unwrap().as_pathname().map(|path| path.to_path_buf())),
+        }
     }
 
-    pub fn peer(&self) -> SocketAddr {
-        return self.peer;
+    pub fn peer(&self) -> HttpClientAddress {
+        return self.peer.clone();
     }
 
-    pub fn reader(&mut self) -> &mut BufReader<TcpStream> {
+    pub fn reader(&mut self) -> &mut BufReader<BoxedReader> {
         return &mut self.reader;
     }
 
-    pub fn writer(&mut self) -> &mut BufWriter<TcpStream> {
+    pub fn writer(&mut self) -> &mut BufWriter<BoxedWriter> {
         return &mut self.writer;
     }
 
+    // Both halves at once, to read and write concurrently
+    pub fn split(&mut self) -> (&mut BufReader<BoxedReader>, &mut BufWriter<BoxedWriter>) {
+        return (&mut self.reader, &mut self.writer);
+    }
+
+    // Perform the server side of a TLS handshake, then read and write through
+    // the session. Must be called before anything is read.
+    #[cfg(feature = "tls")]
+    pub async fn start_tls(&mut self, server_config: Arc<ServerConfig>) -> Result<(), std::io::Error> {
+        let reader = std::mem::replace(&mut self.reader, BufReader::new(Box::new(smol::io::empty())));
+        let writer = std::mem::replace(&mut self.writer, BufWriter::new(Box::new(smol::io::sink())));
+        let stream = HttpDuplex { reader: reader.into_inner(), writer: writer.into_inner() };
+        let stream = futures_rustls::TlsAcceptor::from(server_config).accept(stream).await?;
+        let (reader, writer) = smol::io::split(stream);
+        self.reader = BufReader::new(Box::new(reader));
+        self.writer = BufWriter::new(Box::new(writer));
+        return Ok(());
+    }
+
     pub fn close(&mut self) -> Result<(), std::io::Error>{
-        self.stream.shutdown(Shutdown::Both)?;
+        match &self.transport {
+            HttpTransport::Tcp(stream) => stream.shutdown(Shutdown::Both)?,
+            #[cfg(unix)]
+            HttpTransport::Unix(stream) => stream.shutdown(Shutdown::Both)?,
+        }
         return Ok(());
     }
 
 }
 
 
+// Joins the reader and writer again so rustls can wrap both
+#[cfg(feature = "tls")]
+struct HttpDuplex {
+    reader: BoxedReader,
+    writer: BoxedWriter,
+}
+
+
+#[cfg(feature = "tls")]
+impl AsyncRead for HttpDuplex {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+        return Pin::new(&mut self.reader).poll_read(cx, buf);
+    }
+}
+
+
+#[cfg(feature = "tls")]
+impl AsyncWrite for HttpDuplex {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        return Pin::new(&mut self.writer).poll_write(cx, buf);
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        return Pin::new(&mut self.writer).poll_flush(cx);
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        return Pin::new(&mut self.writer).poll_close(cx);
+    }
+}
+
+
 #[cfg(test)]
 #[allow(dead_code)]
 mod tests {
     use std::net::SocketAddr;
-    use std::io::{Read, Write};
+
+    use smol::future;
+    use smol::io::{AsyncReadExt, AsyncWriteExt};
 
     use super::*;
 
@@ -81,14 +197,14 @@ mod tests {
     fn peer_not_null() {
         let (server, _client) = HttpClientConnection::loopback().unwrap();
         let null: SocketAddr = "0.0.0.0:0".parse().unwrap();
-        assert_ne!(server.peer(), null);
+        assert_ne!(server.peer(), HttpClientAddress::Tcp(null));
     }
 
     #[test]
     fn this_not_null() {
         let (server, _client) = HttpClientConnection::loopback().unwrap();
         let null: SocketAddr = "0.0.0.0:0".parse().unwrap();
-        assert_ne!(server.this(), null);
+        assert_ne!(server.this(), HttpClientAddress::Tcp(null));
     }
 
     #[test]
@@ -114,11 +230,54 @@ mod tests {
         const READER: &[u8] = b"hello world";
         let mut writer: [u8; READER.len()] = [0; READER.len()];
         let (mut server, mut client) = HttpClientConnection::loopback().unwrap();
-        client.writer().write(&READER).expect("write failed");
-        client.writer().flush().expect("flush failed"); // Must flush, or reader() will block waiting for more data
+        future::block_on(client.writer().write_all(READER)).expect("write failed");
+        future::block_on(client.writer().flush()).expect("flush failed"); // Must flush, or reader() will wait for more data
         client.close().expect("close failed");
-        server.reader().read_exact(&mut writer).expect("read failed");
-        assert_eq!(&READER[..], &writer[..]);
+        future::block_on(server.reader().read_exact(&mut writer)).expect("read failed");
+        assert_eq!(READER, &writer[..]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_pair_unnamed() {
+        let (server, client) = HttpClientConnection::unix_pair().unwrap();
+        assert_eq!(server.peer(), HttpClientAddress::Unix(None));
+        assert_eq!(client.this(), HttpClientAddress::Unix(None));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_read_write() {
+        const READER: &[u8] = b"hello world";
+        let mut writer: [u8; READER.len()] = [0; READER.len()];
+        let (mut server, mut client) = HttpClientConnection::unix_pair().unwrap();
+        future::block_on(client.writer().write_all(READER)).expect("write failed");
+        future::block_on(client.writer().flush()).expect("flush failed");
+        client.close().expect("close failed");
+        future::block_on(server.reader().read_exact(&mut writer)).expect("read failed");
+        assert_eq!(READER, &writer[..]);
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn tls_read_write() {
+        const READER: &[u8] = b"hello world";
+        let mut writer: [u8; READER.len()] = [0; READER.len()];
+        let (tls, client_config) = crate::http_tls_config::self_signed();
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        let client = HttpDuplex { reader: client.reader.into_inner(), writer: client.writer.into_inner() };
+        let connector = futures_rustls::TlsConnector::from(Arc::new(client_config));
+        let domain = futures_rustls::rustls::ServerName::try_from("localhost").unwrap();
+        let (handshake, client) = future::block_on(future::zip(
+            server.start_tls(tls.server_config()),
+            connector.connect(domain, client),
+        ));
+        handshake.expect("server handshake failed");
+        let mut client = client.expect("client handshake failed");
+        future::block_on(client.write_all(READER)).expect("write failed");
+        future::block_on(client.flush()).expect("flush failed");
+        future::block_on(server.reader().read_exact(&mut writer)).expect("read failed");
+        assert_eq!(READER, &writer[..]);
     }
 
 }
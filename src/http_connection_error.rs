@@ -0,0 +1,38 @@
+
+// Why an HttpConnectionServer stopped serving a connection, reported by
+// http_connection_status as an HttpServerError event
+
+use http::StatusCode;
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HttpConnectionError {
+    // The request could not be read, the client was answered with this status
+    BadRequest(StatusCode),
+    // Anything else, e.g. a timeout or an I/O error
+    Failed(String),
+}
+
+
+impl std::fmt::Display for HttpConnectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpConnectionError::BadRequest(status) => write!(f, "{}", status),
+            HttpConnectionError::Failed(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        assert_eq!(format!("{}", HttpConnectionError::BadRequest(StatusCode::BAD_REQUEST)), "400 Bad Request");
+        assert_eq!(format!("{}", HttpConnectionError::Failed(String::from("oops"))), "oops");
+    }
+
+}
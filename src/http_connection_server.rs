@@ -1,32 +1,124 @@
 /*
 An HttpConnectionServer is instantiated with...
     1. a HttpClientConnection (contains the peer address, stream handle and read/write buffers)
-    2. an Arc<Mutex<Option<Request<Bytes> for SENDING requests (really just a 1 item queue)
-    3. an Arc<Mutex<Option<Response<Bytes> for RECEIVING responses (really just a 1 item queue)
+    2. a channel Sender<Request<Bytes>> for SENDING requests (a queue holding up to
+       max_pipeline_depth requests)
 
-When .run() is invoked, presumably inside an async task, the HttpConnectionServer will...
-    1. read a request from the client (potentially a slow/blocking call)
-    2. self.set_request() to place it into the shared request queue
-    3. wait for a response to appear in the other shared response queue
-    4. write the HTTP response to the client (potentially a slow/blocking call)
-    5. loop unless connection keep-alive was not requested or there was an error
+When .run() is awaited, normally inside a task on the IoTaskPool, the HttpConnectionServer will...
+    0. perform the TLS handshake within header_timeout, if configured with_tls()
+    1. read a request from the client (waits without blocking a thread),
+       answering 100 Continue first if the client asks for it
+    2. attach an HttpResponseSender for a new 1 item response channel to the request,
+       the HttpClientAddress of the peer, and an HttpBodyReceiver if the body is streamed
+    3. send the request to the HttpConnectionTask through the request channel
+    4. sleep until a response arrives on the response channel while reading
+       a streamed body, or answer 504 Gateway Timeout if none arrives within
+       response_timeout
+    5. write the HTTP response to the client, streaming the body chunk by chunk
+       if it has an HttpBodySender. The response to HEAD, and 1xx, 204 and 304
+       responses, end with the head.
+    6. hand the connection over to an HttpWebSocketServer if the response
+       accepted a WebSocket handshake, see HttpWebSocket
+    7. loop unless connection keep-alive was not requested or there was an error
 
-See also: HttpConnectionTask
+Pipelined requests, sent by the client without waiting for responses, are read
+ahead while waiting for a response, up to max_pipeline_depth requests at a time.
+Each is sent to the HttpConnectionTask as soon as it is read, and the responses
+are written in request order no matter in which order they arrive. Requests
+that need the connection to themselves (100 Continue, a streamed body or a
+WebSocket handshake) wait for the responses to the requests before them.
+
+Every step is limited by the timeouts in HttpServerSettings, and the connection
+is closed after max_requests. The Keep-Alive header sent to the client is
+generated from the same settings.
+
+When the shutdown signal is given, an idle connection is closed immediately
+and a busy one is closed after sending its current response.
+
+The response channel is closed once a response has been received or given up
+on, so any HttpResponseSender still held for that request expires.
+
+See also: HttpConnectionTask, HttpResponseSender, HttpWebSocketServer
 */
 
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::collections::VecDeque;
+use std::future::Future;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use bevy::prelude::*;
+use smol::channel::{Receiver, Sender};
+use smol::{future, Timer};
+#[cfg(feature = "tls")]
+use futures_rustls::rustls::ServerConfig;
 
-use vebb::*;
+use http::{Request, Response, StatusCode, Method, HeaderMap, Version};
+use bytes::Bytes;
 
+use super::HttpBodySender;
+use super::HttpBodyReceiver;
+use super::http_body_receiver::HttpBodyFeed;
 use super::HttpClientConnection;
+use super::HttpConnectionError;
+use super::HttpResponseSender;
+use super::HttpServerSettings;
+use super::HttpWebSocket;
+use super::http_websocket::HttpWebSocketUpgrade;
+use super::http_websocket_server::HttpWebSocketServer;
+use super::http_request_handler::status_response;
+use super::http_protocol;
+use super::http_protocol::HttpBodyDecoder;
+
+
+// Chunks of a streamed request body queued for the HttpBodyReceiver
+const STREAM_BODY_CAPACITY: usize = 16;
+
+
+// Whichever comes first in HttpConnectionServer::stream_body()
+enum StreamEvent {
+    BodyRead(bool),
+    Response(Result<Response<Bytes>, smol::channel::RecvError>),
+}
+
+
+// Whichever comes first in HttpConnectionServer::run()
+enum PipelineEvent {
+    Data(bool),
+    Response(Response<Bytes>),
+}
+
+
+// A request sent to the HttpConnectionTask, waiting for its response to be
+// written after the responses to the requests before it
+struct PipelinedRequest {
+    summary: String,
+    version: Version,
+    head: bool, // The response to HEAD is written without its body
+    remaining: usize,
+    keep_alive_allowed: bool,
+    receiver: Receiver<Response<Bytes>>,
+    deadline: Instant,
+}
+
+
+impl PipelinedRequest {
+
+    // Time spent waiting behind other requests counts towards response_timeout
+    async fn receive(&self) -> Response<Bytes> {
+        return receive_response(&self.receiver, self.deadline.saturating_duration_since(Instant::now())).await;
+    }
+
+}
+
 
 pub struct HttpConnectionServer {
     connection: HttpClientConnection,
-    request: Arc<Mutex<Option<Request<Bytes>>>>,
-    response: Arc<Mutex<Option<Response<Bytes>>>>,
+    request: Sender<Request<Bytes>>,
+    settings: HttpServerSettings,
+    shutdown: Option<Receiver<()>>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<ServerConfig>>,
 }
 
 
@@ -34,174 +126,562 @@ impl HttpConnectionServer {
 
     pub fn new(
         connection: HttpClientConnection,
-        request: Arc<Mutex<Option<Request<Bytes>>>>,
-        response: Arc<Mutex<Option<Response<Bytes>>>>,
+        request: Sender<Request<Bytes>>,
     ) -> Self {
         HttpConnectionServer {  
             connection,
             request,
-            response,
+            settings: HttpServerSettings::default(),
+            shutdown: None,
+            #[cfg(feature = "tls")]
+            tls: None,
         }
     }
 
-    pub fn run(&mut self) -> Result<(), String> {
+    pub fn with_settings(mut self, settings: HttpServerSettings) -> Self {
+        self.settings = settings;
+        return self;
+    }
+
+    // The server shuts down when every Sender of this channel has been dropped
+    pub fn with_shutdown_signal(mut self, shutdown: Receiver<()>) -> Self {
+        self.shutdown = Some(shutdown);
+        return self;
+    }
+
+    // Speak HTTPS, see HttpTlsConfig::server_config()
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, server_config: Option<Arc<ServerConfig>>) -> Self {
+        self.tls = server_config;
+        return self;
+    }
+
+    pub async fn run(&mut self) -> Result<(), HttpConnectionError> {
+        let settings = self.settings;
+        let mut served = 0;
+        let mut pipeline = VecDeque::<PipelinedRequest>::new();
+        let mut reading = true; // False once no more requests will be read
+
+        #[cfg(feature = "tls")]
+        self.start_tls().await?;
+
+        // The whole head of the first request must arrive within header_timeout
+        let first_deadline = Instant::now() + settings.header_timeout();
+
         loop {
-            // Read request from client and put it in self.request
-            let summary;
-            match vebb::read_request(self.connection.reader()) {
-                Err(status) => {
-                    return Err(format!("{}: {}", self.connection.peer(), status));
-                }
-                Ok(opt_request) => { 
-                    match opt_request {
-                        None => break, // Connection closed by peer
-                        Some(request) => {
-                            summary = format!("{} {}",request.method().as_str(), request.uri().to_string());
-                            self.set_request(Some(request))
-                        }
+            // Read ahead while the pipeline has room, unless the oldest response
+            // is ready first. Responses are always written in request order.
+            let can_read = reading && pipeline.len() < settings.max_pipeline_depth();
+            let next = match (pipeline.front(), can_read) {
+                (None, false) => break,
+                (None, true) => {
+                    // Wait for the next request, the first one must arrive within header_timeout
+                    // and idle connections are closed right away when the server shuts down
+                    let idle_timeout = if served == 0 { first_deadline.saturating_duration_since(Instant::now()) } else { settings.idle_timeout() };
+                    let shutdown = self.shutdown.clone();
+                    let data = future::or(
+                        timeout(idle_timeout, http_protocol::wait_for_data(self.connection.reader())),
+                        async { wait_for_shutdown(shutdown).await; None },
+                    ).await;
+                    match data {
+                        None => break, // Idle for too long or shutting down, close connection from our side
+                        Some(false) => break, // Connection closed by peer
+                        Some(true) => PipelineEvent::Data(true),
                     }
                 }
+                (Some(front), false) => PipelineEvent::Response(front.receive().await),
+                (Some(front), true) => {
+                    let reader = self.connection.reader();
+                    future::or(
+                        async { PipelineEvent::Data(http_protocol::wait_for_data(reader).await) },
+                        async { PipelineEvent::Response(front.receive().await) },
+                    ).await
+                }
+            };
+            match next {
+                PipelineEvent::Response(response) => {
+                    let pipelined = pipeline.pop_front().unwrap();
+                    if !self.write_response(&pipelined, response, true).await? { break; }
+                    continue;
+                }
+                PipelineEvent::Data(false) => { reading = false; continue; } // Connection closed by peer, answer what was read
+                PipelineEvent::Data(true) => {}
+            }
+
+            // Read request from client
+            let header_timeout = if served == 0 { first_deadline.saturating_duration_since(Instant::now()) } else { settings.header_timeout() };
+            let mut request = match timeout(header_timeout, http_protocol::read_head(self.connection.reader(), &settings)).await {
+                None => return self.time_out_request(&mut pipeline).await,
+                Some(Err(status)) => return self.reject_pipelined(&mut pipeline, status).await,
+                Some(Ok(None)) => { reading = false; continue; } // Connection closed by peer
+                Some(Ok(Some(request))) => request,
+            };
+
+            // These need the connection to themselves, after the responses to
+            // the requests before them have been written
+            let sends_continue = http_protocol::expects_continue(&request) && http_protocol::body_length(request.headers()) != Ok(Some(0));
+            let streamed = streams_body(&settings, request.headers());
+            let exclusive = sends_continue || streamed || HttpWebSocket::is_upgrade(&request);
+            if exclusive && !self.flush_pipeline(&mut pipeline).await? { break; }
+
+            if sends_continue {
+                self.write_continue().await?;
+            }
+            let mut body_feed = None;
+            if streamed {
+                let decoder = match HttpBodyDecoder::new(request.headers(), &settings) {
+                    Err(status) => return self.reject_request(status).await,
+                    Ok(decoder) => decoder,
+                };
+                let (feed, receiver) = HttpBodyReceiver::channel(STREAM_BODY_CAPACITY);
+                request.extensions_mut().insert(receiver);
+                body_feed = Some((decoder, feed));
+            } else {
+                match timeout(settings.body_timeout(), http_protocol::read_body(self.connection.reader(), &mut request, &settings)).await {
+                    None => return self.time_out_request(&mut pipeline).await,
+                    Some(Err(status)) => return self.reject_pipelined(&mut pipeline, status).await,
+                    Some(Ok(())) => {}
+                }
+            }
+            served += 1;
+
+            // Send it to the HttpConnectionTask
+            let remaining = settings.max_requests() - served;
+            let (sender, response_receiver) = smol::channel::bounded(1);
+            let pipelined = PipelinedRequest {
+                summary: format!("{} {}",request.method().as_str(), request.uri()),
+                version: request.version(),
+                head: request.method() == Method::HEAD,
+                remaining,
+                keep_alive_allowed: http_protocol::keep_alive_requested(&request) && remaining > 0,
+                receiver: response_receiver,
+                deadline: Instant::now() + settings.response_timeout(),
+            };
+            request.extensions_mut().insert(HttpResponseSender::new(sender));
+            request.extensions_mut().insert(self.connection.peer());
+            if self.request.send(request).await.is_err() {
+                return Err(HttpConnectionError::Failed(format!("{}: HttpConnectionTask is gone", self.connection.peer())));
+            }
+            if !pipelined.keep_alive_allowed { reading = false; } // This is the last request
+
+            if !exclusive {
+                pipeline.push_back(pipelined);
+                continue;
+            }
+
+            // Sleep until the response arrives. The rest of a streamed body must
+            // have been read to reuse the connection.
+            let (response, body_complete) = match body_feed {
+                None => (self.wait_for_response(pipelined.receiver.clone()).await, true),
+                Some((decoder, feed)) => self.stream_body(decoder, feed, pipelined.receiver.clone()).await,
+            };
+            if !self.write_response(&pipelined, response, body_complete).await? { break; }
+        }
+
+        return self.close();
+    }
+
+    // Answer 503 Service Unavailable without reading a request and close the
+    // connection, used by http_accept_connections when over the connection limits
+    pub async fn refuse(&mut self, retry_after: Duration) -> Result<(), HttpConnectionError> {
+        #[cfg(feature = "tls")]
+        self.start_tls().await?;
+
+        let mut response = status_response(StatusCode::SERVICE_UNAVAILABLE);
+        http_protocol::header_if_missing(&mut response, "Retry-After", retry_after.as_secs().to_string().as_str());
+        return self.respond_and_close(response).await;
+    }
+
+    // Answer a request that could not be read, e.g. 400 Bad Request, then close
+    // the connection. The client may be gone already, so write errors are ignored.
+    async fn reject_request(&mut self, status: StatusCode) -> Result<(), HttpConnectionError> {
+        let _ = self.respond_and_close(status_response(status)).await;
+        return Err(HttpConnectionError::BadRequest(status));
+    }
+
+    // Write the head, then each chunk as it arrives until every HttpBodySender
+    // is dropped. Every write must finish within write_timeout, a slow client
+    // makes HttpBodySender::try_send() return Full rather than buffering here.
+    // Err(Interrupted) means the server is shutting down and the body was cut short.
+    async fn write_stream(&mut self, response: Response<Bytes>, stream: Receiver<Bytes>, chunked: bool) -> Option<std::io::Result<()>> {
+        let write_timeout = self.settings.write_timeout();
+        match timeout(write_timeout, http_protocol::write_head(&response, self.connection.writer())).await? {
+            Err(os_error) => return Some(Err(os_error)),
+            Ok(()) => {}
+        }
+        let mut chunk = response.into_body();
+        loop {
+            if !chunk.is_empty() {
+                match timeout(write_timeout, http_protocol::write_chunk(&chunk, chunked, self.connection.writer())).await? {
+                    Err(os_error) => return Some(Err(os_error)),
+                    Ok(()) => {}
+                }
             }
+            let shutdown = self.shutdown.clone();
+            let next = future::or(
+                async { Some(stream.recv().await) },
+                async { wait_for_shutdown(shutdown).await; None },
+            ).await;
+            chunk = match next {
+                None => return Some(Err(std::io::Error::from(std::io::ErrorKind::Interrupted))),
+                Some(Err(_)) => break, // Every HttpBodySender was dropped
+                Some(Ok(chunk)) => chunk,
+            };
+        }
+        if !chunked { return Some(Ok(())); }
+        return timeout(write_timeout, http_protocol::write_last_chunk(self.connection.writer())).await;
+    }
 
-            // Wait for response to become ready
-            while !self.has_response() { thread::yield_now(); }
+    // Read a streamed request body into the HttpBodyReceiver while waiting for
+    // the response. An early response is held back until the body has been
+    // read, unless nobody is consuming it. Returns false if the body was not
+    // read in full, so the connection can not be reused.
+    async fn stream_body(&mut self, decoder: HttpBodyDecoder, feed: HttpBodyFeed, receiver: Receiver<Response<Bytes>>) -> (Response<Bytes>, bool) {
+        let settings = self.settings;
+        let reading = read_body_into(self.connection.reader(), decoder, feed, settings.body_timeout());
+        smol::pin!(reading);
+        let first = future::or(
+            async { StreamEvent::BodyRead((&mut reading).await) },
+            async { StreamEvent::Response(receiver.recv().await) },
+        ).await;
+        match first {
+            StreamEvent::BodyRead(complete) => {
+                return (receive_response(&receiver, settings.response_timeout()).await, complete);
+            }
+            StreamEvent::Response(received) => {
+                receiver.close(); // Expire any HttpResponseSender still held for this request
+                let complete = reading.await;
+                match received {
+                    Ok(response) => return (response, complete),
+                    Err(_) => return (status_response(StatusCode::INTERNAL_SERVER_ERROR), complete), // Every sender was dropped
+                }
+            }
+        }
+    }
 
-            // Take response from self.response, send it to the client
-            let response = self.take_response();
+    // Write the response to a request, streaming the body if it has an
+    // HttpBodySender. Returns false if the connection is to be closed.
+    async fn write_response(&mut self, pipelined: &PipelinedRequest, mut response: Response<Bytes>, body_complete: bool) -> Result<bool, HttpConnectionError> {
+        let settings = self.settings;
+        let summary = &pipelined.summary;
 
-            let keep_alive = vebb::keep_alive_granted(&response);
-            info!("{} {} {}", summary, response.status().as_str(), response.status().canonical_reason().unwrap());
-            if let Err(os_error) = vebb::send_response(response, self.connection.writer()) {
-                if os_error.kind() == std::io::ErrorKind::ConnectionAborted { break; } // Connection closed by peer
-                return Err(format!("send_response returned {}", os_error));
+        // A WebSocket handshake hands the connection over for good
+        if let Some(upgrade) = HttpWebSocket::take_upgrade(&mut response) {
+            if body_complete && !self.is_shutting_down() {
+                info!("{} {} {}", summary, response.status().as_str(), response.status().canonical_reason().unwrap_or(""));
+                self.serve_websocket(response, upgrade).await?;
+                return Ok(false);
             }
+            response = status_response(StatusCode::SERVICE_UNAVAILABLE); // Shutting down, or the request body was cut short
+        }
 
-            if keep_alive == false { break; } // Close connection from our side
+        // A streamed body is chunked, except for HTTP/1.0 clients which read
+        // it until the connection closes. A status without a body drops the stream.
+        let mut stream = HttpBodySender::take_stream(&mut response);
+        if !http_protocol::allows_body(response.status()) { stream = None; }
+        let chunked = stream.is_some() && pipelined.version == Version::HTTP_11;
+        let keep_alive_allowed = pipelined.keep_alive_allowed
+            && body_complete
+            && !self.is_shutting_down() // Shutdown may have started while waiting
+            && (stream.is_none() || chunked || pipelined.head);
+        if chunked { http_protocol::header_if_missing(&mut response, "Transfer-Encoding", "chunked"); }
+        finalize_response(keep_alive_allowed, &settings.keep_alive_header(pipelined.remaining), stream.is_some(), &mut response);
+        let keep_alive = http_protocol::keep_alive_granted(&response);
+        info!("{} {} {}", summary, response.status().as_str(), response.status().canonical_reason().unwrap_or(""));
+        // HEAD gets the headers GET would get, Content-Length included, and
+        // nothing after them. A streamed body is never sent, so the stream is dropped.
+        let written = match (stream, pipelined.head) {
+            (_, true) => timeout(settings.write_timeout(), http_protocol::write_head(&response, self.connection.writer())).await,
+            (None, false) => timeout(settings.write_timeout(), http_protocol::write_response(response, self.connection.writer())).await,
+            (Some(stream), false) => self.write_stream(response, stream, chunked).await,
+        };
+        match written {
+            None => return Err(HttpConnectionError::Failed(format!("{}: write_response timed out", self.connection.peer()))),
+            Some(Err(os_error)) => {
+                if os_error.kind() == std::io::ErrorKind::ConnectionAborted { return Ok(false); } // Connection closed by peer
+                if os_error.kind() == std::io::ErrorKind::Interrupted { return Ok(false); } // Shutting down mid-stream
+                return Err(HttpConnectionError::Failed(format!("write_response returned {}", os_error)));
+            }
+            Some(Ok(())) => return Ok(keep_alive),
         }
+    }
+
+    // Write the responses to every request in the pipeline, oldest first.
+    // Returns false if the connection is to be closed.
+    async fn flush_pipeline(&mut self, pipeline: &mut VecDeque<PipelinedRequest>) -> Result<bool, HttpConnectionError> {
+        loop {
+            let pipelined = match pipeline.pop_front() {
+                None => return Ok(true),
+                Some(pipelined) => pipelined,
+            };
+            let response = pipelined.receive().await;
+            if !self.write_response(&pipelined, response, true).await? { return Ok(false); }
+        }
+    }
 
-        // Connection closed by peer
+    // Answer a request that could not be read after the responses to the
+    // requests before it, see reject_request()
+    async fn reject_pipelined(&mut self, pipeline: &mut VecDeque<PipelinedRequest>, status: StatusCode) -> Result<(), HttpConnectionError> {
+        if !self.flush_pipeline(pipeline).await? { return self.close(); }
+        return self.reject_request(status).await;
+    }
+
+    // Answer 408 Request Timeout after the responses to the requests before
+    // it, then close the connection. Reported as a bad request, since it is
+    // the client that was too slow.
+    async fn time_out_request(&mut self, pipeline: &mut VecDeque<PipelinedRequest>) -> Result<(), HttpConnectionError> {
+        if self.flush_pipeline(pipeline).await? {
+            let _ = self.respond_and_close(status_response(StatusCode::REQUEST_TIMEOUT)).await;
+        }
+        return Err(HttpConnectionError::BadRequest(StatusCode::REQUEST_TIMEOUT));
+    }
+
+    // Write the 101 Switching Protocols response, then serve the connection as
+    // a WebSocket until it closes
+    async fn serve_websocket(&mut self, response: Response<Bytes>, upgrade: HttpWebSocketUpgrade) -> Result<(), HttpConnectionError> {
+        match timeout(self.settings.write_timeout(), http_protocol::write_head(&response, self.connection.writer())).await {
+            None => return Err(HttpConnectionError::Failed(format!("{}: write_response timed out", self.connection.peer()))),
+            Some(Err(os_error)) => return Err(HttpConnectionError::Failed(format!("write_response returned {}", os_error))),
+            Some(Ok(())) => {}
+        }
+        return HttpWebSocketServer::new(upgrade, self.settings, self.shutdown.clone()).run(&mut self.connection).await;
+    }
+
+    async fn write_continue(&mut self) -> Result<(), HttpConnectionError> {
+        match timeout(self.settings.write_timeout(), http_protocol::write_continue(self.connection.writer())).await {
+            None => return Err(HttpConnectionError::Failed(format!("{}: write_continue timed out", self.connection.peer()))),
+            Some(Err(os_error)) => return Err(HttpConnectionError::Failed(format!("write_continue returned {}", os_error))),
+            Some(Ok(())) => return Ok(()),
+        }
+    }
+
+    // Write a final response with Connection: close, then close the connection
+    async fn respond_and_close(&mut self, mut response: Response<Bytes>) -> Result<(), HttpConnectionError> {
+        finalize_response(false, "", false, &mut response);
+        match timeout(self.settings.write_timeout(), http_protocol::write_response(response, self.connection.writer())).await {
+            None => return Err(HttpConnectionError::Failed(format!("{}: write_response timed out", self.connection.peer()))),
+            Some(Err(os_error)) => return Err(HttpConnectionError::Failed(format!("write_response returned {}", os_error))),
+            Some(Ok(())) => {}
+        }
+        return self.close();
+    }
+
+    #[cfg(feature = "tls")]
+    async fn start_tls(&mut self) -> Result<(), HttpConnectionError> {
+        let server_config = match self.tls.take() {
+            None => return Ok(()),
+            Some(server_config) => server_config,
+        };
+        match timeout(self.settings.header_timeout(), self.connection.start_tls(server_config)).await {
+            None => return Err(HttpConnectionError::Failed(format!("{}: TLS handshake timed out", self.connection.peer()))),
+            Some(Err(error)) => return Err(HttpConnectionError::Failed(format!("{}: TLS handshake failed: {}", self.connection.peer(), error))),
+            Some(Ok(())) => return Ok(()),
+        }
+    }
+
+    fn close(&mut self) -> Result<(), HttpConnectionError> {
         match self.connection.close() {
             Err(os_error) => {
                 if os_error.kind() == std::io::ErrorKind::ConnectionAborted { return Ok(()) }
-                return Err(format!("{}", os_error))
+                if os_error.kind() == std::io::ErrorKind::NotConnected { return Ok(()) }
+                return Err(HttpConnectionError::Failed(format!("{}", os_error)))
             }
             Ok(()) => return Ok(()), 
         }
     }
 
-    fn set_request(&mut self, request: Option<Request<Bytes>>) {
-        *self.request.lock().unwrap() = request;
+    fn is_shutting_down(&self) -> bool {
+        return self.shutdown.as_ref().map_or(false, |shutdown| shutdown.is_closed());
     }
 
-    fn has_response(&self) -> bool {
-        return self.response.lock().unwrap().is_some();
+    async fn wait_for_response(&self, receiver: Receiver<Response<Bytes>>) -> Response<Bytes> {
+        return receive_response(&receiver, self.settings.response_timeout()).await;
     }
 
-    fn take_response(&mut self) -> Response<Bytes> {
-        if let Some(response) = self.response.lock().unwrap().take() {
-            return response;
-        } else {
-            panic!("can not take_response() because response is None; use has_response() first");
+}
+
+
+// Helper function for HttpConnectionServer::wait_for_response() and stream_body()
+async fn receive_response(receiver: &Receiver<Response<Bytes>>, response_timeout: Duration) -> Response<Bytes> {
+    let received = timeout(response_timeout, receiver.recv()).await;
+    receiver.close(); // Expire any HttpResponseSender still held for this request
+    match received {
+        Some(Ok(response)) => return response,
+        Some(Err(_)) => return status_response(StatusCode::INTERNAL_SERVER_ERROR), // Every sender was dropped
+        None => return status_response(StatusCode::GATEWAY_TIMEOUT),
+    }
+}
+
+
+// Helper function for HttpConnectionServer::stream_body(). Every piece must
+// arrive, and be taken by the HttpBodyReceiver, within body_timeout. Reading
+// stops as soon as every HttpBodyReceiver has been dropped.
+async fn read_body_into<R>(reader: &mut R, mut decoder: HttpBodyDecoder, feed: HttpBodyFeed, body_timeout: Duration) -> bool
+where
+    R: smol::io::AsyncBufRead + Unpin
+{
+    loop {
+        let next = future::or(
+            async { Some(decoder.next(reader).await) },
+            async { feed.abandoned().await; None },
+        );
+        let piece = match timeout(body_timeout, next).await {
+            None => { feed.fail(StatusCode::REQUEST_TIMEOUT); return false; }
+            Some(None) => return false, // Nobody is consuming the body
+            Some(Some(Err(status))) => { feed.fail(status); return false; }
+            Some(Some(Ok(None))) => { feed.finish(); return true; }
+            Some(Some(Ok(Some(piece)))) => piece,
+        };
+        match timeout(body_timeout, feed.send(piece)).await {
+            Some(true) => {}
+            _ => return false, // Nobody is consuming the body
         }
     }
+}
+
+
+// Helper function for HttpConnectionServer::run(), bodies over the threshold
+// and chunked bodies are streamed if a threshold is set
+fn streams_body(settings: &HttpServerSettings, headers: &HeaderMap) -> bool {
+    let threshold = match settings.stream_body_threshold() {
+        None => return false,
+        Some(threshold) => threshold,
+    };
+    match http_protocol::body_length(headers) {
+        Ok(None) => return true, // Chunked
+        Ok(Some(length)) => return length > threshold,
+        Err(_) => return false, // Rejected by read_body()
+    }
+}
+
 
+// Helper function for HttpConnectionServer::run() and HttpWebSocketServer::run(),
+// None if the duration elapsed first
+pub(crate) async fn timeout<T>(duration: Duration, future: impl Future<Output = T>) -> Option<T> {
+    return future::or(
+        async { Some(future.await) },
+        async { Timer::after(duration).await; None },
+    ).await;
+}
+
+
+// Helper function for HttpConnectionServer::run() and HttpWebSocketServer::run(),
+// never returns without a shutdown signal
+pub(crate) async fn wait_for_shutdown(shutdown: Option<Receiver<()>>) {
+    match shutdown {
+        None => future::pending::<()>().await,
+        Some(shutdown) => { let _ = shutdown.recv().await; }
+    }
+}
+
+
+// Helper function for HttpConnectionServer::run(), a streamed body has no Content-Length
+fn finalize_response(keep_alive_allowed: bool, keep_alive_header: &str, streaming: bool, response: &mut Response<Bytes>) {
+    if keep_alive_allowed && !http_protocol::keep_alive_denied(response) {
+        http_protocol::header_if_missing(response, "Connection", "keep-alive");
+        http_protocol::header_if_missing(response, "Keep-Alive", keep_alive_header);
+    } else {
+        http_protocol::header_if_missing(response, "Connection", "close");
+    }
+    // 1xx, 204 and 304 end with the head, see http_protocol::allows_body()
+    if !http_protocol::allows_body(response.status()) {
+        response.headers_mut().remove("Content-Length");
+        response.headers_mut().remove("Transfer-Encoding");
+        *response.body_mut() = Bytes::new();
+        return;
+    }
+    if !streaming {
+        let len = format!("{}", response.body().len());
+        http_protocol::header_if_missing(response, "Content-Length", len.as_str());
+    }
+    http_protocol::header_if_missing(response, "Content-Type", "text/html; charset=utf-8");
 }
 
 
 #[cfg(test)]
 #[allow(dead_code)]
 mod tests {
+    use std::thread;
+
+    use smol::io::{AsyncReadExt, AsyncWriteExt};
+
     use super::*;
+    use http::Uri;
+
+    fn ok() -> Response<Bytes> {
+        return Response::builder().status(StatusCode::OK).body(Bytes::from_static(b"")).unwrap();
+    }
 
     #[test]
     fn new() {
         let (_server, client) = HttpClientConnection::loopback().unwrap();
+        let (sender, _receiver) = smol::channel::bounded(1);
         let _connserv = HttpConnectionServer::new(
             client,
-            Arc::new(Mutex::new(None)),
-            Arc::new(Mutex::new(None)),
+            sender,
         );
         assert!(true);
     }
 
     #[test]
-    fn has_response_none() {
+    fn wait_for_response_some() {
         let (_server, client) = HttpClientConnection::loopback().unwrap();
-        let connserv = HttpConnectionServer::new(
-            client,
-            Arc::new(Mutex::new(None)),
-            Arc::new(Mutex::new(None)),
-        );
-        assert_eq!(connserv.has_response(), false);
+        let connserv = HttpConnectionServer::new(client, smol::channel::bounded(1).0);
+        let (sender, receiver) = smol::channel::bounded(1);
+        let sender = HttpResponseSender::new(sender);
+        assert_eq!(sender.send(ok()), true);
+        let response = future::block_on(connserv.wait_for_response(receiver));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(sender.is_expired(), true);
     }
 
     #[test]
-    fn has_response_some() {
+    fn wait_for_response_later() {
         let (_server, client) = HttpClientConnection::loopback().unwrap();
-        let response = Response::builder().status(StatusCode::OK).body(Bytes::from_static(b"")).unwrap();
-        let connserv = HttpConnectionServer::new(
-            client,
-            Arc::new(Mutex::new(None)),
-            Arc::new(Mutex::new(Some(response))),
-        );
-        assert_eq!(connserv.has_response(), true);
+        let connserv = HttpConnectionServer::new(client, smol::channel::bounded(1).0);
+        let (sender, receiver) = smol::channel::bounded(1);
+        let sender = HttpResponseSender::new(sender);
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            sender.send(ok());
+        });
+        let response = future::block_on(connserv.wait_for_response(receiver));
+        assert_eq!(response.status(), StatusCode::OK);
     }
 
     #[test]
-    fn set_request() {
+    fn wait_for_response_timeout() {
         let (_server, client) = HttpClientConnection::loopback().unwrap();
-        let arc_req = Arc::new(Mutex::new(None));
-        let mut connserv = HttpConnectionServer::new(
-            client,
-            arc_req.clone(),
-            Arc::new(Mutex::new(None)),
-        );
-        let request = Request::builder()
-            .method(Method::GET)
-            .uri("/".parse::<Uri>().unwrap())
-            .body(Bytes::from_static(b""))
-            .unwrap();
-
-        connserv.set_request(Some(request));
-        assert_eq!(arc_req.lock().unwrap().is_some(), true);
+        let connserv = HttpConnectionServer::new(client, smol::channel::bounded(1).0)
+            .with_settings(HttpServerSettings::new().with_response_timeout(Duration::from_millis(10)));
+        let (sender, receiver) = smol::channel::bounded(1);
+        let sender = HttpResponseSender::new(sender);
+        let response = future::block_on(connserv.wait_for_response(receiver));
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(sender.is_expired(), true);
+        assert_eq!(sender.send(ok()), false);
     }
 
     #[test]
-    #[should_panic]
-    fn take_response_none() {
+    fn wait_for_response_sender_dropped() {
         let (_server, client) = HttpClientConnection::loopback().unwrap();
-        let mut connserv = HttpConnectionServer::new(
-            client,
-            Arc::new(Mutex::new(None)),
-            Arc::new(Mutex::new(None)),
-        );
-        let _response: Response<Bytes> = connserv.take_response();
-    }
-
-    #[test]
-    fn take_response_some() {
-        let (_server, client) = HttpClientConnection::loopback().unwrap();
-        let response = Response::builder().status(StatusCode::OK).body(Bytes::from_static(b"")).unwrap();
-        let mut connserv = HttpConnectionServer::new(
-            client,
-            Arc::new(Mutex::new(None)),
-            Arc::new(Mutex::new(Some(response))),
-        );
-        assert_eq!(connserv.has_response(), true);
-        let _response: Response<Bytes> = connserv.take_response();
-        assert_eq!(connserv.has_response(), false);
+        let connserv = HttpConnectionServer::new(client, smol::channel::bounded(1).0);
+        let (sender, receiver) = smol::channel::bounded(1);
+        drop(HttpResponseSender::new(sender));
+        let response = future::block_on(connserv.wait_for_response(receiver));
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
     }
 
     #[test]
     fn run_client_close() {
         let (mut server, client) = HttpClientConnection::loopback().unwrap();
         server.close().expect("close failed");
+        let (sender, _receiver) = smol::channel::bounded(1);
         let mut connserv = HttpConnectionServer::new(
             client,
-            Arc::new(Mutex::new(None)),
-            Arc::new(Mutex::new(None)),
+            sender,
         );
-        let handle = thread::spawn(move || connserv.run());
+        let handle = thread::spawn(move || future::block_on(connserv.run()));
         let _ = handle.join().expect("run() crashed");
         assert!(true)
     }
@@ -216,23 +696,21 @@ mod tests {
             .header("Host", "localhost")
             .body(Bytes::from_static(b""))
             .unwrap();
-        vebb::send_request(request, server.writer()).expect("send_request failed");
-        let arc_req = Arc::new(Mutex::new(None));
-        let arc_res = Arc::new(Mutex::new(None));
+        future::block_on(http_protocol::write_request(request, server.writer())).expect("write_request failed");
+        let (sender, receiver) = smol::channel::bounded(1);
         let mut connserv = HttpConnectionServer::new(
             client,
-            arc_req.clone(),
-            arc_res.clone(),
+            sender,
         );
-        thread::spawn(move || connserv.run());
-        while arc_req.lock().unwrap().is_none() { thread::yield_now(); }
-        let _request: Request<Bytes> = arc_req.lock().unwrap().take().unwrap();
+        thread::spawn(move || future::block_on(connserv.run()));
+        let request: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
         let response = Response::builder()
             .status(StatusCode::OK)
             .body(Bytes::from_static(b""))
             .unwrap();
-        *arc_res.lock().unwrap() = Some(response);
-        assert!(true)
+        assert_eq!(HttpResponseSender::from_request(&request).unwrap().send(response), true);
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
     }
 
     #[test]
@@ -246,24 +724,22 @@ mod tests {
             .header("Connection", "keep-alive")
             .body(Bytes::from_static(b""))
             .unwrap();
-        vebb::send_request(request, server.writer()).expect("send_request failed");
-        let arc_req = Arc::new(Mutex::new(None));
-        let arc_res = Arc::new(Mutex::new(None));
+        future::block_on(http_protocol::write_request(request, server.writer())).expect("write_request failed");
+        let (sender, receiver) = smol::channel::bounded(1);
         let mut connserv = HttpConnectionServer::new(
             client,
-            arc_req.clone(),
-            arc_res.clone(),
+            sender,
         );
-        thread::spawn(move || connserv.run());
-        while arc_req.lock().unwrap().is_none() { thread::yield_now(); }
-        let _request: Request<Bytes> = arc_req.lock().unwrap().take().unwrap();
+        thread::spawn(move || future::block_on(connserv.run()));
+        let request: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
         let response = Response::builder()
             .status(StatusCode::OK)
             .header("Connection", "close")
             .body(Bytes::from_static(b""))
             .unwrap();
-        *arc_res.lock().unwrap() = Some(response);
-        assert!(true)
+        assert_eq!(HttpResponseSender::from_request(&request).unwrap().send(response), true);
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.headers().get("Connection").unwrap(), "close");
     }
 
     #[test]
@@ -277,23 +753,599 @@ mod tests {
             .header("Connection", "keep-alive")
             .body(Bytes::from_static(b""))
             .unwrap();
-        vebb::send_request(request, server.writer()).expect("send_request failed");
-        let arc_req = Arc::new(Mutex::new(None));
-        let arc_res = Arc::new(Mutex::new(None));
+        future::block_on(http_protocol::write_request(request, server.writer())).expect("write_request failed");
+        let (sender, receiver) = smol::channel::bounded(1);
         let mut connserv = HttpConnectionServer::new(
             client,
-            arc_req.clone(),
-            arc_res.clone(),
+            sender,
         );
-        thread::spawn(move || connserv.run());
-        while arc_req.lock().unwrap().is_none() { thread::yield_now(); }
-        let _request: Request<Bytes> = arc_req.lock().unwrap().take().unwrap();
+        thread::spawn(move || future::block_on(connserv.run()));
+        let request: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
         let response = Response::builder()
             .status(StatusCode::OK)
             .body(Bytes::from_static(b""))
             .unwrap();
-        *arc_res.lock().unwrap() = Some(response);
-        assert!(true)
+        assert_eq!(HttpResponseSender::from_request(&request).unwrap().send(response), true);
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.headers().get("Connection").unwrap(), "keep-alive");
+    }
+
+    #[test]
+    fn run_idle_connections_share_thread() {
+        // Both connections are served by a single thread; a blocking read on
+        // the idle one would keep the other from ever being answered
+        let (_idle_server, idle_client) = HttpClientConnection::loopback().unwrap();
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        let request = Request::builder()
+            .uri("/foo".parse::<Uri>().unwrap())
+            .body(Bytes::from_static(b""))
+            .unwrap();
+        future::block_on(http_protocol::write_request(request, server.writer())).expect("write_request failed");
+        let (idle_sender, _idle_receiver) = smol::channel::bounded(1);
+        let (sender, receiver) = smol::channel::bounded(1);
+        let mut idle_connserv = HttpConnectionServer::new(idle_client, idle_sender);
+        let mut connserv = HttpConnectionServer::new(client, sender);
+        thread::spawn(move || future::block_on(future::zip(idle_connserv.run(), connserv.run())));
+        let request: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
+        assert_eq!(HttpResponseSender::from_request(&request).unwrap().send(ok()), true);
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    fn keep_alive_request() -> Request<Bytes> {
+        return Request::builder()
+            .uri("/foo".parse::<Uri>().unwrap())
+            .header("Connection", "keep-alive")
+            .body(Bytes::from_static(b""))
+            .unwrap();
+    }
+
+    #[test]
+    fn run_keepalive_header() {
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        future::block_on(http_protocol::write_request(keep_alive_request(), server.writer())).expect("write_request failed");
+        let (sender, receiver) = smol::channel::bounded(1);
+        let mut connserv = HttpConnectionServer::new(client, sender)
+            .with_settings(HttpServerSettings::new().with_idle_timeout(Duration::from_secs(5)).with_max_requests(3));
+        thread::spawn(move || future::block_on(connserv.run()));
+        let request: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
+        assert_eq!(HttpResponseSender::from_request(&request).unwrap().send(ok()), true);
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.headers().get("Connection").unwrap(), "keep-alive");
+        assert_eq!(response.headers().get("Keep-Alive").unwrap(), "timeout=5, max=2");
+    }
+
+    #[test]
+    fn run_max_requests() {
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        future::block_on(http_protocol::write_request(keep_alive_request(), server.writer())).expect("write_request failed");
+        future::block_on(http_protocol::write_request(keep_alive_request(), server.writer())).expect("write_request failed");
+        let (sender, receiver) = smol::channel::bounded(1);
+        let mut connserv = HttpConnectionServer::new(client, sender)
+            .with_settings(HttpServerSettings::new().with_max_requests(2));
+        let handle = thread::spawn(move || future::block_on(connserv.run()));
+        for facit in ["keep-alive", "close"] {
+            let request: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
+            assert_eq!(HttpResponseSender::from_request(&request).unwrap().send(ok()), true);
+            let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+            assert_eq!(response.headers().get("Connection").unwrap(), facit);
+        }
+        assert_eq!(handle.join().expect("run() crashed").is_ok(), true);
+    }
+
+    #[test]
+    fn run_idle_timeout() {
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        future::block_on(http_protocol::write_request(keep_alive_request(), server.writer())).expect("write_request failed");
+        let (sender, receiver) = smol::channel::bounded(1);
+        let mut connserv = HttpConnectionServer::new(client, sender)
+            .with_settings(HttpServerSettings::new().with_idle_timeout(Duration::from_millis(20)));
+        let handle = thread::spawn(move || future::block_on(connserv.run()));
+        let request: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
+        assert_eq!(HttpResponseSender::from_request(&request).unwrap().send(ok()), true);
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.headers().get("Connection").unwrap(), "keep-alive");
+        // No further request is sent, so the server closes the connection
+        assert_eq!(handle.join().expect("run() crashed").is_ok(), true);
+        assert_eq!(future::block_on(http_protocol::read_response(server.reader())).unwrap().is_none(), true);
+    }
+
+    #[test]
+    fn run_header_timeout() {
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        future::block_on(server.writer().write_all(b"GET / HTTP/1.1\r\nHost: loc")).expect("write failed");
+        future::block_on(server.writer().flush()).expect("flush failed");
+        let (sender, _receiver) = smol::channel::bounded(1);
+        let mut connserv = HttpConnectionServer::new(client, sender)
+            .with_settings(HttpServerSettings::new().with_header_timeout(Duration::from_millis(20)));
+        let result = future::block_on(connserv.run());
+        assert_eq!(result, Err(HttpConnectionError::BadRequest(StatusCode::REQUEST_TIMEOUT)));
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(response.headers().get("Connection").unwrap(), "close");
+    }
+
+    #[test]
+    fn run_header_timeout_first_request() {
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        let (sender, _receiver) = smol::channel::bounded(1);
+        let mut connserv = HttpConnectionServer::new(client, sender)
+            .with_settings(HttpServerSettings::new().with_header_timeout(Duration::from_millis(200)));
+        let started = Instant::now();
+        let handle = thread::spawn(move || future::block_on(connserv.run()));
+        thread::sleep(Duration::from_millis(150));
+        future::block_on(server.writer().write_all(b"GET / HTTP/1.1\r\nHost: loc")).expect("write failed");
+        future::block_on(server.writer().flush()).expect("flush failed");
+        let result = handle.join().expect("run() crashed");
+        assert_eq!(result, Err(HttpConnectionError::BadRequest(StatusCode::REQUEST_TIMEOUT)));
+        assert_eq!(started.elapsed() < Duration::from_millis(300), true);
+    }
+
+    #[test]
+    fn run_body_timeout() {
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        future::block_on(server.writer().write_all(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nhello")).expect("write failed");
+        future::block_on(server.writer().flush()).expect("flush failed");
+        let (sender, _receiver) = smol::channel::bounded(1);
+        let mut connserv = HttpConnectionServer::new(client, sender)
+            .with_settings(HttpServerSettings::new().with_body_timeout(Duration::from_millis(20)));
+        let result = future::block_on(connserv.run());
+        assert_eq!(result, Err(HttpConnectionError::BadRequest(StatusCode::REQUEST_TIMEOUT)));
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(response.headers().get("Connection").unwrap(), "close");
+    }
+
+    #[test]
+    fn run_shutdown_idle() {
+        let (_server, client) = HttpClientConnection::loopback().unwrap();
+        let (shutdown_sender, shutdown_receiver) = smol::channel::bounded::<()>(1);
+        let (sender, _receiver) = smol::channel::bounded(1);
+        let mut connserv = HttpConnectionServer::new(client, sender)
+            .with_shutdown_signal(shutdown_receiver);
+        let handle = thread::spawn(move || future::block_on(connserv.run()));
+        drop(shutdown_sender);
+        assert_eq!(handle.join().expect("run() crashed").is_ok(), true);
+    }
+
+    #[test]
+    fn run_shutdown_in_flight() {
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        future::block_on(http_protocol::write_request(keep_alive_request(), server.writer())).expect("write_request failed");
+        let (shutdown_sender, shutdown_receiver) = smol::channel::bounded::<()>(1);
+        let (sender, receiver) = smol::channel::bounded(1);
+        let mut connserv = HttpConnectionServer::new(client, sender)
+            .with_shutdown_signal(shutdown_receiver);
+        let handle = thread::spawn(move || future::block_on(connserv.run()));
+        let request: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
+        drop(shutdown_sender);
+        assert_eq!(HttpResponseSender::from_request(&request).unwrap().send(ok()), true);
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get("Connection").unwrap(), "close");
+        assert_eq!(handle.join().expect("run() crashed").is_ok(), true);
+    }
+
+    #[test]
+    fn run_streaming() {
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        future::block_on(http_protocol::write_request(keep_alive_request(), server.writer())).expect("write_request failed");
+        let (sender, receiver) = smol::channel::bounded(1);
+        let mut connserv = HttpConnectionServer::new(client, sender);
+        thread::spawn(move || future::block_on(connserv.run()));
+        let request: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
+        let (mut response, body) = HttpBodySender::response(1);
+        *response.body_mut() = Bytes::from_static(b"first");
+        assert_eq!(HttpResponseSender::from_request(&request).unwrap().send(response), true);
+        thread::spawn(move || {
+            future::block_on(body.send(Bytes::from_static(b"second")));
+            future::block_on(body.send(Bytes::from_static(b"third")));
+        });
+
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.headers().get("Transfer-Encoding").unwrap(), "chunked");
+        assert_eq!(response.headers().get("Connection").unwrap(), "keep-alive");
+        assert_eq!(response.headers().contains_key("Content-Length"), false);
+        let facit = b"5\r\nfirst\r\n6\r\nsecond\r\n5\r\nthird\r\n0\r\n\r\n";
+        let mut body = [0; 36];
+        future::block_on(server.reader().read_exact(&mut body)).expect("read failed");
+        assert_eq!(String::from_utf8_lossy(&body), String::from_utf8_lossy(facit));
+
+        // The connection is still usable
+        future::block_on(http_protocol::write_request(keep_alive_request(), server.writer())).expect("write_request failed");
+        let request: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
+        assert_eq!(HttpResponseSender::from_request(&request).unwrap().send(ok()), true);
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn run_streaming_http10() {
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        let request = Request::builder()
+            .version(Version::HTTP_10)
+            .uri("/foo")
+            .header("Connection", "keep-alive")
+            .body(Bytes::from_static(b""))
+            .unwrap();
+        future::block_on(http_protocol::write_request(request, server.writer())).expect("write_request failed");
+        let (sender, receiver) = smol::channel::bounded(1);
+        let mut connserv = HttpConnectionServer::new(client, sender);
+        let handle = thread::spawn(move || future::block_on(connserv.run()));
+        let request: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
+        let (response, body) = HttpBodySender::response(4);
+        assert_eq!(HttpResponseSender::from_request(&request).unwrap().send(response), true);
+        future::block_on(body.send(Bytes::from_static(b"all ")));
+        future::block_on(body.send(Bytes::from_static(b"of it")));
+        body.finish();
+
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.headers().get("Connection").unwrap(), "close");
+        assert_eq!(response.headers().contains_key("Transfer-Encoding"), false);
+        let mut body = Vec::new();
+        future::block_on(server.reader().read_to_end(&mut body)).expect("read failed");
+        assert_eq!(body, b"all of it".to_vec());
+        assert_eq!(handle.join().unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn run_bad_request() {
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        future::block_on(server.writer().write_all(b"GET /\r\n\r\n")).expect("write failed");
+        future::block_on(server.writer().flush()).expect("flush failed");
+        let (sender, _receiver) = smol::channel::bounded(1);
+        let mut connserv = HttpConnectionServer::new(client, sender);
+        assert_eq!(future::block_on(connserv.run()), Err(HttpConnectionError::BadRequest(StatusCode::BAD_REQUEST)));
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.headers().get("Connection").unwrap(), "close");
+        assert_eq!(response.body(), &Bytes::from_static(b"400 Bad Request"));
+    }
+
+    #[test]
+    fn run_version_not_supported() {
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        future::block_on(server.writer().write_all(b"GET / HTTP/2.0\r\n\r\n")).expect("write failed");
+        future::block_on(server.writer().flush()).expect("flush failed");
+        let (sender, _receiver) = smol::channel::bounded(1);
+        let mut connserv = HttpConnectionServer::new(client, sender);
+        assert_eq!(future::block_on(connserv.run()), Err(HttpConnectionError::BadRequest(StatusCode::HTTP_VERSION_NOT_SUPPORTED)));
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::HTTP_VERSION_NOT_SUPPORTED);
+    }
+
+    #[test]
+    fn run_body_too_large() {
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/foo")
+            .body(Bytes::from_static(b"too large"))
+            .unwrap();
+        future::block_on(http_protocol::write_request(request, server.writer())).expect("write_request failed");
+        let (sender, _receiver) = smol::channel::bounded(1);
+        let mut connserv = HttpConnectionServer::new(client, sender)
+            .with_settings(HttpServerSettings::new().with_max_body_bytes(4));
+        assert_eq!(future::block_on(connserv.run()), Err(HttpConnectionError::BadRequest(StatusCode::PAYLOAD_TOO_LARGE)));
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(response.headers().get("Connection").unwrap(), "close");
+    }
+
+    #[test]
+    fn run_headers_too_large() {
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        let request = Request::builder()
+            .uri("/foo")
+            .header("X-Large", "x".repeat(100))
+            .body(Bytes::from_static(b""))
+            .unwrap();
+        future::block_on(http_protocol::write_request(request, server.writer())).expect("write_request failed");
+        let (sender, _receiver) = smol::channel::bounded(1);
+        let mut connserv = HttpConnectionServer::new(client, sender)
+            .with_settings(HttpServerSettings::new().with_max_header_bytes(64));
+        assert_eq!(future::block_on(connserv.run()), Err(HttpConnectionError::BadRequest(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)));
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    }
+
+    #[test]
+    fn run_chunked_request() {
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        future::block_on(server.writer().write_all(b"POST /foo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n")).expect("write failed");
+        future::block_on(server.writer().flush()).expect("flush failed");
+        let (sender, receiver) = smol::channel::bounded(1);
+        let mut connserv = HttpConnectionServer::new(client, sender);
+        thread::spawn(move || future::block_on(connserv.run()));
+        let request: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
+        assert_eq!(request.body(), &Bytes::from_static(b"hello"));
+        assert_eq!(HttpBodyReceiver::is_streaming(&request), false);
+        assert_eq!(HttpResponseSender::from_request(&request).unwrap().send(ok()), true);
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn run_expect_continue() {
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        future::block_on(server.writer().write_all(b"POST /foo HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n")).expect("write failed");
+        future::block_on(server.writer().flush()).expect("flush failed");
+        let (sender, receiver) = smol::channel::bounded(1);
+        let mut connserv = HttpConnectionServer::new(client, sender);
+        thread::spawn(move || future::block_on(connserv.run()));
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::CONTINUE);
+        future::block_on(server.writer().write_all(b"hello")).expect("write failed");
+        future::block_on(server.writer().flush()).expect("flush failed");
+        let request: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
+        assert_eq!(request.body(), &Bytes::from_static(b"hello"));
+    }
+
+    #[test]
+    fn run_streamed_body() {
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        future::block_on(server.writer().write_all(b"POST /foo HTTP/1.1\r\nConnection: keep-alive\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nfirst\r\n")).expect("write failed");
+        future::block_on(server.writer().flush()).expect("flush failed");
+        let (sender, receiver) = smol::channel::bounded(1);
+        let mut connserv = HttpConnectionServer::new(client, sender)
+            .with_settings(HttpServerSettings::new().with_stream_body_threshold(1024));
+        thread::spawn(move || future::block_on(connserv.run()));
+
+        // The request arrives before the body is complete
+        let request: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
+        let body = HttpBodyReceiver::from_request(&request).unwrap();
+        assert_eq!(future::block_on(body.recv()), Some(Ok(Bytes::from_static(b"first"))));
+        future::block_on(server.writer().write_all(b"6\r\nsecond\r\n0\r\n\r\n")).expect("write failed");
+        future::block_on(server.writer().flush()).expect("flush failed");
+        assert_eq!(future::block_on(body.collect()), Ok(Bytes::from_static(b"second")));
+        assert_eq!(HttpResponseSender::from_request(&request).unwrap().send(ok()), true);
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.headers().get("Connection").unwrap(), "keep-alive");
+
+        // Small bodies are still read in full
+        future::block_on(http_protocol::write_request(keep_alive_request(), server.writer())).expect("write_request failed");
+        let request: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
+        assert_eq!(HttpBodyReceiver::is_streaming(&request), false);
+    }
+
+    #[test]
+    fn run_streamed_body_early_response() {
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        future::block_on(server.writer().write_all(b"POST /foo HTTP/1.1\r\nConnection: keep-alive\r\nContent-Length: 100\r\n\r\npartial")).expect("write failed");
+        future::block_on(server.writer().flush()).expect("flush failed");
+        let (sender, receiver) = smol::channel::bounded(1);
+        let mut connserv = HttpConnectionServer::new(client, sender)
+            .with_settings(HttpServerSettings::new().with_stream_body_threshold(10));
+        let handle = thread::spawn(move || future::block_on(connserv.run()));
+
+        // Answered without consuming the body, which is then left unread
+        let request: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
+        let response_sender = HttpResponseSender::from_request(&request).unwrap();
+        drop(request);
+        assert_eq!(response_sender.send(ok()), true);
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get("Connection").unwrap(), "close");
+        assert_eq!(handle.join().expect("run() crashed").is_ok(), true);
+    }
+
+    #[test]
+    fn run_streamed_body_too_large() {
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        future::block_on(server.writer().write_all(b"POST /foo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n3\r\ndef\r\n")).expect("write failed");
+        future::block_on(server.writer().flush()).expect("flush failed");
+        let (sender, receiver) = smol::channel::bounded(1);
+        let mut connserv = HttpConnectionServer::new(client, sender)
+            .with_settings(HttpServerSettings::new().with_stream_body_threshold(0).with_max_body_bytes(4));
+        thread::spawn(move || future::block_on(connserv.run()));
+        let request: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
+        let body = HttpBodyReceiver::from_request(&request).unwrap();
+        assert_eq!(future::block_on(body.collect()), Err(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+
+    #[test]
+    fn refuse() {
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        let (sender, _receiver) = smol::channel::bounded(1);
+        let mut connserv = HttpConnectionServer::new(client, sender);
+        future::block_on(connserv.refuse(Duration::from_secs(7))).expect("refuse failed");
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get("Retry-After").unwrap(), "7");
+        assert_eq!(response.headers().get("Connection").unwrap(), "close");
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn run_tls() {
+        let (tls, client_config) = crate::http_tls_config::self_signed();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, peer) = listener.accept().unwrap();
+        let (sender, receiver) = smol::channel::bounded(1);
+        let mut connserv = HttpConnectionServer::new(HttpClientConnection::new(stream, peer).unwrap(), sender)
+            .with_tls(Some(tls.server_config()));
+        thread::spawn(move || future::block_on(connserv.run()));
+
+        let connector = futures_rustls::TlsConnector::from(Arc::new(client_config));
+        let domain = futures_rustls::rustls::ServerName::try_from("localhost").unwrap();
+        let client = future::block_on(connector.connect(domain, smol::Async::new(client).unwrap())).expect("handshake failed");
+        let mut client = smol::io::BufReader::new(client);
+        future::block_on(http_protocol::write_request(keep_alive_request(), client.get_mut())).expect("write_request failed");
+        let request: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
+        assert_eq!(request.uri(), "/foo");
+        assert_eq!(HttpResponseSender::from_request(&request).unwrap().send(ok()), true);
+        let response = future::block_on(http_protocol::read_response(&mut client)).unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn run_tls_plaintext_client() {
+        let (tls, _) = crate::http_tls_config::self_signed();
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        future::block_on(http_protocol::write_request(keep_alive_request(), server.writer())).expect("write_request failed");
+        let (sender, _receiver) = smol::channel::bounded(1);
+        let mut connserv = HttpConnectionServer::new(client, sender)
+            .with_tls(Some(tls.server_config()));
+        let result = future::block_on(connserv.run());
+        assert_eq!(result.unwrap_err().to_string().contains("TLS handshake failed"), true);
+    }
+
+    #[test]
+    fn run_websocket_upgrade() {
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        future::block_on(server.writer().write_all(b"GET /chat HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n")).expect("write failed");
+        future::block_on(server.writer().flush()).expect("flush failed");
+        let (sender, receiver) = smol::channel::bounded(1);
+        let mut connserv = HttpConnectionServer::new(client, sender);
+        let handle = thread::spawn(move || future::block_on(connserv.run()));
+        let request: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
+        assert_eq!(request.extensions().get::<crate::HttpClientAddress>().is_some(), true);
+        let (response, socket) = HttpWebSocket::accept(&request).unwrap();
+        assert_eq!(HttpResponseSender::from_request(&request).unwrap().send(response), true);
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(response.headers().get("Content-Length"), None);
+
+        // The connection now speaks WebSocket frames
+        assert_eq!(socket.send(crate::WsMessage::text("hi")), true);
+        let frame = future::block_on(crate::http_websocket_protocol::read_unmasked_frame(server.reader())).unwrap();
+        assert_eq!(frame, (0x1, b"hi".to_vec()));
+        drop(socket);
+        let frame = future::block_on(crate::http_websocket_protocol::read_unmasked_frame(server.reader())).unwrap();
+        assert_eq!(frame.0, 0x8);
+        server.close().expect("close failed");
+        assert_eq!(handle.join().expect("run() crashed").is_ok(), true);
+    }
+
+    fn text(body: &str) -> Response<Bytes> {
+        return Response::builder().status(StatusCode::OK).body(Bytes::from(String::from(body))).unwrap();
+    }
+
+    #[test]
+    fn run_pipelined_in_order() {
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        future::block_on(server.writer().write_all(b"GET /one HTTP/1.1\r\nConnection: keep-alive\r\n\r\nGET /two HTTP/1.1\r\nConnection: keep-alive\r\n\r\nGET /three HTTP/1.1\r\nConnection: close\r\n\r\n")).expect("write failed");
+        future::block_on(server.writer().flush()).expect("flush failed");
+        let (sender, receiver) = smol::channel::bounded(8);
+        let mut connserv = HttpConnectionServer::new(client, sender);
+        thread::spawn(move || future::block_on(connserv.run()));
+
+        // Every request arrives before any response is sent, answer them in reverse
+        let requests: Vec<Request<Bytes>> = (0..3).map(|_| future::block_on(receiver.recv()).unwrap()).collect();
+        assert_eq!(requests[2].uri().path(), "/three");
+        for (request, body) in requests.iter().zip(["one", "two", "three"]).rev() {
+            assert_eq!(HttpResponseSender::from_request(request).unwrap().send(text(body)), true);
+        }
+        for body in ["one", "two", "three"] {
+            let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+            assert_eq!(response.body(), &Bytes::from(body));
+        }
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap();
+        assert_eq!(response.is_none(), true); // Closed after the last request
+    }
+
+    #[test]
+    fn run_pipelined_head() {
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        future::block_on(server.writer().write_all(b"HEAD /one HTTP/1.1\r\nConnection: keep-alive\r\n\r\nGET /two HTTP/1.1\r\nConnection: close\r\n\r\n")).expect("write failed");
+        future::block_on(server.writer().flush()).expect("flush failed");
+        let (sender, receiver) = smol::channel::bounded(8);
+        let mut connserv = HttpConnectionServer::new(client, sender);
+        thread::spawn(move || future::block_on(connserv.run()));
+
+        // The handler answers HEAD like GET, the body is left out
+        for body in ["one", "two"] {
+            let request: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
+            assert_eq!(HttpResponseSender::from_request(&request).unwrap().send(text(body)), true);
+        }
+        let response = future::block_on(http_protocol::read_response_head(server.reader())).unwrap().unwrap();
+        assert_eq!(response.headers().get("Content-Length").unwrap(), "3");
+        assert_eq!(response.headers().get("Connection").unwrap(), "keep-alive");
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), &Bytes::from_static(b"two"));
+    }
+
+    #[test]
+    fn run_pipelined_no_content() {
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        future::block_on(server.writer().write_all(b"DELETE /one HTTP/1.1\r\nConnection: keep-alive\r\n\r\nGET /two HTTP/1.1\r\nConnection: close\r\n\r\n")).expect("write failed");
+        future::block_on(server.writer().flush()).expect("flush failed");
+        let (sender, receiver) = smol::channel::bounded(8);
+        let mut connserv = HttpConnectionServer::new(client, sender);
+        thread::spawn(move || future::block_on(connserv.run()));
+
+        let first: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
+        let mut response = text("gone");
+        *response.status_mut() = StatusCode::NO_CONTENT;
+        assert_eq!(HttpResponseSender::from_request(&first).unwrap().send(response), true);
+        let second: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
+        assert_eq!(HttpResponseSender::from_request(&second).unwrap().send(text("two")), true);
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers().contains_key("Content-Length"), false);
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.body(), &Bytes::from_static(b"two"));
+    }
+
+    #[test]
+    fn run_pipeline_depth() {
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        future::block_on(server.writer().write_all(b"GET /one HTTP/1.1\r\nConnection: keep-alive\r\n\r\nGET /two HTTP/1.1\r\nConnection: keep-alive\r\n\r\nGET /three HTTP/1.1\r\nConnection: keep-alive\r\n\r\n")).expect("write failed");
+        future::block_on(server.writer().flush()).expect("flush failed");
+        let (sender, receiver) = smol::channel::bounded(8);
+        let mut connserv = HttpConnectionServer::new(client, sender)
+            .with_settings(HttpServerSettings::new().with_max_pipeline_depth(2));
+        thread::spawn(move || future::block_on(connserv.run()));
+
+        let first: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
+        let _second: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(receiver.is_empty(), true); // The pipeline is full
+        assert_eq!(HttpResponseSender::from_request(&first).unwrap().send(text("one")), true);
+        let third: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
+        assert_eq!(third.uri().path(), "/three");
+    }
+
+    #[test]
+    fn run_pipelined_bad_request() {
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        future::block_on(server.writer().write_all(b"GET /one HTTP/1.1\r\nConnection: keep-alive\r\n\r\nnonsense\r\n\r\n")).expect("write failed");
+        future::block_on(server.writer().flush()).expect("flush failed");
+        let (sender, receiver) = smol::channel::bounded(8);
+        let mut connserv = HttpConnectionServer::new(client, sender);
+        thread::spawn(move || future::block_on(connserv.run()));
+
+        // The error response waits for the response to the request before it
+        let request: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(HttpResponseSender::from_request(&request).unwrap().send(text("one")), true);
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.body(), &Bytes::from_static(b"one"));
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn run_pipelined_response_timeout() {
+        let (mut server, client) = HttpClientConnection::loopback().unwrap();
+        future::block_on(server.writer().write_all(b"GET /one HTTP/1.1\r\nConnection: keep-alive\r\n\r\nGET /two HTTP/1.1\r\nConnection: keep-alive\r\n\r\n")).expect("write failed");
+        future::block_on(server.writer().flush()).expect("flush failed");
+        let (sender, receiver) = smol::channel::bounded(8);
+        let mut connserv = HttpConnectionServer::new(client, sender)
+            .with_settings(HttpServerSettings::new().with_response_timeout(Duration::from_millis(50)));
+        let handle = thread::spawn(move || future::block_on(connserv.run()));
+
+        // The 504 closes the connection, the request behind it is dropped
+        let _first: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
+        let second: Request<Bytes> = future::block_on(receiver.recv()).unwrap();
+        let response = future::block_on(http_protocol::read_response(server.reader())).unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(future::block_on(http_protocol::read_response(server.reader())).unwrap().is_none(), true);
+        let _ = handle.join().expect("run() crashed");
+        assert_eq!(HttpResponseSender::from_request(&second).unwrap().is_expired(), true);
     }
 
 }
@@ -2,62 +2,70 @@
 /*
 An HttpConnectionTask is instantiated with...
     1. an async Task handle from Bevy
-    2. an Arc<Mutex<Option<Request<Bytes> for RECEIVING requests (really just a 1 item queue)
-    3. an Arc<Mutex<Option<Response<Bytes> for SENDING responses (really just a 1 item queue)
+    2. a channel Receiver<Request<Bytes>> for RECEIVING requests (a queue holding
+       up to max_pipeline_depth requests, see HttpServerSettings)
 
 This is a Bevy component facing Bevy, serving two purposes:
     1. in http_systems::http_connection_status, track the status of .get_mut_task(),
        removing tasks that have finished (connection closed or an error occurred)
     2. in http_systems::http_request_responder, use .take_request)() and .set_response()
-       to serve requests.
+       to serve requests. Every request queued is taken in the same frame.
 
-See also: HttpConnectionServer
+.set_response() answers the request last taken, using the HttpResponseSender
+that HttpConnectionServer attached to it.
 
+See also: HttpConnectionServer, HttpResponseSender
 
-*/
-use std::sync::{Arc, Mutex};
 
+*/
 use bevy::prelude::*;
 use bevy::tasks::Task;
+use smol::channel::Receiver;
+
+use http::{Request, Response};
+use bytes::Bytes;
 
-use vebb::*;
+use super::HttpConnectionError;
+use super::HttpResponseSender;
 
 #[derive(Component)]
 pub struct HttpConnectionTask {
-    task: Task<Result<(),String>>,
-    request: Arc<Mutex<Option<Request<Bytes>>>>,
-    response: Arc<Mutex<Option<Response<Bytes>>>>,
+    task: Task<Result<(),HttpConnectionError>>,
+    request: Receiver<Request<Bytes>>,
+    sender: Option<HttpResponseSender>,
 }
 
 
 impl HttpConnectionTask {
 
     pub fn new(
-        task: Task<Result<(),String>>,
-        request: Arc<Mutex<Option<Request<Bytes>>>>,
-        response: Arc<Mutex<Option<Response<Bytes>>>>,
+        task: Task<Result<(),HttpConnectionError>>,
+        request: Receiver<Request<Bytes>>,
     ) -> Self {
         HttpConnectionTask { 
             task, 
             request,
-            response, 
+            sender: None, 
         }
     }
 
-    pub fn get_mut_task(&mut self) -> &mut Task<Result<(),String>> {
+    pub fn get_mut_task(&mut self) -> &mut Task<Result<(),HttpConnectionError>> {
         return &mut self.task;
     }
 
     pub fn set_response(&mut self, response: Option<Response<Bytes>>) {
-        *self.response.lock().unwrap() = response;
+        if let (Some(sender), Some(response)) = (self.sender.take(), response) {
+            sender.send(response);
+        }
     }
 
     pub fn has_request(&self) -> bool {
-        return self.request.lock().unwrap().is_some();
+        return !self.request.is_empty();
     }
 
     pub fn take_request(&mut self) -> Request<Bytes> {
-        if let Some(request) = self.request.lock().unwrap().take() {
+        if let Ok(request) = self.request.try_recv() {
+            self.sender = HttpResponseSender::from_request(&request);
             return request;
         } else {
             panic!("can not take_request() because request is None; use has_request() first");
@@ -69,53 +77,57 @@ impl HttpConnectionTask {
 
 #[cfg(test)]
 mod tests {
+    use smol::channel::Sender;
+
     use super::*;
+    use http::StatusCode;
+
+    fn test_task() -> Task<Result<(),HttpConnectionError>> {
+        let pool = bevy::tasks::AsyncComputeTaskPool::init(bevy::tasks::TaskPool::new);
+        return pool.spawn(async move { return Ok(()); });
+    }
+
+    fn test_request(sender: &Sender<Response<Bytes>>) -> Request<Bytes> {
+        let mut request = Request::builder().body(Bytes::from_static(b"")).unwrap();
+        request.extensions_mut().insert(HttpResponseSender::new(sender.clone()));
+        return request;
+    }
 
     #[test]
     fn new() {
-        let pool = bevy::tasks::AsyncComputeTaskPool::init(|| bevy::tasks::TaskPool::new());
-        let task: Task<Result<(),String>> = pool.spawn(async move { return Ok(()); });
         let _conntask: HttpConnectionTask = HttpConnectionTask::new(
-            task,
-            Arc::new(Mutex::new(None)),
-            Arc::new(Mutex::new(None)),
+            test_task(),
+            smol::channel::bounded(1).1,
         );
         assert!(true);
     }
 
     #[test]
     fn get_mut_task() {
-        let pool = bevy::tasks::AsyncComputeTaskPool::init(|| bevy::tasks::TaskPool::new());
-        let task: Task<Result<(),String>> = pool.spawn(async move { return Ok(()); });
         let mut conntask: HttpConnectionTask = HttpConnectionTask::new(
-            task,
-            Arc::new(Mutex::new(None)),
-            Arc::new(Mutex::new(None)),
+            test_task(),
+            smol::channel::bounded(1).1,
         );
-        let _: &mut Task<Result<(), String>> = conntask.get_mut_task();
+        let _task: &mut Task<Result<(), HttpConnectionError>> = conntask.get_mut_task();
     }
 
     #[test]
     fn has_request_none() {
-        let pool = bevy::tasks::AsyncComputeTaskPool::init(|| bevy::tasks::TaskPool::new());
-        let task: Task<Result<(),String>> = pool.spawn(async move { return Ok(()); });
         let conntask: HttpConnectionTask = HttpConnectionTask::new(
-            task,
-            Arc::new(Mutex::new(None)),
-            Arc::new(Mutex::new(None)),
+            test_task(),
+            smol::channel::bounded(1).1,
         );
         assert_eq!(conntask.has_request(), false);
     }
 
     #[test]
     fn has_request_some() {
-        let pool = bevy::tasks::AsyncComputeTaskPool::init(|| bevy::tasks::TaskPool::new());
-        let task: Task<Result<(),String>> = pool.spawn(async move { return Ok(()); });
-        let request = Request::builder().body(Bytes::from_static(b"")).unwrap();
+        let (req_tx, req_rx) = smol::channel::bounded(1);
+        let (res_tx, _res_rx) = smol::channel::bounded(1);
+        req_tx.try_send(test_request(&res_tx)).unwrap();
         let conntask: HttpConnectionTask = HttpConnectionTask::new(
-            task,
-            Arc::new(Mutex::new(Some(request))),
-            Arc::new(Mutex::new(None)),
+            test_task(),
+            req_rx,
         );
         assert_eq!(conntask.has_request(), true);
     }
@@ -123,59 +135,64 @@ mod tests {
     #[test]
     #[should_panic]
     fn take_request_none() {
-        let pool = bevy::tasks::AsyncComputeTaskPool::init(|| bevy::tasks::TaskPool::new());
-        let task: Task<Result<(),String>> = pool.spawn(async move { return Ok(()); });
         let mut conntask: HttpConnectionTask = HttpConnectionTask::new(
-            task,
-            Arc::new(Mutex::new(None)),
-            Arc::new(Mutex::new(None)),
+            test_task(),
+            smol::channel::bounded(1).1,
         );
         let _ = conntask.take_request();
     }
 
     #[test]
     fn take_request_some() {
-        let pool = bevy::tasks::AsyncComputeTaskPool::init(|| bevy::tasks::TaskPool::new());
-        let task: Task<Result<(),String>> = pool.spawn(async move { return Ok(()); });
-        let request = Request::builder().body(Bytes::from_static(b"")).unwrap();
-        let arc_req = Arc::new(Mutex::new(Some(request)));
+        let (req_tx, req_rx) = smol::channel::bounded(1);
+        let (res_tx, _res_rx) = smol::channel::bounded(1);
+        req_tx.try_send(test_request(&res_tx)).unwrap();
         let mut conntask: HttpConnectionTask = HttpConnectionTask::new(
-            task,
-            arc_req.clone(),
-            Arc::new(Mutex::new(None)),
+            test_task(),
+            req_rx,
         );
         let _request: Request<Bytes> = conntask.take_request();
     }
 
     #[test]
     fn request_empty_after_take() {
-        let pool = bevy::tasks::AsyncComputeTaskPool::init(|| bevy::tasks::TaskPool::new());
-        let task: Task<Result<(),String>> = pool.spawn(async move { return Ok(()); });
-        let request = Request::builder().body(Bytes::from_static(b"")).unwrap();
-        let arc_req = Arc::new(Mutex::new(Some(request)));
+        let (req_tx, req_rx) = smol::channel::bounded(1);
+        let (res_tx, _res_rx) = smol::channel::bounded(1);
+        req_tx.try_send(test_request(&res_tx)).unwrap();
         let mut conntask: HttpConnectionTask = HttpConnectionTask::new(
-            task,
-            arc_req.clone(),
-            Arc::new(Mutex::new(None)),
+            test_task(),
+            req_rx.clone(),
         );
         let _request: Request<Bytes> = conntask.take_request();
-        assert_eq!(arc_req.lock().unwrap().is_some(), false);
+        assert_eq!(req_rx.is_empty(), true);
         assert_eq!(conntask.has_request(), false);
     }
 
     #[test]
     fn set_response() {
-        let pool = bevy::tasks::AsyncComputeTaskPool::init(|| bevy::tasks::TaskPool::new());
-        let task: Task<Result<(),String>> = pool.spawn(async move { return Ok(()); });
+        let (req_tx, req_rx) = smol::channel::bounded(1);
+        let (res_tx, res_rx) = smol::channel::bounded(1);
+        req_tx.try_send(test_request(&res_tx)).unwrap();
+        let response = Response::builder().status(StatusCode::OK).body(Bytes::from_static(b"")).unwrap();
+        let mut conntask: HttpConnectionTask = HttpConnectionTask::new(
+            test_task(),
+            req_rx,
+        );
+        let _request: Request<Bytes> = conntask.take_request();
+        conntask.set_response(Some(response));
+        assert_eq!(res_rx.try_recv().is_ok(), true);
+    }
+
+    #[test]
+    fn set_response_without_request() {
+        let (_res_tx, res_rx) = smol::channel::bounded::<Response<Bytes>>(1);
         let response = Response::builder().status(StatusCode::OK).body(Bytes::from_static(b"")).unwrap();
-        let arc_res = Arc::new(Mutex::new(None));
         let mut conntask: HttpConnectionTask = HttpConnectionTask::new(
-            task,
-            Arc::new(Mutex::new(None)),
-            arc_res.clone(),
+            test_task(),
+            smol::channel::bounded(1).1,
         );
         conntask.set_response(Some(response));
-        assert_eq!(arc_res.lock().unwrap().is_some(), true);
+        assert_eq!(res_rx.is_empty(), true);
     }
 
 }
@@ -0,0 +1,333 @@
+
+// Error returned by handlers and middleware. Anything convertible into an
+// HttpError can be returned with `?`, including a bare StatusCode:
+//
+//    fn player(world: &mut World, request: &Request<Bytes>, params: &HttpPathParams) -> Result<Response<Bytes>, HttpError> {
+//        let id: u32 = params.get("id").unwrap().parse()?; // 400 Bad Request
+//        let save = std::fs::read(format!("saves/{}.json", id))?; // 404 Not Found
+//        if !my_auth::is_valid(world, request) {
+//            return Err(HttpError::new(StatusCode::UNAUTHORIZED).with_header("WWW-Authenticate", "Bearer"));
+//        }
+//        ...
+//    }
+//
+// The message is sent to the client, the source error is not. It is logged
+// instead when the error response is built by the middleware chain, see
+// http_middleware, for server errors and for I/O errors of any status.
+//
+// With the "serde_json" cargo feature, serde_json::Error converts into
+// 400 Bad Request, or 500 Internal Server Error for I/O errors.
+
+use std::error::Error;
+use std::fmt;
+
+use http::{Response, StatusCode, HeaderName, HeaderValue};
+use bytes::Bytes;
+
+use super::http_request_handler::status_response;
+
+
+type BoxedError = Box<dyn Error + Send + Sync>;
+
+
+#[derive(Debug)]
+pub struct HttpError {
+    status: StatusCode,
+    message: Option<String>,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    source: Option<BoxedError>,
+}
+
+
+impl HttpError {
+
+    pub fn new(status: StatusCode) -> Self {
+        HttpError {
+            status,
+            message: None,
+            headers: Vec::new(),
+            source: None,
+        }
+    }
+
+    // Shorthand for HttpError::new(StatusCode::BAD_REQUEST).with_message(message)
+    pub fn bad_request(message: &str) -> Self {
+        return HttpError::new(StatusCode::BAD_REQUEST).with_message(message);
+    }
+
+    // Shorthand for HttpError::new(StatusCode::INTERNAL_SERVER_ERROR).with_source(source)
+    pub fn internal(source: impl Into<BoxedError>) -> Self {
+        return HttpError::new(StatusCode::INTERNAL_SERVER_ERROR).with_source(source);
+    }
+
+    // Sent to the client after the status line, e.g. "400 Bad Request: missing name"
+    pub fn with_message(mut self, message: &str) -> Self {
+        self.message = Some(String::from(message));
+        return self;
+    }
+
+    // Added to the error response, e.g. WWW-Authenticate or Retry-After.
+    // Panics if name or value is not a valid header, see try_with_header().
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        let name = HeaderName::from_bytes(name.as_bytes()).unwrap();
+        self.headers.push((name, HeaderValue::from_str(value).unwrap()));
+        return self;
+    }
+
+    // Like with_header(), for values computed at runtime. An invalid name or
+    // value gives 500 Internal Server Error, so `?` can pass it on.
+    pub fn try_with_header(mut self, name: &str, value: &str) -> Result<Self, HttpError> {
+        let name = HeaderName::from_bytes(name.as_bytes()).map_err(HttpError::internal)?;
+        let value = HeaderValue::from_str(value).map_err(HttpError::internal)?;
+        self.headers.push((name, value));
+        return Ok(self);
+    }
+
+    // The underlying error, for logging only
+    pub fn with_source(mut self, source: impl Into<BoxedError>) -> Self {
+        self.source = Some(source.into());
+        return self;
+    }
+
+    pub fn status(&self) -> StatusCode {
+        return self.status;
+    }
+
+    pub fn message(&self) -> Option<&str> {
+        return self.message.as_deref();
+    }
+
+    pub fn headers(&self) -> &Vec<(HeaderName, HeaderValue)> {
+        return &self.headers;
+    }
+
+    // Plain text response like "404 Not Found", followed by the message if any
+    pub fn response(&self) -> Response<Bytes> {
+        let mut response = status_response(self.status);
+        if self.message.is_some() {
+            *response.body_mut() = Bytes::from(self.to_string());
+        }
+        // Headers added with with_header() replace the defaults, e.g. Content-Type
+        for (name, _) in self.headers.iter() {
+            response.headers_mut().remove(name);
+        }
+        for (name, value) in self.headers.iter() {
+            response.headers_mut().append(name.clone(), value.clone());
+        }
+        return response;
+    }
+
+}
+
+
+impl fmt::Display for HttpError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = format!("{} {}", self.status.as_u16(), self.status.canonical_reason().unwrap_or(""));
+        match &self.message {
+            None => write!(f, "{}", status.trim_end()),
+            Some(message) => write!(f, "{}: {}", status.trim_end(), message),
+        }
+    }
+
+}
+
+
+impl Error for HttpError {
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        return self.source.as_ref().map(|source| source.as_ref() as &(dyn Error + 'static));
+    }
+
+}
+
+
+// Lets existing code compare errors with a StatusCode
+impl PartialEq<StatusCode> for HttpError {
+
+    fn eq(&self, other: &StatusCode) -> bool {
+        return self.status == *other;
+    }
+
+}
+
+
+impl From<StatusCode> for HttpError {
+
+    fn from(status: StatusCode) -> Self {
+        return HttpError::new(status);
+    }
+
+}
+
+
+// The message of an I/O error may include file names, so it is not sent.
+// These are logged whatever the status, as a missing file may just as well be
+// a problem on the server as a bad request.
+impl From<std::io::Error> for HttpError {
+
+    fn from(error: std::io::Error) -> Self {
+        let status = match error.kind() {
+            std::io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            std::io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+            std::io::ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+            std::io::ErrorKind::InvalidData => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        return HttpError::new(status).with_source(error);
+    }
+
+}
+
+
+impl From<std::str::Utf8Error> for HttpError {
+
+    fn from(error: std::str::Utf8Error) -> Self {
+        return HttpError::bad_request(&error.to_string()).with_source(error);
+    }
+
+}
+
+
+impl From<std::string::FromUtf8Error> for HttpError {
+
+    fn from(error: std::string::FromUtf8Error) -> Self {
+        return HttpError::bad_request(&error.to_string()).with_source(error);
+    }
+
+}
+
+
+impl From<std::num::ParseIntError> for HttpError {
+
+    fn from(error: std::num::ParseIntError) -> Self {
+        return HttpError::bad_request(&error.to_string()).with_source(error);
+    }
+
+}
+
+
+impl From<std::num::ParseFloatError> for HttpError {
+
+    fn from(error: std::num::ParseFloatError) -> Self {
+        return HttpError::bad_request(&error.to_string()).with_source(error);
+    }
+
+}
+
+
+#[cfg(feature = "serde_json")]
+impl From<serde_json::Error> for HttpError {
+
+    fn from(error: serde_json::Error) -> Self {
+        if error.is_io() { return HttpError::internal(error); }
+        return HttpError::bad_request(&error.to_string()).with_source(error);
+    }
+
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use super::*;
+
+    fn parse(value: &str) -> Result<u32, HttpError> {
+        return Ok(value.parse::<u32>()?);
+    }
+
+    #[test]
+    fn from_status() {
+        let error = HttpError::from(StatusCode::NOT_FOUND);
+        assert_eq!(error, StatusCode::NOT_FOUND);
+        assert_eq!(error.message(), None);
+        assert_eq!(error.source().is_none(), true);
+        assert_eq!(error.to_string(), "404 Not Found");
+        let response = error.response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers().get("Content-Type").unwrap(), "text/plain; charset=utf-8");
+        assert_eq!(response.into_body(), Bytes::from_static(b"404 Not Found"));
+    }
+
+    #[test]
+    fn message_and_headers() {
+        let error = HttpError::new(StatusCode::UNAUTHORIZED)
+            .with_message("token expired")
+            .with_header("WWW-Authenticate", "Bearer");
+        assert_eq!(error.to_string(), "401 Unauthorized: token expired");
+        let response = error.response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers().get("WWW-Authenticate").unwrap(), "Bearer");
+        assert_eq!(response.into_body(), Bytes::from_static(b"401 Unauthorized: token expired"));
+    }
+
+    #[test]
+    fn try_with_header() {
+        let error = HttpError::new(StatusCode::SERVICE_UNAVAILABLE).try_with_header("Retry-After", &5.to_string()).unwrap();
+        assert_eq!(error.response().headers().get("Retry-After").unwrap(), "5");
+        let error = HttpError::new(StatusCode::SERVICE_UNAVAILABLE).try_with_header("Retry-After", "5\r\nX-Injected: 1").unwrap_err();
+        assert_eq!(error, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(error.source().is_some(), true);
+        let error = HttpError::new(StatusCode::SERVICE_UNAVAILABLE).try_with_header("Retry After", "5").unwrap_err();
+        assert_eq!(error, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn without_canonical_reason() {
+        let error = HttpError::new(StatusCode::from_u16(499).unwrap());
+        assert_eq!(error.to_string(), "499");
+        let response = error.response();
+        assert_eq!(response.status().as_u16(), 499);
+        assert_eq!(response.into_body(), Bytes::from_static(b"499"));
+        let error = HttpError::new(StatusCode::from_u16(499).unwrap()).with_message("client closed");
+        assert_eq!(error.to_string(), "499: client closed");
+    }
+
+    #[test]
+    fn replace_header() {
+        let response = HttpError::new(StatusCode::SERVICE_UNAVAILABLE)
+            .with_header("Retry-After", "5")
+            .with_header("Content-Type", "text/html")
+            .with_header("Link", "</status>")
+            .with_header("Link", "</help>")
+            .response();
+        assert_eq!(response.headers().get("Retry-After").unwrap(), "5");
+        assert_eq!(response.headers().get_all("Content-Type").iter().count(), 1);
+        assert_eq!(response.headers().get("Content-Type").unwrap(), "text/html");
+        assert_eq!(response.headers().get_all("Link").iter().count(), 2);
+    }
+
+    #[test]
+    fn from_parse_int() {
+        let error = parse("forty-two").unwrap_err();
+        assert_eq!(error, StatusCode::BAD_REQUEST);
+        assert_eq!(error.message(), Some("invalid digit found in string"));
+        assert_eq!(error.source().is_some(), true);
+        assert_eq!(parse("42").unwrap(), 42);
+    }
+
+    #[test]
+    fn from_io() {
+        let error = HttpError::from(std::io::Error::new(std::io::ErrorKind::NotFound, "saves/42.json"));
+        assert_eq!(error, StatusCode::NOT_FOUND);
+        assert_eq!(error.response().into_body(), Bytes::from_static(b"404 Not Found"));
+        assert_eq!(error.source().unwrap().to_string(), "saves/42.json");
+        let error = HttpError::from(std::io::Error::other("disk full"));
+        assert_eq!(error, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn from_utf8() {
+        let error = HttpError::from(String::from_utf8(vec![0xff]).unwrap_err());
+        assert_eq!(error, StatusCode::BAD_REQUEST);
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn from_serde_json() {
+        let error = HttpError::from(serde_json::from_str::<u32>("{").unwrap_err());
+        assert_eq!(error, StatusCode::BAD_REQUEST);
+        assert_eq!(error.message().is_some(), true);
+    }
+
+}
@@ -0,0 +1,108 @@
+
+// Anything that can answer a request routed to it by HttpRequestHandler:
+// plain functions, closures capturing their own configuration, or user
+// types implementing Handler directly.
+//
+// Functions taking SystemParams are accepted too, see SystemHandlerFunction.
+//
+// Closures must annotate their argument types, e.g.
+// move |world: &mut World, request: &Request<Bytes>, params: &HttpPathParams| { ... }
+//
+// Functions and closures may fail with anything convertible into HttpError,
+// such as a bare StatusCode. A closure that never returns an error must name
+// the error type, e.g. Ok::<_, HttpError>(response). This is a breaking
+// change from when handlers could only fail with StatusCode, see lib.rs.
+
+use bevy::prelude::*;
+use http::{Request, Response};
+use bytes::Bytes;
+
+use super::HttpError;
+use super::HttpPathParams;
+
+
+pub trait Handler {
+    fn call(&self, world: &mut World, request: &Request<Bytes>, params: &HttpPathParams) -> Result<Response<Bytes>, HttpError>;
+}
+
+
+impl<F, E> Handler for F
+where
+    F: Fn(&mut World, &Request<Bytes>, &HttpPathParams) -> Result<Response<Bytes>, E>,
+    E: Into<HttpError>,
+{
+    fn call(&self, world: &mut World, request: &Request<Bytes>, params: &HttpPathParams) -> Result<Response<Bytes>, HttpError> {
+        return self(world, request, params).map_err(|error| error.into());
+    }
+}
+
+
+// Conversion used by HttpRequestHandler::new() and friends, so that both
+// Handler implementations and system-style functions can be registered.
+// The Marker type only exists to keep the blanket implementations apart.
+pub trait IntoHandler<Marker> {
+    type Handler: Handler + Send + Sync + 'static;
+    fn into_handler(self) -> Self::Handler;
+}
+
+
+impl<H> IntoHandler<()> for H
+where
+    H: Handler + Send + Sync + 'static
+{
+    type Handler = H;
+    fn into_handler(self) -> Self::Handler {
+        return self;
+    }
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use super::*;
+    use http::StatusCode;
+
+    fn test_handler_ok(_world: &mut World, _request: &Request<Bytes>, _params: &HttpPathParams) -> Result<Response<Bytes>, StatusCode> {
+        return Ok(Response::builder().status(StatusCode::OK).body(Bytes::from_static(b"")).unwrap());
+    }
+
+    struct TestHandler {
+        status: StatusCode,
+    }
+
+    impl Handler for TestHandler {
+        fn call(&self, _world: &mut World, _request: &Request<Bytes>, _params: &HttpPathParams) -> Result<Response<Bytes>, HttpError> {
+            return Err(HttpError::new(self.status));
+        }
+    }
+
+    fn call(handler: &dyn Handler) -> Result<Response<Bytes>, HttpError> {
+        let request = Request::builder().uri("/").body(Bytes::from_static(b"")).unwrap();
+        let mut world = World::new();
+        return handler.call(&mut world, &request, &HttpPathParams::new());
+    }
+
+    #[test]
+    fn fn_handler() {
+        assert_eq!(call(&test_handler_ok).unwrap().status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn closure_handler() {
+        let body = String::from("captured");
+        // The error type can not be inferred from Ok(..) alone since handlers
+        // may fail with anything convertible into HttpError
+        let handler = move |_world: &mut World, _request: &Request<Bytes>, _params: &HttpPathParams| {
+            return Ok::<_, StatusCode>(Response::builder().status(StatusCode::OK).body(Bytes::from(body.clone())).unwrap());
+        };
+        assert_eq!(call(&handler).unwrap().into_body(), Bytes::from_static(b"captured"));
+    }
+
+    #[test]
+    fn struct_handler() {
+        let handler = TestHandler { status: StatusCode::IM_A_TEAPOT };
+        assert_eq!(call(&handler).unwrap_err(), StatusCode::IM_A_TEAPOT);
+    }
+
+}
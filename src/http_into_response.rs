@@ -0,0 +1,137 @@
+
+// Return types accepted from system-style handlers, see SystemHandlerFunction
+
+use http::{Response, StatusCode};
+use bytes::Bytes;
+
+use super::HttpError;
+
+
+pub trait IntoResponse {
+    fn into_response(self) -> Result<Response<Bytes>, HttpError>;
+}
+
+
+impl IntoResponse for Response<Bytes> {
+    fn into_response(self) -> Result<Response<Bytes>, HttpError> {
+        return Ok(self);
+    }
+}
+
+
+// Error statuses are passed on so they get the same error response as
+// Err(status), anything else becomes a response with an empty body
+impl IntoResponse for StatusCode {
+    fn into_response(self) -> Result<Response<Bytes>, HttpError> {
+        if self.is_client_error() || self.is_server_error() { return Err(HttpError::new(self)); }
+        return Ok(Response::builder()
+            .status(self)
+            .body(Bytes::new())
+            .unwrap());
+    }
+}
+
+
+impl<T: IntoResponse, E: Into<HttpError>> IntoResponse for Result<T, E> {
+    fn into_response(self) -> Result<Response<Bytes>, HttpError> {
+        return self.map_err(|error| error.into()).and_then(|value| value.into_response());
+    }
+}
+
+
+impl IntoResponse for HttpError {
+    fn into_response(self) -> Result<Response<Bytes>, HttpError> {
+        return Err(self);
+    }
+}
+
+
+impl<T: IntoResponse> IntoResponse for (StatusCode, T) {
+    fn into_response(self) -> Result<Response<Bytes>, HttpError> {
+        let (status, value) = self;
+        let mut response = value.into_response()?;
+        *response.status_mut() = status;
+        return Ok(response);
+    }
+}
+
+
+impl IntoResponse for String {
+    fn into_response(self) -> Result<Response<Bytes>, HttpError> {
+        return Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(Bytes::from(self))
+            .unwrap());
+    }
+}
+
+
+impl IntoResponse for &'static str {
+    fn into_response(self) -> Result<Response<Bytes>, HttpError> {
+        return Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(Bytes::from_static(self.as_bytes()))
+            .unwrap());
+    }
+}
+
+
+impl IntoResponse for Bytes {
+    fn into_response(self) -> Result<Response<Bytes>, HttpError> {
+        return Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/octet-stream")
+            .body(self)
+            .unwrap());
+    }
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_ok() {
+        let response = StatusCode::NO_CONTENT.into_response().unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[test]
+    fn status_error() {
+        assert_eq!(StatusCode::NOT_FOUND.into_response().unwrap_err(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn string() {
+        let response = String::from("hello").into_response().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get("Content-Type").unwrap(), "text/plain; charset=utf-8");
+        assert_eq!(response.body(), &Bytes::from_static(b"hello"));
+    }
+
+    #[test]
+    fn status_and_str() {
+        let response = (StatusCode::CREATED, "created").into_response().unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.body(), &Bytes::from_static(b"created"));
+    }
+
+    #[test]
+    fn result_err() {
+        let result: Result<String, StatusCode> = Err(StatusCode::FORBIDDEN);
+        assert_eq!(result.into_response().unwrap_err(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn result_http_error() {
+        let result: Result<String, HttpError> = Err(HttpError::bad_request("missing name"));
+        let error = result.into_response().unwrap_err();
+        assert_eq!(error, StatusCode::BAD_REQUEST);
+        assert_eq!(error.message(), Some("missing name"));
+    }
+
+}
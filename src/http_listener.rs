@@ -0,0 +1,179 @@
+
+// A non-blocking listener bound to an HttpBindAddress, polled every frame
+// by http_accept_connections. A Unix socket file is removed again when the
+// listener is dropped, unless another listener has replaced it since, and a
+// stale one left behind by a crash is replaced.
+
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+
+use super::HttpBindAddress;
+use super::HttpClientConnection;
+
+
+pub enum HttpListener {
+    Tcp(TcpListener),
+    // The device and inode numbers identify the socket file created by bind()
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf, (u64, u64)),
+}
+
+
+impl HttpListener {
+
+    pub fn bind(bind_address: &HttpBindAddress) -> std::io::Result<Self> {
+        let listener = match bind_address {
+            HttpBindAddress::Tcp(address) => HttpListener::Tcp(TcpListener::bind(*address)?),
+            #[cfg(unix)]
+            HttpBindAddress::Unix(path) => {
+                // Nobody answering means the socket file is stale. Any other
+                // kind of file is left alone and binding fails.
+                if is_socket(path)? && UnixStream::connect(path).is_err() {
+                    std::fs::remove_file(path)?;
+                }
+                let listener = UnixListener::bind(path)?;
+                let metadata = std::fs::symlink_metadata(path)?;
+                HttpListener::Unix(listener, path.clone(), (metadata.dev(), metadata.ino()))
+            }
+            #[cfg(not(unix))]
+            HttpBindAddress::Unix(_) => {
+                return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "unix sockets are not supported on this platform"));
+            }
+        };
+        listener.set_nonblocking(true)?;
+        return Ok(listener);
+    }
+
+    // The address actually bound, e.g. the port chosen when binding to port 0
+    pub fn local_addr(&self) -> std::io::Result<HttpBindAddress> {
+        match self {
+            HttpListener::Tcp(listener) => return Ok(HttpBindAddress::Tcp(listener.local_addr()?)),
+            #[cfg(unix)]
+            HttpListener::Unix(_, path, _) => return Ok(HttpBindAddress::Unix(path.clone())),
+        }
+    }
+
+    // Returns ErrorKind::WouldBlock if no connections are waiting. Other errors,
+    // e.g. running out of file descriptors, become HttpServerError::AcceptFailed.
+    pub fn accept(&self) -> std::io::Result<HttpClientConnection> {
+        match self {
+            HttpListener::Tcp(listener) => {
+                let (stream, peer) = listener.accept()?;
+                return HttpClientConnection::new(stream, peer);
+            }
+            #[cfg(unix)]
+            HttpListener::Unix(listener, _, _) => {
+                let (stream, _) = listener.accept()?;
+                return HttpClientConnection::from_unix(stream);
+            }
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        match self {
+            HttpListener::Tcp(listener) => return listener.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            HttpListener::Unix(listener, _, _) => return listener.set_nonblocking(nonblocking),
+        }
+    }
+
+}
+
+
+impl Drop for HttpListener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let HttpListener::Unix(_, path, id) = self {
+            // Someone else may have bound a new socket at this path since
+            let unchanged = std::fs::symlink_metadata(&path).map_or(false, |metadata| (metadata.dev(), metadata.ino()) == *id);
+            if unchanged { let _ = std::fs::remove_file(path); }
+        }
+    }
+}
+
+
+// Helper function for HttpListener::bind(), false if there is no file at all
+#[cfg(unix)]
+fn is_socket(path: &Path) -> std::io::Result<bool> {
+    match std::fs::symlink_metadata(path) {
+        Err(error) => {
+            if error.kind() == std::io::ErrorKind::NotFound { return Ok(false); }
+            return Err(error);
+        }
+        Ok(metadata) => return Ok(metadata.file_type().is_socket()),
+    }
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use crate::HttpClientAddress;
+
+    use super::*;
+
+    #[test]
+    fn bind_tcp() {
+        let listener = HttpListener::bind(&HttpBindAddress::Tcp("127.0.0.1:0".parse().unwrap())).unwrap();
+        match listener.local_addr().unwrap() {
+            HttpBindAddress::Tcp(address) => assert_ne!(address.port(), 0),
+            _ => panic!("expected a tcp address"),
+        }
+        assert_eq!(listener.accept().err().map(|error| error.kind()), Some(std::io::ErrorKind::WouldBlock));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bind_unix() {
+        let path = std::env::temp_dir().join(format!("bevy_httpserver_bind_unix_{}.sock", std::process::id()));
+        let listener = HttpListener::bind(&HttpBindAddress::unix(&path)).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), HttpBindAddress::unix(&path));
+        assert_eq!(listener.accept().err().map(|error| error.kind()), Some(std::io::ErrorKind::WouldBlock));
+
+        let _client = UnixStream::connect(&path).unwrap();
+        let connection = listener.accept().unwrap();
+        assert_eq!(connection.peer(), HttpClientAddress::Unix(None));
+
+        drop(listener);
+        assert_eq!(path.exists(), false);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bind_unix_stale() {
+        let path = std::env::temp_dir().join(format!("bevy_httpserver_bind_unix_stale_{}.sock", std::process::id()));
+        drop(UnixListener::bind(&path).unwrap()); // Leaves the socket file behind
+        let stale = UnixListener::bind(&path);
+        assert_eq!(stale.is_err(), true);
+        let _listener = HttpListener::bind(&HttpBindAddress::unix(&path)).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bind_unix_regular_file() {
+        let path = std::env::temp_dir().join(format!("bevy_httpserver_bind_unix_file_{}.sock", std::process::id()));
+        std::fs::write(&path, b"not a socket").unwrap();
+        assert_eq!(HttpListener::bind(&HttpBindAddress::unix(&path)).is_err(), true);
+        assert_eq!(std::fs::read(&path).unwrap(), b"not a socket");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn drop_unix_replaced() {
+        let path = std::env::temp_dir().join(format!("bevy_httpserver_drop_unix_replaced_{}.sock", std::process::id()));
+        let old = HttpListener::bind(&HttpBindAddress::unix(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let new = HttpListener::bind(&HttpBindAddress::unix(&path)).unwrap();
+        drop(old);
+        assert_eq!(path.exists(), true);
+        drop(new);
+        assert_eq!(path.exists(), false);
+    }
+
+}
@@ -0,0 +1,228 @@
+
+// Code running before and after the handlers, e.g. for authentication,
+// logging or adding headers to every response. Middleware added with
+// HttpServerPlugin::with_middleware() wraps every request to that server,
+// including those answered with 404 Not Found. Middleware added with
+// HttpRequestHandler::with_middleware() only wraps requests routed to that
+// handler or one of its children.
+//
+// before() is called outermost first: server middleware, then the handler
+// tree from the root down. after() is called in the reverse order, and only
+// for middleware whose before() was called.
+//
+// after() does not run for responses deferred with HttpResponseSender, at any
+// level of the chain. The response sent later goes to the client as it is,
+// so headers every response needs must be added by whoever sends it. The
+// deferred response itself is passed up unchanged, as after() could lose the
+// marker telling http_request_responder not to answer the request.
+
+use std::sync::Arc;
+
+use bevy::prelude::*;
+use http::{Request, Response};
+use bytes::Bytes;
+
+use super::HttpError;
+use super::HttpResponseSender;
+
+pub(crate) type SharedMiddleware = Arc<dyn Middleware + Send + Sync>;
+
+
+pub trait Middleware {
+
+    // Return Ok(None) to pass the request on, or answer it here with
+    // Ok(Some(response)) or Err(error) without calling the handler
+    fn before(&self, _world: &mut World, _request: &Request<Bytes>) -> Result<Option<Response<Bytes>>, HttpError> {
+        return Ok(None);
+    }
+
+    // Called with the response of the handler, or of a before() that
+    // answered the request. Errors have been turned into responses already.
+    // Not called when the response is deferred, see HttpResponseSender.
+    fn after(&self, _world: &mut World, _request: &Request<Bytes>, _response: &mut Response<Bytes>) {
+    }
+
+}
+
+
+// Run handler wrapped in the middleware chain, used by HttpRequestHandler
+// and http_request_responder. Errors with a source are logged here, see
+// error_response().
+pub(crate) fn apply_middleware<F>(middleware: &[SharedMiddleware], world: &mut World, request: &Request<Bytes>, handler: F) -> Response<Bytes>
+where
+    F: FnOnce(&mut World) -> Result<Response<Bytes>, HttpError>
+{
+    let mut answered = None;
+    let mut called = 0;
+    for layer in middleware.iter() {
+        called += 1;
+        match layer.before(world, request) {
+            Ok(None) => continue,
+            Ok(Some(response)) => answered = Some(Ok(response)),
+            Err(error) => answered = Some(Err(error)),
+        }
+        break;
+    }
+
+    let result = match answered {
+        Some(result) => result,
+        None => handler(world),
+    };
+    let mut response = match result {
+        Err(error) => error_response(request, error),
+        Ok(response) => response,
+    };
+    if HttpResponseSender::is_deferred(&response) { return response; }
+
+    for layer in middleware[..called].iter().rev() {
+        layer.after(world, request, &mut response);
+    }
+    return response;
+}
+
+
+// Helper function for apply_middleware()
+fn error_response(request: &Request<Bytes>, error: HttpError) -> Response<Bytes> {
+    if let Some(source) = logged_source(&error) {
+        warn!("{} {} failed with {}: {}", request.method(), request.uri().path(), error, source);
+    }
+    return error.response();
+}
+
+
+// Helper function for error_response(). Server errors are logged, and so are
+// I/O errors whatever their status, e.g. a missing file giving 404 Not Found.
+fn logged_source(error: &HttpError) -> Option<&(dyn std::error::Error + 'static)> {
+    let source = std::error::Error::source(error)?;
+    if error.status().is_server_error() || source.is::<std::io::Error>() { return Some(source); }
+    return None;
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use super::*;
+    use http::{HeaderValue, StatusCode};
+
+    #[derive(Resource, Default)]
+    struct Calls(Vec<String>);
+
+    struct TestMiddleware {
+        name: &'static str,
+        answer: Option<StatusCode>,
+    }
+
+    impl Middleware for TestMiddleware {
+        fn before(&self, world: &mut World, _request: &Request<Bytes>) -> Result<Option<Response<Bytes>>, HttpError> {
+            world.resource_mut::<Calls>().0.push(format!("before {}", self.name));
+            match self.answer {
+                None => return Ok(None),
+                Some(status) => return Err(HttpError::new(status)),
+            }
+        }
+
+        fn after(&self, world: &mut World, _request: &Request<Bytes>, response: &mut Response<Bytes>) {
+            world.resource_mut::<Calls>().0.push(format!("after {}", self.name));
+            response.headers_mut().append("X-Middleware", HeaderValue::from_static(self.name));
+        }
+    }
+
+    fn layer(name: &'static str, answer: Option<StatusCode>) -> SharedMiddleware {
+        return Arc::new(TestMiddleware { name, answer });
+    }
+
+    fn run(middleware: &[SharedMiddleware], result: Result<Response<Bytes>, HttpError>) -> (Response<Bytes>, Vec<String>) {
+        let mut world = World::new();
+        world.init_resource::<Calls>();
+        let request = Request::builder().uri("/").body(Bytes::new()).unwrap();
+        let response = apply_middleware(middleware, &mut world, &request, |world| {
+            world.resource_mut::<Calls>().0.push(String::from("handler"));
+            return result;
+        });
+        let calls = world.remove_resource::<Calls>().unwrap().0;
+        return (response, calls);
+    }
+
+    #[test]
+    fn order() {
+        let ok = Response::new(Bytes::from_static(b"ok"));
+        let (response, calls) = run(&[layer("outer", None), layer("inner", None)], Ok(ok));
+        assert_eq!(calls, vec!["before outer", "before inner", "handler", "after inner", "after outer"]);
+        let names: Vec<&str> = response.headers().get_all("X-Middleware").iter().map(|value| value.to_str().unwrap()).collect();
+        assert_eq!(names, vec!["inner", "outer"]);
+        assert_eq!(response.into_body(), Bytes::from_static(b"ok"));
+    }
+
+    #[test]
+    fn short_circuit() {
+        let ok = Response::new(Bytes::from_static(b"ok"));
+        let (response, calls) = run(&[layer("outer", None), layer("auth", Some(StatusCode::UNAUTHORIZED)), layer("inner", None)], Ok(ok));
+        assert_eq!(calls, vec!["before outer", "before auth", "after auth", "after outer"]);
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn handler_error() {
+        let (response, calls) = run(&[layer("outer", None)], Err(HttpError::new(StatusCode::NOT_FOUND).with_message("no such player")));
+        assert_eq!(calls, vec!["before outer", "handler", "after outer"]);
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers().get("X-Middleware").unwrap(), "outer");
+        assert_eq!(response.into_body(), Bytes::from_static(b"404 Not Found: no such player"));
+    }
+
+    #[test]
+    fn deferred() {
+        let (response, calls) = run(&[layer("outer", None)], Ok(HttpResponseSender::deferred()));
+        assert_eq!(calls, vec!["before outer", "handler"]);
+        assert_eq!(HttpResponseSender::is_deferred(&response), true);
+    }
+
+    #[test]
+    fn deferred_by_before() {
+        struct Defer;
+
+        impl Middleware for Defer {
+            fn before(&self, _world: &mut World, _request: &Request<Bytes>) -> Result<Option<Response<Bytes>>, HttpError> {
+                return Ok(Some(HttpResponseSender::deferred()));
+            }
+        }
+
+        let ok = Response::new(Bytes::from_static(b"ok"));
+        let (response, calls) = run(&[layer("outer", None), Arc::new(Defer)], Ok(ok));
+        assert_eq!(calls, vec!["before outer"]);
+        assert_eq!(HttpResponseSender::is_deferred(&response), true);
+    }
+
+    #[test]
+    fn deferred_nested() {
+        // Handler middleware inside server middleware, as with HttpRequestHandler::respond()
+        let mut world = World::new();
+        world.init_resource::<Calls>();
+        let request = Request::builder().uri("/").body(Bytes::new()).unwrap();
+        let response = apply_middleware(&[layer("server", None)], &mut world, &request, |world| {
+            return Ok(apply_middleware(&[layer("handler", None)], world, &request, |_world| Ok(HttpResponseSender::deferred())));
+        });
+        assert_eq!(world.resource::<Calls>().0, vec!["before server", "before handler"]);
+        assert_eq!(HttpResponseSender::is_deferred(&response), true);
+        assert_eq!(response.headers().contains_key("X-Middleware"), false);
+    }
+
+    #[test]
+    fn logged() {
+        assert_eq!(logged_source(&HttpError::internal("disk full")).is_some(), true);
+        assert_eq!(logged_source(&HttpError::new(StatusCode::INTERNAL_SERVER_ERROR)).is_none(), true);
+        let missing = HttpError::from(std::io::Error::new(std::io::ErrorKind::NotFound, "config.toml"));
+        assert_eq!(logged_source(&missing).unwrap().to_string(), "config.toml");
+        let parse = HttpError::from("x".parse::<u32>().unwrap_err());
+        assert_eq!(logged_source(&parse).is_none(), true);
+    }
+
+    #[test]
+    fn no_middleware() {
+        let (response, calls) = run(&[], Err(HttpError::new(StatusCode::IM_A_TEAPOT)));
+        assert_eq!(calls, vec!["handler"]);
+        assert_eq!(response.status(), StatusCode::IM_A_TEAPOT);
+    }
+
+}
@@ -14,23 +14,13 @@ impl HttpPath {
         return HttpPath::default();
     }
 
-    pub fn to_string(&self) -> String {
-        let mut result = String::new();
-        if self.parts.len() > 0 {
-            if self.parts.len() == 1 {
-                result.push_str("/");
-            } else {
-                result.push_str(self.parts.join("/").as_str());
-            }
-        }
-        return result;
-    }
-
+    #[allow(dead_code)]
     pub fn push(&mut self, str: &str) {
-        if self.parts.len() == 0 { self.parts.push(String::new()); }
+        if self.parts.is_empty() { self.parts.push(String::new()); }
         self.parts.push(String::from(str));
     }
 
+    #[allow(dead_code)]
     pub fn starts_with(&self, other: &Self) -> bool {
         if self.parts.len() < other.parts.len() { return false; }
         for (i, part) in other.parts.iter().enumerate() {
@@ -39,6 +29,41 @@ impl HttpPath {
         return true;
     }
 
+    pub fn len(&self) -> usize {
+        return self.parts.len();
+    }
+
+    pub fn get(&self, index: usize) -> Option<&str> {
+        return self.parts.get(index).map(|part| part.as_str());
+    }
+
+    // Join all parts from index onwards, without a leading slash
+    pub fn tail(&self, index: usize) -> String {
+        if index >= self.parts.len() { return String::new(); }
+        return self.parts[index..].join("/");
+    }
+
+}
+
+
+// Decode %XX escapes in a path segment. Invalid escapes are kept as-is
+// and invalid UTF-8 is replaced rather than rejected.
+pub fn percent_decode(str: &str) -> String {
+    let bytes = str.as_bytes();
+    let mut decoded = Vec::<u8>::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() 
+            && bytes[i+1].is_ascii_hexdigit() && bytes[i+2].is_ascii_hexdigit() {
+            let hex = std::str::from_utf8(&bytes[i+1..i+3]).unwrap();
+            decoded.push(u8::from_str_radix(hex, 16).unwrap());
+            i += 3;
+            continue;
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    return String::from_utf8_lossy(&decoded).into_owned();
 }
 
 
@@ -46,14 +71,14 @@ impl From<&str> for HttpPath {
 
     fn from(str: &str) -> Self {
         let mut path = HttpPath::new();
-        if str == "" { 
+        if str.is_empty() { 
             return path; 
         }
         if str == "/" {
             path.parts.push(String::new());
             return path;
         }
-        path.parts = str.split("/").map(|str| String::from(str)).collect();
+        path.parts = str.split("/").map(String::from).collect();
         return path;
     }
 
@@ -62,7 +87,8 @@ impl From<&str> for HttpPath {
 
 impl std::fmt::Display for HttpPath {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.to_string())
+        if self.parts.len() == 1 { return write!(f, "/"); }
+        return write!(f, "{}", self.parts.join("/"));
     }
 }
 
@@ -195,35 +221,35 @@ mod tests {
     fn empty_to_string() {
         let path = HttpPath::from("");
         let facit = String::from("");
-        assert_eq!(format!("{}",path.to_string()), format!("{}",facit))
+        assert_eq!(path.to_string(), facit)
     }
 
     #[test]
     fn root_to_string() {
         let path = HttpPath::from("/");
         let facit = String::from("/");
-        assert_eq!(format!("{}",path.to_string()), format!("{}",facit))
+        assert_eq!(path.to_string(), facit)
     }
 
     #[test]
     fn foo_to_string() {
         let path = HttpPath::from("/foo");
         let facit = String::from("/foo");
-        assert_eq!(format!("{}",path.to_string()), format!("{}",facit))
+        assert_eq!(path.to_string(), facit)
     }
 
     #[test]
     fn foo_bar_to_string() {
         let path = HttpPath::from("/foo/bar");
         let facit = String::from("/foo/bar");
-        assert_eq!(format!("{}",path.to_string()), format!("{}",facit))
+        assert_eq!(path.to_string(), facit)
     }
 
     #[test]
     fn foo_bar_baz_to_string() {
         let path = HttpPath::from("/foo/bar/baz");
         let facit = String::from("/foo/bar/baz");
-        assert_eq!(format!("{}",path.to_string()), format!("{}",facit))
+        assert_eq!(path.to_string(), facit)
     }
 
     #[test]
@@ -331,5 +357,46 @@ mod tests {
         assert_eq!(a.starts_with(&b), false)
     }
 
+    #[test]
+    fn foobar_len() {
+        let path = HttpPath::from("/foo/bar");
+        assert_eq!(path.len(), 3)
+    }
+
+    #[test]
+    fn foobar_get() {
+        let path = HttpPath::from("/foo/bar");
+        assert_eq!(path.get(1), Some("foo"));
+        assert_eq!(path.get(3), None);
+    }
+
+    #[test]
+    fn foobarbaz_tail() {
+        let path = HttpPath::from("/foo/bar/baz");
+        assert_eq!(path.tail(2), String::from("bar/baz"));
+        assert_eq!(path.tail(4), String::from(""));
+    }
+
+    #[test]
+    fn percent_decode_plain() {
+        assert_eq!(percent_decode("foo"), String::from("foo"))
+    }
+
+    #[test]
+    fn percent_decode_space() {
+        assert_eq!(percent_decode("foo%20bar"), String::from("foo bar"))
+    }
+
+    #[test]
+    fn percent_decode_utf8() {
+        assert_eq!(percent_decode("bl%C3%A5b%C3%A6r"), String::from("blåbær"))
+    }
+
+    #[test]
+    fn percent_decode_invalid() {
+        assert_eq!(percent_decode("100%"), String::from("100%"));
+        assert_eq!(percent_decode("%zz"), String::from("%zz"));
+        assert_eq!(percent_decode("%+1"), String::from("%+1"));
+    }
 
 }
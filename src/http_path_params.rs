@@ -0,0 +1,75 @@
+
+// Values captured from the request path by ":name" and "*name" handlers,
+// keyed by name without the leading ":" or "*"
+
+use std::collections::HashMap;
+
+
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct HttpPathParams {
+    params: HashMap<String, String>,
+}
+
+
+impl HttpPathParams {
+
+    pub fn new() -> Self {
+        return HttpPathParams::default();
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        return self.params.get(name).map(|value| value.as_str());
+    }
+
+    pub fn insert(&mut self, name: &str, value: String) {
+        self.params.insert(name.to_owned(), value);
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        return self.params.remove(name);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.params.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.params.is_empty();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        return self.params.iter().map(|(name, value)| (name.as_str(), value.as_str()));
+    }
+
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_is_empty() {
+        let params = HttpPathParams::new();
+        assert_eq!(params.is_empty(), true);
+        assert_eq!(params.len(), 0);
+    }
+
+    #[test]
+    fn insert_get() {
+        let mut params = HttpPathParams::new();
+        params.insert("id", String::from("42"));
+        assert_eq!(params.get("id"), Some("42"));
+        assert_eq!(params.get("name"), None);
+    }
+
+    #[test]
+    fn insert_remove() {
+        let mut params = HttpPathParams::new();
+        params.insert("id", String::from("42"));
+        assert_eq!(params.remove("id"), Some(String::from("42")));
+        assert_eq!(params.is_empty(), true);
+    }
+
+}
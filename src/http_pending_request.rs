@@ -0,0 +1,68 @@
+/*
+With HttpRequestMode::Entities, every incoming request is spawned as an entity
+with an HttpPendingRequest, an HttpClientAddress and an HttpServerName component,
+and announced with an HttpRequestEvent. Any ordinary system can answer it by inserting an
+HttpResponse component on that entity:
+
+    fn answer_requests(
+        query: Query<(Entity, &HttpPendingRequest), Without<HttpResponse>>,
+        mut commands: Commands,
+    ) {
+        for (entity, pending) in query.iter() {
+            let response = Response::builder()
+                .status(StatusCode::OK)
+                .body(Bytes::from(format!("you asked for {}", pending.request().uri())))
+                .unwrap();
+            commands.entity(entity).insert(HttpResponse(response));
+        }
+    }
+
+Requests are spawned in CoreSet::PreUpdate and responses are collected in
+CoreSet::PostUpdate, so a request can be answered in the frame it arrived.
+The entity is despawned once the response is sent or the request has timed out.
+
+See also: http_request_dispatcher, http_response_collector
+*/
+
+use bevy::prelude::*;
+
+use http::{Request, Response};
+use bytes::Bytes;
+
+use super::HttpResponseSender;
+
+
+#[derive(Component)]
+pub struct HttpPendingRequest {
+    request: Request<Bytes>,
+    sender: HttpResponseSender,
+}
+
+
+impl HttpPendingRequest {
+
+    pub fn new(request: Request<Bytes>, sender: HttpResponseSender) -> Self {
+        HttpPendingRequest {
+            request,
+            sender,
+        }
+    }
+
+    pub fn request(&self) -> &Request<Bytes> {
+        return &self.request;
+    }
+
+    pub fn sender(&self) -> &HttpResponseSender {
+        return &self.sender;
+    }
+
+}
+
+
+#[derive(Component)]
+pub struct HttpResponse(pub Response<Bytes>);
+
+
+pub struct HttpRequestEvent {
+    pub entity: Entity,
+}
@@ -0,0 +1,778 @@
+
+// Async reading and writing of HTTP/1.x messages for HttpConnectionServer,
+// this code is meant only to cover the very specific needs of HttpServerPlugin
+
+use smol::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use http::{Request, Response, StatusCode, Method, HeaderName, HeaderValue, HeaderMap, Uri, Version};
+use bytes::Bytes;
+
+use super::HttpServerSettings;
+
+
+// Wait for the first byte of the next request without consuming anything.
+// Returns false if the connection was closed by the peer.
+pub async fn wait_for_data<R>(reader: &mut R) -> bool
+where
+    R: AsyncBufRead + Unpin
+{
+    match reader.fill_buf().await {
+        Ok(buffer) => return !buffer.is_empty(),
+        Err(_) => return false,
+    }
+}
+
+
+// Read the request line and headers, the body is left for read_body().
+// Nothing beyond the size limits is buffered: a long request line gives
+// 414 URI Too Long, too many or too long headers give 431 Request Header
+// Fields Too Large and a Content-Length over the limit gives 413 Payload Too Large.
+// The body framing is checked here too, see body_length().
+pub async fn read_head<R>(reader: &mut R, settings: &HttpServerSettings) -> Result<Option<Request<Bytes>>, StatusCode>
+where
+    R: AsyncBufRead + Unpin
+{
+    // Skip empty lines between requests, see RFC 7230 section 3.5
+    let request_line = loop {
+        match read_line(reader, settings.max_request_line(), StatusCode::URI_TOO_LONG).await? {
+            None => return Ok(None), // Connection closed by peer
+            Some(line) => if !line.is_empty() { break line; }
+        }
+    };
+
+    let mut request = Request::new(Bytes::new());
+    let mut parts = request_line.split(' ');
+    let (method, uri, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(uri), Some(version), None) => (method, uri, version),
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+    *request.method_mut() = Method::from_bytes(method.as_bytes()).map_err(|_| StatusCode::BAD_REQUEST)?;
+    *request.uri_mut() = uri.parse::<Uri>().map_err(|_| StatusCode::BAD_REQUEST)?;
+    *request.version_mut() = parse_version(version)?;
+
+    let mut header_bytes = 0;
+    loop {
+        let max_line = settings.max_header_bytes().saturating_sub(header_bytes + 2);
+        let line = match read_line(reader, max_line, StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE).await? {
+            None => return Err(StatusCode::BAD_REQUEST), // Connection closed mid-request
+            Some(line) => line,
+        };
+        if line.is_empty() { break; }
+        header_bytes += line.len() + 2;
+        if request.headers().len() >= settings.max_headers() { return Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE); }
+        let (name, value) = line.split_once(':').ok_or(StatusCode::BAD_REQUEST)?;
+        let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| StatusCode::BAD_REQUEST)?;
+        let value = HeaderValue::from_str(value.trim()).map_err(|_| StatusCode::BAD_REQUEST)?;
+        request.headers_mut().append(name, value);
+    }
+
+    if body_length(request.headers())?.unwrap_or(0) > settings.max_body_bytes() {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    return Ok(Some(request));
+}
+
+
+// Read the body announced by the headers returned from read_head(), see HttpBodyDecoder
+pub async fn read_body<R>(reader: &mut R, request: &mut Request<Bytes>, settings: &HttpServerSettings) -> Result<(), StatusCode>
+where
+    R: AsyncBufRead + Unpin
+{
+    let mut decoder = HttpBodyDecoder::new(request.headers(), settings)?;
+    let mut body = Vec::new();
+    while let Some(piece) = decoder.next(reader).await? {
+        body.extend_from_slice(&piece);
+    }
+    *request.body_mut() = Bytes::from(body);
+    return Ok(());
+}
+
+
+// True if the final transfer coding is chunked, i.e. the request body is
+// chunked rather than Content-Length delimited
+pub fn is_chunked(headers: &HeaderMap) -> bool {
+    return transfer_codings(headers).last().map_or(false, |coding| coding.eq_ignore_ascii_case("chunked"));
+}
+
+
+// Announced body size, None if chunked. Chunked is the only transfer coding
+// supported, others give 501 Not Implemented. Transfer-Encoding along with
+// Content-Length is a request smuggling attempt and gives 400 Bad Request,
+// see RFC 7230 section 3.3.3.
+pub fn body_length(headers: &HeaderMap) -> Result<Option<usize>, StatusCode> {
+    if !headers.contains_key("Transfer-Encoding") {
+        return content_length(headers).map(Some);
+    }
+    if headers.contains_key("Content-Length") { return Err(StatusCode::BAD_REQUEST); }
+    if transfer_codings(headers).len() != 1 || !is_chunked(headers) { return Err(StatusCode::NOT_IMPLEMENTED); }
+    return Ok(None);
+}
+
+
+// True if the client waits for 100 Continue before sending the body
+pub fn expects_continue(request: &Request<Bytes>) -> bool {
+    if request.version() != Version::HTTP_11 { return false; }
+    match request.headers().get("Expect") {
+        None => return false,
+        Some(value) => return value.as_bytes().eq_ignore_ascii_case(b"100-continue"),
+    }
+}
+
+
+// True if the client asked to keep the connection open for further requests
+pub fn keep_alive_requested(request: &Request<Bytes>) -> bool {
+    return has_connection_option(request.headers(), "keep-alive");
+}
+
+
+// True if the response tells the client the connection stays open
+pub fn keep_alive_granted(response: &Response<Bytes>) -> bool {
+    return has_connection_option(response.headers(), "keep-alive");
+}
+
+
+// True if the handler asked to close the connection after the response
+pub fn keep_alive_denied(response: &Response<Bytes>) -> bool {
+    return has_connection_option(response.headers(), "close");
+}
+
+
+// False for 1xx, 204 No Content and 304 Not Modified, which end with the
+// head and must not carry a body or Content-Length, see RFC 7230 section 3.3
+pub fn allows_body(status: StatusCode) -> bool {
+    return !status.is_informational() && status != StatusCode::NO_CONTENT && status != StatusCode::NOT_MODIFIED;
+}
+
+
+// Add a header unless the response has one by that name already. The values
+// given here are plain ASCII, anything else is left out.
+pub fn header_if_missing(response: &mut Response<Bytes>, name: &'static str, value: &str) {
+    if response.headers().contains_key(name) { return; }
+    if let Ok(value) = HeaderValue::from_str(value) {
+        response.headers_mut().insert(name, value);
+    }
+}
+
+
+// Interim response telling the client to go ahead with the body
+pub async fn write_continue<W>(writer: &mut W) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin
+{
+    writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+    writer.flush().await?;
+    return Ok(());
+}
+
+
+// Reads a request body piece by piece as it arrives, whether delimited by
+// Content-Length or sent with Transfer-Encoding: chunked. max_body_bytes is
+// checked as each chunk is announced, so nothing beyond it is ever read.
+pub struct HttpBodyDecoder {
+    chunked: bool,
+    remaining: usize, // Bytes left of the body, or of the current chunk if chunked
+    received: usize,
+    finished: bool,
+    max_body_bytes: usize,
+    max_header_bytes: usize,
+}
+
+
+impl HttpBodyDecoder {
+
+    pub fn new(headers: &HeaderMap, settings: &HttpServerSettings) -> Result<Self, StatusCode> {
+        let length = body_length(headers)?;
+        if length.unwrap_or(0) > settings.max_body_bytes() { return Err(StatusCode::PAYLOAD_TOO_LARGE); }
+        return Ok(HttpBodyDecoder {
+            chunked: length.is_none(),
+            remaining: length.unwrap_or(0),
+            received: 0,
+            finished: length == Some(0),
+            max_body_bytes: settings.max_body_bytes(),
+            max_header_bytes: settings.max_header_bytes(),
+        });
+    }
+
+    // The next piece of the body, whatever is buffered up to the end of the
+    // current chunk. Ok(None) means the body is complete.
+    pub async fn next<R>(&mut self, reader: &mut R) -> Result<Option<Bytes>, StatusCode>
+    where
+        R: AsyncBufRead + Unpin
+    {
+        if self.finished { return Ok(None); }
+        if self.chunked && self.remaining == 0 {
+            let size = self.read_chunk_size(reader).await?;
+            if size == 0 {
+                self.read_trailers(reader).await?;
+                self.finished = true;
+                return Ok(None);
+            }
+            if size > self.max_body_bytes - self.received { return Err(StatusCode::PAYLOAD_TOO_LARGE); }
+            self.remaining = size;
+        }
+
+        let buffer = reader.fill_buf().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if buffer.is_empty() { return Err(StatusCode::BAD_REQUEST); } // Connection closed mid-body
+        let length = buffer.len().min(self.remaining);
+        let piece = Bytes::copy_from_slice(&buffer[..length]);
+        reader.consume(length);
+        self.remaining -= length;
+        self.received += length;
+
+        if self.remaining == 0 {
+            if self.chunked {
+                // Every chunk is followed by an empty line
+                match read_line(reader, 0, StatusCode::BAD_REQUEST).await? {
+                    Some(line) if line.is_empty() => {}
+                    _ => return Err(StatusCode::BAD_REQUEST),
+                }
+            } else {
+                self.finished = true;
+            }
+        }
+        return Ok(Some(piece));
+    }
+
+    // Chunk size in hex, optionally followed by chunk extensions which are ignored
+    async fn read_chunk_size<R>(&mut self, reader: &mut R) -> Result<usize, StatusCode>
+    where
+        R: AsyncBufRead + Unpin
+    {
+        let line = read_line(reader, 1024, StatusCode::BAD_REQUEST).await?.ok_or(StatusCode::BAD_REQUEST)?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        if size.is_empty() || !size.bytes().all(|byte| byte.is_ascii_hexdigit()) { return Err(StatusCode::BAD_REQUEST); }
+        return usize::from_str_radix(size, 16).map_err(|_| StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    // Trailer fields after the last chunk are read within max_header_bytes and ignored
+    async fn read_trailers<R>(&mut self, reader: &mut R) -> Result<(), StatusCode>
+    where
+        R: AsyncBufRead + Unpin
+    {
+        let mut trailer_bytes = 0;
+        loop {
+            let max_line = self.max_header_bytes.saturating_sub(trailer_bytes + 2);
+            match read_line(reader, max_line, StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE).await? {
+                None => return Err(StatusCode::BAD_REQUEST), // Connection closed mid-request
+                Some(line) => {
+                    if line.is_empty() { return Ok(()); }
+                    trailer_bytes += line.len() + 2;
+                }
+            }
+        }
+    }
+
+}
+
+
+// Write the response exactly as given, except for a missing Content-Length.
+// A status that does not allow a body gets neither, see allows_body().
+pub async fn write_response<W>(response: Response<Bytes>, writer: &mut W) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin
+{
+    let body = allows_body(response.status());
+    let mut head = format_head(&response);
+    if body && !response.headers().contains_key("Content-Length") {
+        head.extend_from_slice(format!("Content-Length: {}\r\n", response.body().len()).as_bytes());
+    }
+    head.extend_from_slice(b"\r\n");
+
+    writer.write_all(&head).await?;
+    if body { writer.write_all(response.body()).await?; }
+    writer.flush().await?;
+    return Ok(());
+}
+
+
+// Write the status line and headers exactly as given, the body is written
+// afterwards with write_chunk()
+pub async fn write_head<W>(response: &Response<Bytes>, writer: &mut W) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin
+{
+    let mut head = format_head(response);
+    head.extend_from_slice(b"\r\n");
+    writer.write_all(&head).await?;
+    writer.flush().await?;
+    return Ok(());
+}
+
+
+// Write part of a body, framed as one chunk if chunked. Must not be empty
+// when chunked, since an empty chunk ends the body.
+pub async fn write_chunk<W>(chunk: &[u8], chunked: bool, writer: &mut W) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin
+{
+    if chunked { writer.write_all(format!("{:X}\r\n", chunk.len()).as_bytes()).await?; }
+    writer.write_all(chunk).await?;
+    if chunked { writer.write_all(b"\r\n").await?; }
+    writer.flush().await?;
+    return Ok(());
+}
+
+
+// End a chunked body
+pub async fn write_last_chunk<W>(writer: &mut W) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin
+{
+    writer.write_all(b"0\r\n\r\n").await?;
+    writer.flush().await?;
+    return Ok(());
+}
+
+
+// Read a line terminated by CRLF (or a bare LF) and return it without the line ending.
+// A line longer than max_len, not counting the line ending, gives Err(too_long).
+async fn read_line<R>(reader: &mut R, max_len: usize, too_long: StatusCode) -> Result<Option<String>, StatusCode>
+where
+    R: AsyncBufRead + Unpin
+{
+    let limit = (max_len as u64).saturating_add(2);
+    let mut line = Vec::<u8>::new();
+    let count = reader.take(limit).read_until(b'\n', &mut line).await.map_err(|_| StatusCode::BAD_REQUEST)?;
+    if count == 0 { return Ok(None); }
+    if line.last() != Some(&b'\n') {
+        if count as u64 == limit { return Err(too_long); }
+        return Err(StatusCode::BAD_REQUEST); // Connection closed mid-line
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') { line.pop(); }
+    return String::from_utf8(line).map(Some).map_err(|_| StatusCode::BAD_REQUEST);
+}
+
+
+// Status line and headers, without the empty line ending the head
+fn format_head(response: &Response<Bytes>) -> Vec<u8> {
+    let status = response.status();
+    let mut head = format!("{:?} {} {}\r\n", response.version(), status.as_str(), status.canonical_reason().unwrap_or("")).into_bytes();
+    for (name, value) in response.headers().iter() {
+        head.extend_from_slice(name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    return head;
+}
+
+
+fn parse_version(version: &str) -> Result<Version, StatusCode> {
+    match version {
+        "HTTP/1.1" => return Ok(Version::HTTP_11),
+        "HTTP/1.0" => return Ok(Version::HTTP_10),
+        _ => {
+            if version.starts_with("HTTP/") { return Err(StatusCode::HTTP_VERSION_NOT_SUPPORTED); }
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    }
+}
+
+
+// Helper function for is_chunked() and body_length(), the codings of every
+// Transfer-Encoding header in order. A value that is not text counts as an
+// unknown coding.
+fn transfer_codings(headers: &HeaderMap) -> Vec<String> {
+    let mut codings = Vec::new();
+    for value in headers.get_all("Transfer-Encoding").iter() {
+        let value = match value.to_str() {
+            Err(_) => { codings.push(String::new()); continue; }
+            Ok(value) => value,
+        };
+        for coding in value.split(',').map(|coding| coding.trim()).filter(|coding| !coding.is_empty()) {
+            codings.push(String::from(coding));
+        }
+    }
+    return codings;
+}
+
+
+// Helper function for keep_alive_requested() and friends, true if one of the
+// comma separated options of the Connection headers is option
+fn has_connection_option(headers: &HeaderMap, option: &str) -> bool {
+    for value in headers.get_all("Connection").iter() {
+        let value = match value.to_str() {
+            Err(_) => continue,
+            Ok(value) => value,
+        };
+        if value.split(',').any(|token| token.trim().eq_ignore_ascii_case(option)) { return true; }
+    }
+    return false;
+}
+
+
+// Helper function for body_length()
+fn content_length(headers: &HeaderMap) -> Result<usize, StatusCode> {
+    match headers.get("Content-Length") {
+        None => return Ok(0),
+        Some(value) => {
+            let value = value.to_str().map_err(|_| StatusCode::BAD_REQUEST)?;
+            return value.trim().parse::<usize>().map_err(|_| StatusCode::BAD_REQUEST);
+        }
+    }
+}
+
+
+// Client side counterparts, for testing
+#[cfg(test)]
+pub async fn write_request<W>(request: Request<Bytes>, writer: &mut W) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin
+{
+    let mut head = format!("{} {} {:?}\r\n", request.method().as_str(), request.uri(), request.version()).into_bytes();
+    for (name, value) in request.headers().iter() {
+        head.extend_from_slice(name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    if !request.body().is_empty() && !request.headers().contains_key("Content-Length") {
+        head.extend_from_slice(format!("Content-Length: {}\r\n", request.body().len()).as_bytes());
+    }
+    head.extend_from_slice(b"\r\n");
+
+    writer.write_all(&head).await?;
+    writer.write_all(request.body()).await?;
+    writer.flush().await?;
+    return Ok(());
+}
+
+
+#[cfg(test)]
+pub async fn read_response<R>(reader: &mut R) -> Result<Option<Response<Bytes>>, StatusCode>
+where
+    R: AsyncBufRead + Unpin
+{
+    let mut response = match read_response_head(reader).await? {
+        None => return Ok(None),
+        Some(response) => response,
+    };
+    let mut body = vec![0; content_length(response.headers())?];
+    reader.read_exact(&mut body).await.map_err(|_| StatusCode::BAD_REQUEST)?;
+    *response.body_mut() = Bytes::from(body);
+    return Ok(Some(response));
+}
+
+
+// The response to HEAD has no body whatever its Content-Length
+#[cfg(test)]
+pub async fn read_response_head<R>(reader: &mut R) -> Result<Option<Response<Bytes>>, StatusCode>
+where
+    R: AsyncBufRead + Unpin
+{
+    let status_line = match read_line(reader, usize::MAX, StatusCode::BAD_REQUEST).await? {
+        None => return Ok(None),
+        Some(line) => line,
+    };
+    let mut response = Response::new(Bytes::new());
+    let status = status_line.split(' ').nth(1).ok_or(StatusCode::BAD_REQUEST)?;
+    *response.status_mut() = StatusCode::from_bytes(status.as_bytes()).map_err(|_| StatusCode::BAD_REQUEST)?;
+    loop {
+        let line = read_line(reader, usize::MAX, StatusCode::BAD_REQUEST).await?.ok_or(StatusCode::BAD_REQUEST)?;
+        if line.is_empty() { break; }
+        let (name, value) = line.split_once(':').ok_or(StatusCode::BAD_REQUEST)?;
+        let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| StatusCode::BAD_REQUEST)?;
+        let value = HeaderValue::from_str(value.trim()).map_err(|_| StatusCode::BAD_REQUEST)?;
+        response.headers_mut().append(name, value);
+    }
+    return Ok(Some(response));
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use smol::future;
+    use smol::io::{BufReader, Cursor};
+
+    use super::*;
+
+    // Read one request the way HttpConnectionServer::run() does, head first
+    // and then the body
+    async fn read_head_and_body<R>(reader: &mut R, settings: &HttpServerSettings) -> Result<Option<Request<Bytes>>, StatusCode>
+    where
+        R: AsyncBufRead + Unpin
+    {
+        match read_head(reader, settings).await? {
+            None => return Ok(None),
+            Some(mut request) => {
+                read_body(reader, &mut request, settings).await?;
+                return Ok(Some(request));
+            }
+        }
+    }
+
+    fn parse(bytes: &'static [u8]) -> Result<Option<Request<Bytes>>, StatusCode> {
+        let mut reader = BufReader::new(Cursor::new(bytes));
+        return future::block_on(read_head_and_body(&mut reader, &HttpServerSettings::default()));
+    }
+
+    fn parse_with(bytes: &'static [u8], settings: HttpServerSettings) -> Result<Option<Request<Bytes>>, StatusCode> {
+        let mut reader = BufReader::new(Cursor::new(bytes));
+        return future::block_on(read_head_and_body(&mut reader, &settings));
+    }
+
+    #[test]
+    fn read_request_get() {
+        let request = parse(b"GET /foo?bar=1 HTTP/1.1\r\nHost: localhost\r\nX-Test:  spaces \r\n\r\n").unwrap().unwrap();
+        assert_eq!(request.method(), Method::GET);
+        assert_eq!(request.uri().path(), "/foo");
+        assert_eq!(request.uri().query(), Some("bar=1"));
+        assert_eq!(request.version(), Version::HTTP_11);
+        assert_eq!(request.headers().get("Host").unwrap(), "localhost");
+        assert_eq!(request.headers().get("X-Test").unwrap(), "spaces");
+        assert_eq!(request.body().len(), 0);
+    }
+
+    #[test]
+    fn read_request_post() {
+        let request = parse(b"POST / HTTP/1.0\r\nContent-Length: 5\r\n\r\nhello").unwrap().unwrap();
+        assert_eq!(request.method(), Method::POST);
+        assert_eq!(request.version(), Version::HTTP_10);
+        assert_eq!(request.body(), &Bytes::from_static(b"hello"));
+    }
+
+    #[test]
+    fn read_request_bare_lf() {
+        let request = parse(b"\nGET / HTTP/1.1\nHost: localhost\n\n").unwrap().unwrap();
+        assert_eq!(request.headers().get("Host").unwrap(), "localhost");
+    }
+
+    #[test]
+    fn read_request_closed() {
+        assert_eq!(parse(b"").unwrap().is_none(), true);
+    }
+
+    #[test]
+    fn read_request_truncated() {
+        assert_eq!(parse(b"GET / HTTP/1.1\r\nHost: loc").unwrap_err(), StatusCode::BAD_REQUEST);
+        assert_eq!(parse(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhel").unwrap_err(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn read_request_malformed() {
+        assert_eq!(parse(b"GET /\r\n\r\n").unwrap_err(), StatusCode::BAD_REQUEST);
+        assert_eq!(parse(b"GET / HTTP/1.1\r\nNo colon\r\n\r\n").unwrap_err(), StatusCode::BAD_REQUEST);
+        assert_eq!(parse(b"GET / HTTP/1.1\r\nContent-Length: x\r\n\r\n").unwrap_err(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn read_request_version() {
+        assert_eq!(parse(b"GET / HTTP/2.0\r\n\r\n").unwrap_err(), StatusCode::HTTP_VERSION_NOT_SUPPORTED);
+        assert_eq!(parse(b"GET / FTP/1.0\r\n\r\n").unwrap_err(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn read_request_two() {
+        let mut reader = BufReader::new(Cursor::new(&b"GET /one HTTP/1.1\r\n\r\nGET /two HTTP/1.1\r\n\r\n"[..]));
+        let one = future::block_on(read_head_and_body(&mut reader, &HttpServerSettings::default())).unwrap().unwrap();
+        let two = future::block_on(read_head_and_body(&mut reader, &HttpServerSettings::default())).unwrap().unwrap();
+        assert_eq!(one.uri().path(), "/one");
+        assert_eq!(two.uri().path(), "/two");
+        assert_eq!(future::block_on(read_head_and_body(&mut reader, &HttpServerSettings::default())).unwrap().is_none(), true);
+    }
+
+    #[test]
+    fn read_request_line_too_long() {
+        let settings = HttpServerSettings::new().with_max_request_line(19);
+        assert_eq!(parse_with(b"GET /12345 HTTP/1.1\r\n\r\n", settings).unwrap().is_some(), true);
+        assert_eq!(parse_with(b"GET /123456 HTTP/1.1\r\n\r\n", settings).unwrap_err(), StatusCode::URI_TOO_LONG);
+        assert_eq!(parse_with(b"GET /123456", settings).unwrap_err(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn read_request_too_many_headers() {
+        let settings = HttpServerSettings::new().with_max_headers(2);
+        assert_eq!(parse_with(b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\n\r\n", settings).unwrap().is_some(), true);
+        assert_eq!(parse_with(b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n", settings).unwrap_err(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    }
+
+    #[test]
+    fn read_request_headers_too_large() {
+        let settings = HttpServerSettings::new().with_max_header_bytes(16);
+        assert_eq!(parse_with(b"GET / HTTP/1.1\r\nA: 1\r\nB: 23456\r\n\r\n", settings).unwrap().is_some(), true);
+        assert_eq!(parse_with(b"GET / HTTP/1.1\r\nA: 1\r\nB: 234567\r\n\r\n", settings).unwrap_err(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+        assert_eq!(parse_with(b"GET / HTTP/1.1\r\nB: 234567890123456\r\n\r\n", settings).unwrap_err(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    }
+
+    #[test]
+    fn read_request_body_too_large() {
+        let settings = HttpServerSettings::new().with_max_body_bytes(4);
+        assert_eq!(parse_with(b"POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nabcd", settings).unwrap().is_some(), true);
+        assert_eq!(parse_with(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nabcde", settings).unwrap_err(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn wait_for_data() {
+        let mut reader = BufReader::new(Cursor::new(&b"GET / HTTP/1.1\r\n\r\n"[..]));
+        assert_eq!(future::block_on(super::wait_for_data(&mut reader)), true);
+        assert_eq!(future::block_on(read_head_and_body(&mut reader, &HttpServerSettings::default())).unwrap().is_some(), true);
+        assert_eq!(future::block_on(super::wait_for_data(&mut reader)), false);
+    }
+
+    #[test]
+    fn read_head_body() {
+        let mut reader = BufReader::new(Cursor::new(&b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello"[..]));
+        let mut request = future::block_on(read_head(&mut reader, &HttpServerSettings::default())).unwrap().unwrap();
+        assert_eq!(request.body().len(), 0);
+        future::block_on(read_body(&mut reader, &mut request, &HttpServerSettings::default())).unwrap();
+        assert_eq!(request.body(), &Bytes::from_static(b"hello"));
+    }
+
+    #[test]
+    fn read_request_chunked() {
+        let request = parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\nX-Trailer: 1\r\n\r\n").unwrap().unwrap();
+        assert_eq!(request.body(), &Bytes::from_static(b"hello, world"));
+    }
+
+    #[test]
+    fn read_request_chunked_two() {
+        let mut reader = BufReader::new(Cursor::new(&b"POST /one HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\nGET /two HTTP/1.1\r\n\r\n"[..]));
+        let one = future::block_on(read_head_and_body(&mut reader, &HttpServerSettings::default())).unwrap().unwrap();
+        let two = future::block_on(read_head_and_body(&mut reader, &HttpServerSettings::default())).unwrap().unwrap();
+        assert_eq!(one.body(), &Bytes::from_static(b"abc"));
+        assert_eq!(two.uri().path(), "/two");
+    }
+
+    #[test]
+    fn read_request_chunked_malformed() {
+        assert_eq!(parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nx\r\n").unwrap_err(), StatusCode::BAD_REQUEST);
+        assert_eq!(parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabcd\r\n0\r\n\r\n").unwrap_err(), StatusCode::BAD_REQUEST);
+        assert_eq!(parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nab").unwrap_err(), StatusCode::BAD_REQUEST);
+        assert_eq!(parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n").unwrap_err(), StatusCode::BAD_REQUEST);
+        assert_eq!(parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n").unwrap_err(), StatusCode::NOT_IMPLEMENTED);
+    }
+
+    #[test]
+    fn read_request_transfer_coding_not_chunked() {
+        assert_eq!(parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n").unwrap_err(), StatusCode::NOT_IMPLEMENTED);
+        assert_eq!(parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: gzip\r\n\r\n").unwrap_err(), StatusCode::NOT_IMPLEMENTED);
+        assert_eq!(parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n").unwrap_err(), StatusCode::NOT_IMPLEMENTED);
+        assert_eq!(parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: \r\n\r\n").unwrap_err(), StatusCode::NOT_IMPLEMENTED);
+        assert_eq!(parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: Chunked\r\n\r\n0\r\n\r\n").unwrap().is_some(), true);
+    }
+
+    #[test]
+    fn read_request_transfer_encoding_and_content_length() {
+        assert_eq!(parse(b"POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n").unwrap_err(), StatusCode::BAD_REQUEST);
+        assert_eq!(parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\nContent-Length: 3\r\n\r\n").unwrap_err(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn is_chunked() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(super::is_chunked(&headers), false);
+        headers.append("Transfer-Encoding", HeaderValue::from_static("gzip"));
+        assert_eq!(super::is_chunked(&headers), false);
+        headers.append("Transfer-Encoding", HeaderValue::from_static("chunked"));
+        assert_eq!(super::is_chunked(&headers), true);
+        assert_eq!(body_length(&headers), Err(StatusCode::NOT_IMPLEMENTED));
+        headers.append("Transfer-Encoding", HeaderValue::from_static("identity"));
+        assert_eq!(super::is_chunked(&headers), false);
+    }
+
+    #[test]
+    fn read_request_chunked_too_large() {
+        let settings = HttpServerSettings::new().with_max_body_bytes(4);
+        assert_eq!(parse_with(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nab\r\n2\r\ncd\r\n0\r\n\r\n", settings).unwrap().is_some(), true);
+        assert_eq!(parse_with(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nab\r\n3\r\ncde\r\n0\r\n\r\n", settings).unwrap_err(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(parse_with(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nFFFFFFFFFFFFFFFFFFFF\r\n", settings).unwrap_err(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn body_decoder_pieces() {
+        let mut reader = BufReader::with_capacity(4, Cursor::new(&b"6\r\nabcdef\r\n0\r\n\r\n"[..]));
+        let mut headers = HeaderMap::new();
+        headers.insert("Transfer-Encoding", HeaderValue::from_static("chunked"));
+        let mut decoder = HttpBodyDecoder::new(&headers, &HttpServerSettings::default()).unwrap();
+        let mut pieces = Vec::new();
+        while let Some(piece) = future::block_on(decoder.next(&mut reader)).unwrap() {
+            pieces.push(piece);
+        }
+        assert_eq!(pieces.len() > 1, true);
+        assert_eq!(pieces.concat(), b"abcdef".to_vec());
+    }
+
+    #[test]
+    fn expects_continue() {
+        let request = parse(b"POST / HTTP/1.1\r\nExpect: 100-continue\r\n\r\n").unwrap().unwrap();
+        assert_eq!(super::expects_continue(&request), true);
+        let request = parse(b"POST / HTTP/1.0\r\nExpect: 100-continue\r\n\r\n").unwrap().unwrap();
+        assert_eq!(super::expects_continue(&request), false);
+    }
+
+    #[test]
+    fn write_response_bytes() {
+        let response = Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .header("Content-Type", "text/plain")
+            .body(Bytes::from_static(b"missing"))
+            .unwrap();
+        let mut writer = Cursor::new(Vec::<u8>::new());
+        future::block_on(write_response(response, &mut writer)).unwrap();
+        let facit = b"HTTP/1.1 404 Not Found\r\ncontent-type: text/plain\r\nContent-Length: 7\r\n\r\nmissing";
+        assert_eq!(String::from_utf8_lossy(&writer.into_inner()), String::from_utf8_lossy(facit));
+    }
+
+    #[test]
+    fn write_response_no_content() {
+        let response = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Bytes::from_static(b"ignored"))
+            .unwrap();
+        let mut writer = Cursor::new(Vec::<u8>::new());
+        future::block_on(write_response(response, &mut writer)).unwrap();
+        let facit = b"HTTP/1.1 204 No Content\r\n\r\n";
+        assert_eq!(String::from_utf8_lossy(&writer.into_inner()), String::from_utf8_lossy(facit));
+        assert_eq!(allows_body(StatusCode::NOT_MODIFIED), false);
+        assert_eq!(allows_body(StatusCode::SWITCHING_PROTOCOLS), false);
+        assert_eq!(allows_body(StatusCode::OK), true);
+    }
+
+    #[test]
+    fn write_chunked() {
+        let response = Response::builder()
+            .header("Transfer-Encoding", "chunked")
+            .body(Bytes::new())
+            .unwrap();
+        let mut writer = Cursor::new(Vec::<u8>::new());
+        future::block_on(write_head(&response, &mut writer)).unwrap();
+        future::block_on(write_chunk(b"hello world, this is chunked", true, &mut writer)).unwrap();
+        future::block_on(write_chunk(b"!", true, &mut writer)).unwrap();
+        future::block_on(write_last_chunk(&mut writer)).unwrap();
+        let facit = b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n1C\r\nhello world, this is chunked\r\n1\r\n!\r\n0\r\n\r\n";
+        assert_eq!(String::from_utf8_lossy(&writer.into_inner()), String::from_utf8_lossy(facit));
+    }
+
+    #[test]
+    fn write_unframed() {
+        let mut writer = Cursor::new(Vec::<u8>::new());
+        future::block_on(write_chunk(b"hello", false, &mut writer)).unwrap();
+        assert_eq!(writer.into_inner(), b"hello".to_vec());
+    }
+
+    #[test]
+    fn write_request_read_response() {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/foo")
+            .body(Bytes::from_static(b"hello"))
+            .unwrap();
+        let mut writer = Cursor::new(Vec::<u8>::new());
+        future::block_on(write_request(request, &mut writer)).unwrap();
+        let mut reader = BufReader::new(Cursor::new(writer.into_inner()));
+        let request = future::block_on(read_head_and_body(&mut reader, &HttpServerSettings::default())).unwrap().unwrap();
+        assert_eq!(request.body(), &Bytes::from_static(b"hello"));
+
+        let response = Response::builder().status(StatusCode::OK).body(Bytes::from_static(b"world")).unwrap();
+        let mut writer = Cursor::new(Vec::<u8>::new());
+        future::block_on(write_response(response, &mut writer)).unwrap();
+        let mut reader = BufReader::new(Cursor::new(writer.into_inner()));
+        let response = future::block_on(read_response(&mut reader)).unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), &Bytes::from_static(b"world"));
+    }
+
+}
@@ -0,0 +1,178 @@
+
+// Request extractors for system-style handlers, see SystemHandlerFunction.
+// The first argument of such a handler is any type implementing FromHttpRequest,
+// all other arguments are Bevy SystemParams.
+
+use http::{Request, StatusCode};
+use bytes::Bytes;
+
+use super::HttpPathParams;
+use super::HttpResponseSender;
+
+
+pub trait FromHttpRequest: Sized {
+    fn from_http_request(request: &Request<Bytes>, params: &HttpPathParams) -> Result<Self, StatusCode>;
+}
+
+
+// An owned copy of the request and the path parameters captured while routing it
+pub struct HttpRequest {
+    request: Request<Bytes>,
+    params: HttpPathParams,
+}
+
+
+impl HttpRequest {
+
+    pub fn new(request: Request<Bytes>, params: HttpPathParams) -> Self {
+        HttpRequest {
+            request,
+            params,
+        }
+    }
+
+    pub fn request(&self) -> &Request<Bytes> {
+        return &self.request;
+    }
+
+    pub fn params(&self) -> &HttpPathParams {
+        return &self.params;
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        return self.params.get(name);
+    }
+
+    pub fn into_request(self) -> Request<Bytes> {
+        return self.request;
+    }
+
+}
+
+
+impl std::ops::Deref for HttpRequest {
+    type Target = Request<Bytes>;
+    fn deref(&self) -> &Self::Target {
+        return &self.request;
+    }
+}
+
+
+impl FromHttpRequest for HttpRequest {
+    fn from_http_request(request: &Request<Bytes>, params: &HttpPathParams) -> Result<Self, StatusCode> {
+        return Ok(HttpRequest::new(copy_request(request), params.clone()));
+    }
+}
+
+
+impl FromHttpRequest for Request<Bytes> {
+    fn from_http_request(request: &Request<Bytes>, _params: &HttpPathParams) -> Result<Self, StatusCode> {
+        return Ok(copy_request(request));
+    }
+}
+
+
+impl FromHttpRequest for HttpPathParams {
+    fn from_http_request(_request: &Request<Bytes>, params: &HttpPathParams) -> Result<Self, StatusCode> {
+        return Ok(params.clone());
+    }
+}
+
+
+impl FromHttpRequest for Bytes {
+    fn from_http_request(request: &Request<Bytes>, _params: &HttpPathParams) -> Result<Self, StatusCode> {
+        return Ok(request.body().clone());
+    }
+}
+
+
+impl FromHttpRequest for HttpResponseSender {
+    fn from_http_request(request: &Request<Bytes>, _params: &HttpPathParams) -> Result<Self, StatusCode> {
+        return HttpResponseSender::from_request(request).ok_or(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
+
+
+impl<A: FromHttpRequest, B: FromHttpRequest> FromHttpRequest for (A, B) {
+    fn from_http_request(request: &Request<Bytes>, params: &HttpPathParams) -> Result<Self, StatusCode> {
+        return Ok((A::from_http_request(request, params)?, B::from_http_request(request, params)?));
+    }
+}
+
+
+impl<A: FromHttpRequest, B: FromHttpRequest, C: FromHttpRequest> FromHttpRequest for (A, B, C) {
+    fn from_http_request(request: &Request<Bytes>, params: &HttpPathParams) -> Result<Self, StatusCode> {
+        return Ok((A::from_http_request(request, params)?, B::from_http_request(request, params)?, C::from_http_request(request, params)?));
+    }
+}
+
+
+// Request<T> does not implement Clone because of its extensions,
+// everything else is copied. Bytes bodies are reference counted.
+fn copy_request(request: &Request<Bytes>) -> Request<Bytes> {
+    let mut copy = Request::new(request.body().clone());
+    *copy.method_mut() = request.method().clone();
+    *copy.uri_mut() = request.uri().clone();
+    *copy.version_mut() = request.version();
+    *copy.headers_mut() = request.headers().clone();
+    return copy;
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use super::*;
+    use http::Method;
+
+    fn test_request() -> Request<Bytes> {
+        return Request::builder()
+            .method(Method::POST)
+            .uri("/players/42")
+            .header("Content-Type", "text/plain")
+            .body(Bytes::from_static(b"hello"))
+            .unwrap();
+    }
+
+    fn test_params() -> HttpPathParams {
+        let mut params = HttpPathParams::new();
+        params.insert("id", String::from("42"));
+        return params;
+    }
+
+    #[test]
+    fn http_request() {
+        let request = HttpRequest::from_http_request(&test_request(), &test_params()).unwrap();
+        assert_eq!(request.method(), Method::POST);
+        assert_eq!(request.uri().path(), "/players/42");
+        assert_eq!(request.headers().get("Content-Type").unwrap(), "text/plain");
+        assert_eq!(request.body(), &Bytes::from_static(b"hello"));
+        assert_eq!(request.param("id"), Some("42"));
+    }
+
+    #[test]
+    fn params() {
+        let params = HttpPathParams::from_http_request(&test_request(), &test_params()).unwrap();
+        assert_eq!(params.get("id"), Some("42"));
+    }
+
+    #[test]
+    fn response_sender_missing() {
+        let result = HttpResponseSender::from_http_request(&test_request(), &test_params());
+        assert_eq!(result.err(), Some(StatusCode::INTERNAL_SERVER_ERROR));
+    }
+
+    #[test]
+    fn tuple() {
+        let (params, body) = <(HttpPathParams, Bytes)>::from_http_request(&test_request(), &test_params()).unwrap();
+        assert_eq!(params.get("id"), Some("42"));
+        assert_eq!(body, Bytes::from_static(b"hello"));
+    }
+
+    #[test]
+    fn body() {
+        let body = Bytes::from_http_request(&test_request(), &test_params()).unwrap();
+        assert_eq!(body, Bytes::from_static(b"hello"));
+    }
+
+}
@@ -1,79 +1,310 @@
 
+use std::sync::Arc;
+
 use bevy::prelude::*;
-use vebb::*;
+use http::{Request, Response, StatusCode, Method, HeaderValue};
+use bytes::Bytes;
 
 use super::http_path::*;
+use super::{Handler, IntoHandler};
+use super::HttpError;
+use super::HttpPathParams;
+use super::HttpSseHandler;
+use super::HttpWebSocket;
+use super::Middleware;
+use super::http_middleware::{apply_middleware, SharedMiddleware};
 
-type HttpRequestHandlerFn = fn(&mut World, &Request<Bytes>) -> Result<Response<Bytes>, StatusCode>;
+type SharedHandler = Arc<dyn Handler + Send + Sync>;
 
 
 #[derive(Clone)]
 pub struct HttpRequestHandler {
     dir_name: String,
-    function: HttpRequestHandlerFn,
+    function: Option<SharedHandler>,
+    methods: Vec<(Method, SharedHandler)>,
     children: Vec<HttpRequestHandler>,
+    middleware: Vec<SharedMiddleware>,
 }
 
 
 impl HttpRequestHandler {
 
-    pub fn new(dir_name: &str, function: HttpRequestHandlerFn) -> Self {
+    pub fn new<Marker>(dir_name: &str, function: impl IntoHandler<Marker>) -> Self {
+        HttpRequestHandler {
+            dir_name: dir_name.to_owned(),
+            function: Some(Arc::new(function.into_handler())),
+            methods: vec![],
+            children: vec![],
+            middleware: vec![],
+        }
+    }
+
+
+    // A handler with no function for any method; add children and/or
+    // per-method functions with .get(), .post() etc.
+    pub fn dir(dir_name: &str) -> Self {
         HttpRequestHandler {
             dir_name: dir_name.to_owned(),
-            function,
+            function: None,
+            methods: vec![],
             children: vec![],
+            middleware: vec![],
         }
     }
 
 
+    // A GET route streaming a Server-Sent Event channel, see HttpSseChannels
+    pub fn sse(dir_name: &str, channel: &str) -> Self {
+        return HttpRequestHandler::dir(dir_name).get(HttpSseHandler::new(channel));
+    }
+
+
+    // A GET route accepting WebSocket handshakes, each socket is spawned as
+    // an HttpWebSocket entity
+    pub fn websocket(dir_name: &str) -> Self {
+        return HttpRequestHandler::dir(dir_name).get(|world: &mut World, request: &Request<Bytes>, _params: &HttpPathParams| {
+            return HttpWebSocket::upgrade(world, request).map(|(response, _entity)| response);
+        });
+    }
+
+
+    // Register a function for one specific method. Methods without a
+    // function of their own fall back to the one given to new(), if any.
+    pub fn method<Marker>(mut self, method: Method, function: impl IntoHandler<Marker>) -> Self {
+        self.methods.retain(|(existing, _)| *existing != method);
+        self.methods.push((method, Arc::new(function.into_handler())));
+        return self;
+    }
+
+
+    pub fn get<Marker>(self, function: impl IntoHandler<Marker>) -> Self {
+        return self.method(Method::GET, function);
+    }
+
+
+    pub fn post<Marker>(self, function: impl IntoHandler<Marker>) -> Self {
+        return self.method(Method::POST, function);
+    }
+
+
+    pub fn put<Marker>(self, function: impl IntoHandler<Marker>) -> Self {
+        return self.method(Method::PUT, function);
+    }
+
+
+    pub fn delete<Marker>(self, function: impl IntoHandler<Marker>) -> Self {
+        return self.method(Method::DELETE, function);
+    }
+
+
+    pub fn patch<Marker>(self, function: impl IntoHandler<Marker>) -> Self {
+        return self.method(Method::PATCH, function);
+    }
+
+
     pub fn add_child(mut self, handler: HttpRequestHandler) -> Self {
         if handler.dir_name.contains("/") {
             panic!("dir_name cannot contain {:?}", String::from("/"));
         }
+        if handler.dir_name == ":" || handler.dir_name == "*" {
+            panic!("dir_name {:?} must include a parameter name", handler.dir_name);
+        }
+        if self.wildcard_name().is_some() {
+            panic!("wildcard handler {:?} cannot have children", self.dir_name);
+        }
         self.children.push(handler);
         return self
     }
 
 
+    // Wrap this handler and all its children, see Middleware. Middleware
+    // added first runs first.
+    pub fn with_middleware(mut self, middleware: impl Middleware + Send + Sync + 'static) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        return self;
+    }
+
+
     pub fn dir_name(&self) -> &str {
         return self.dir_name.as_str();
     }
 
 
-    pub fn handle(&self, world: &mut World, path: &str, request: &Request<Bytes>) -> Result<Response<Bytes>, StatusCode> {
+    // Call the handler for the request path, Err(404 Not Found) if there is
+    // none. Middleware is not applied, see respond().
+    pub fn handle(&self, world: &mut World, path: &str, request: &Request<Bytes>) -> Result<Response<Bytes>, HttpError> {
+        let current_path = HttpPath::from(path);
+        let request_path = HttpPath::from(request.uri().path());
+        let mut params = HttpPathParams::new();
+        let mut middleware = Vec::new();
+        match self.route(current_path.len(), &request_path, &mut params, &mut middleware) {
+            None => return Err(HttpError::new(StatusCode::NOT_FOUND)),
+            Some(handler) => return handler.dispatch(world, request, &params),
+        }
+    }
+
+
+    // Like handle(), wrapped in the middleware of every handler along the
+    // request path. Errors are always turned into responses, as middleware
+    // sees them in after(). Used by http_request_responder.
+    pub fn respond(&self, world: &mut World, path: &str, request: &Request<Bytes>) -> Response<Bytes> {
         let current_path = HttpPath::from(path);
         let request_path = HttpPath::from(request.uri().path());
+        let mut params = HttpPathParams::new();
+        let mut middleware = Vec::new();
+        match self.route(current_path.len(), &request_path, &mut params, &mut middleware) {
+            None => return apply_middleware(&[], world, request, |_world| Err(HttpError::new(StatusCode::NOT_FOUND))),
+            Some(handler) => return apply_middleware(&middleware, world, request, |world| handler.dispatch(world, request, &params)),
+        }
+    }
+
+
+    // Call the function registered for the request method, HEAD falls back to
+    // GET. If there is none, answer OPTIONS and 405 Method Not Allowed based on
+    // the registered methods.
+    fn dispatch(&self, world: &mut World, request: &Request<Bytes>, params: &HttpPathParams) -> Result<Response<Bytes>, HttpError> {
+        if let Some(function) = self.method_function(request.method()) {
+            return function.call(world, request, params);
+        }
+        if request.method() == Method::HEAD {
+            if let Some(function) = self.method_function(&Method::GET) {
+                return function.call(world, request, params);
+            }
+        }
+        if let Some(function) = &self.function {
+            return function.call(world, request, params);
+        }
+        if self.methods.is_empty() { return Err(HttpError::new(StatusCode::NOT_FOUND)); }
+
+        let allow = HeaderValue::from_str(self.allow().as_str()).unwrap();
+        if request.method() == Method::OPTIONS {
+            let mut response = Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Bytes::new())
+                .unwrap();
+            response.headers_mut().insert("Allow", allow);
+            return Ok(response);
+        }
+        let mut response = self.error_response(StatusCode::METHOD_NOT_ALLOWED);
+        // A method mismatch is no reason to drop a keep-alive connection
+        response.headers_mut().remove("Connection");
+        response.headers_mut().insert("Allow", allow);
+        return Ok(response);
+    }
+
+
+    // Helper function for dispatch()
+    fn method_function(&self, method: &Method) -> Option<&SharedHandler> {
+        return self.methods.iter().find(|(existing, _)| existing == method).map(|(_, function)| function);
+    }
+
+
+    // Value for the Allow header, e.g. "GET, HEAD, POST, OPTIONS"
+    fn allow(&self) -> String {
+        let mut methods: Vec<&str> = self.methods.iter().map(|(method, _)| method.as_str()).collect();
+        if let Some(get) = methods.iter().position(|method| *method == Method::GET.as_str()) {
+            if !methods.contains(&Method::HEAD.as_str()) { methods.insert(get + 1, Method::HEAD.as_str()); }
+        }
+        if !methods.contains(&Method::OPTIONS.as_str()) { methods.push(Method::OPTIONS.as_str()); }
+        return methods.join(", ");
+    }
+
+
+    // Find the handler for request_path, where depth is the number of parts
+    // consumed by self. Literal children are tried before ":param" children,
+    // "*wildcard" children last. Captured values are stored in params, and
+    // the middleware of every handler along the way in middleware. A wildcard
+    // does not match a segment that decodes to ".." or contains a slash or
+    // backslash, nor an empty first segment giving an absolute path, any of
+    // which would let a handler joining the value onto a directory leave it.
+    fn route(&self, depth: usize, request_path: &HttpPath, params: &mut HttpPathParams, middleware: &mut Vec<SharedMiddleware>) -> Option<&HttpRequestHandler> {
+        let mark = middleware.len();
+        middleware.extend(self.middleware.iter().cloned());
+        let found = self.route_children(depth, request_path, params, middleware);
+        if found.is_none() { middleware.truncate(mark); }
+        return found;
+    }
+
+
+    // Helper function for route()
+    fn route_children(&self, depth: usize, request_path: &HttpPath, params: &mut HttpPathParams, middleware: &mut Vec<SharedMiddleware>) -> Option<&HttpRequestHandler> {
+        if depth == request_path.len() { return Some(self); }
+        let part = request_path.get(depth)?;
+
+        for child in self.children.iter() {
+            if child.param_name().is_some() || child.wildcard_name().is_some() { continue; }
+            if child.dir_name() != part { continue; }
+            if let Some(handler) = child.route(depth + 1, request_path, params, middleware) { return Some(handler); }
+        }
+
+        for child in self.children.iter() {
+            if let Some(name) = child.param_name() {
+                if part.is_empty() { continue; }
+                params.insert(name, percent_decode(part));
+                if let Some(handler) = child.route(depth + 1, request_path, params, middleware) { return Some(handler); }
+                params.remove(name);
+            }
+        }
+
         for child in self.children.iter() {
-            let mut candidate = current_path.clone();
-            candidate.push(child.dir_name());
-            if request_path.starts_with(&candidate) {
-                return child.handle(world, candidate.to_string().as_str(), request);
+            if let Some(name) = child.wildcard_name() {
+                let mut rest = Vec::new();
+                for segment in request_path.tail(depth).split('/') {
+                    let segment = percent_decode(segment);
+                    if segment == ".." || segment.contains(['/', '\\']) { return None; }
+                    rest.push(segment);
+                }
+                let rest = rest.join("/");
+                if rest.starts_with('/') { return None; }
+                params.insert(name, rest);
+                middleware.extend(child.middleware.iter().cloned());
+                return Some(child);
             }
         }
-        if current_path != request_path { return Err(StatusCode::NOT_FOUND); }
-        return (self.function)(world, request);
+
+        return None;
     }
 
 
-    pub fn error_response(&self, status: StatusCode) -> Response<Bytes> {
-        let message = format!("{} {}", status.as_u16(), status.canonical_reason().unwrap());
-        return Response::builder()
-            .status(status)
-            .header("Content-Type", "text/plain; charset=utf-8")
-            .header("Connection", "close")
-            .body(Bytes::from(message))
-            .unwrap();
+    // "id" if dir_name is ":id"
+    fn param_name(&self) -> Option<&str> {
+        return self.dir_name.strip_prefix(":");
+    }
+
+
+    // "rest" if dir_name is "*rest"
+    fn wildcard_name(&self) -> Option<&str> {
+        return self.dir_name.strip_prefix("*");
+    }
+
+
+    // Accepts a StatusCode or an HttpError, see HttpError::response()
+    pub fn error_response(&self, error: impl Into<HttpError>) -> Response<Bytes> {
+        return error.into().response();
     }
     
 }
 
 
+// Plain text response like "404 Not Found", also used by HttpConnectionServer
+// and HttpError. Just "499" for a status without a canonical reason.
+pub(crate) fn status_response(status: StatusCode) -> Response<Bytes> {
+    let message = format!("{} {}", status.as_u16(), status.canonical_reason().unwrap_or(""));
+    return Response::builder()
+        .status(status)
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .header("Connection", "close")
+        .body(Bytes::from(String::from(message.trim_end())))
+        .unwrap();
+}
+
+
 #[cfg(test)]
 #[allow(dead_code)]
 mod tests {
     use super::*;
 
-    fn test_handler_ok(_world: &mut World, _request: &Request<Bytes>) -> Result<Response<Bytes>, StatusCode> {
+    fn test_handler_ok(_world: &mut World, _request: &Request<Bytes>, _params: &HttpPathParams) -> Result<Response<Bytes>, StatusCode> {
         let response = Response::builder()
             .status(StatusCode::OK)
             .body(Bytes::from_static(b""))
@@ -82,7 +313,7 @@ mod tests {
         return Ok(response);
     }
 
-    fn test_handler_error(_world: &mut World, _request: &Request<Bytes>) -> Result<Response<Bytes>, StatusCode> {
+    fn test_handler_error(_world: &mut World, _request: &Request<Bytes>, _params: &HttpPathParams) -> Result<Response<Bytes>, StatusCode> {
         let response = Response::builder()
             .status(StatusCode::INTERNAL_SERVER_ERROR)
             .body(Bytes::from_static(b""))
@@ -91,6 +322,17 @@ mod tests {
         return Ok(response);
     }
 
+    fn test_handler_params(_world: &mut World, _request: &Request<Bytes>, params: &HttpPathParams) -> Result<Response<Bytes>, StatusCode> {
+        let mut pairs: Vec<String> = params.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+        pairs.sort();
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .body(Bytes::from(pairs.join("&")))
+            .unwrap();
+
+        return Ok(response);
+    }
+
     #[test]
     fn new_1() {
         let _handler: HttpRequestHandler = HttpRequestHandler::new("/", test_handler_ok);
@@ -113,7 +355,7 @@ mod tests {
         let mut world = World::new();
 
         match handler.handle(&mut world, "/", &request) {
-            Err(status) => { panic!("handler returned {:?} {:?}", status.as_str(), status.canonical_reason()); }
+            Err(error) => { panic!("handler returned {}", error); }
             Ok(_) => { assert!(true) }
         }
     }
@@ -150,7 +392,7 @@ mod tests {
         let mut world = World::new();
 
         match handler.handle(&mut world, "/", &request) {
-            Err(status) => { panic!("handler returned {:?} {:?}", status.as_str(), status.canonical_reason()); }
+            Err(error) => { panic!("handler returned {}", error); }
             Ok(_) => { assert!(true) }
         }
     }
@@ -172,7 +414,7 @@ mod tests {
         let mut world = World::new();
 
         match handler.handle(&mut world, "/", &request) {
-            Err(status) => { panic!("handler returned {:?} {:?}", status.as_str(), status.canonical_reason()); }
+            Err(error) => { panic!("handler returned {}", error); }
             Ok(_) => { assert!(true) }
         }
     }
@@ -194,7 +436,7 @@ mod tests {
         let mut world = World::new();
 
         match handler.handle(&mut world, "/", &request) {
-            Err(status) => { panic!("handler returned {:?} {:?}", status.as_str(), status.canonical_reason()); }
+            Err(error) => { panic!("handler returned {}", error); }
             Ok(_) => { assert!(true) }
         }
     }
@@ -216,7 +458,7 @@ mod tests {
         let mut world = World::new();
 
         match handler.handle(&mut world, "/", &request) {
-            Err(status) => { panic!("handler returned {:?} {:?}", status.as_str(), status.canonical_reason()); }
+            Err(error) => { panic!("handler returned {}", error); }
             Ok(_) => { assert!(true) }
         }
     }
@@ -238,9 +480,336 @@ mod tests {
         let mut world = World::new();
 
         match handler.handle(&mut world, "/", &request) {
-            Err(status) => { panic!("handler returned {:?} {:?}", status.as_str(), status.canonical_reason()); }
+            Err(error) => { panic!("handler returned {}", error); }
             Ok(_) => { assert!(true) }
         }
     }
 
+    fn players() -> HttpRequestHandler {
+        return HttpRequestHandler::new("/", test_handler_error)
+            .add_child(
+                HttpRequestHandler::new("players", test_handler_error)
+                    .add_child(
+                        HttpRequestHandler::new(":id", test_handler_params)
+                            .add_child(
+                                HttpRequestHandler::new("inventory", test_handler_params)
+                            )
+                    )
+                    .add_child(
+                        HttpRequestHandler::new("me", test_handler_ok)
+                    )
+            )
+            .add_child(
+                HttpRequestHandler::new("files", test_handler_error)
+                    .add_child(
+                        HttpRequestHandler::new("*rest", test_handler_params)
+                    )
+            );
+    }
+
+    fn handle_body(handler: &HttpRequestHandler, uri: &str) -> Result<Bytes, StatusCode> {
+        let request = Request::builder()
+            .uri(uri)
+            .body(Bytes::from_static(b""))
+            .unwrap();
+        let mut world = World::new();
+        let response = handler.handle(&mut world, "/", &request).map_err(|error| error.status())?;
+        assert_eq!(response.status(), StatusCode::OK);
+        return Ok(response.into_body());
+    }
+
+    #[test]
+    fn handle_param() {
+        assert_eq!(handle_body(&players(), "/players/42"), Ok(Bytes::from_static(b"id=42")));
+    }
+
+    #[test]
+    fn handle_param_nested() {
+        assert_eq!(handle_body(&players(), "/players/42/inventory"), Ok(Bytes::from_static(b"id=42")));
+    }
+
+    #[test]
+    fn handle_param_decoded() {
+        assert_eq!(handle_body(&players(), "/players/bl%C3%A5%20b%C3%A6r"), Ok(Bytes::from("id=blå bær")));
+    }
+
+    #[test]
+    fn handle_param_empty() {
+        assert_eq!(handle_body(&players(), "/players//inventory"), Err(StatusCode::NOT_FOUND));
+    }
+
+    #[test]
+    fn handle_literal_before_param() {
+        assert_eq!(handle_body(&players(), "/players/me"), Ok(Bytes::from_static(b"")));
+    }
+
+    #[test]
+    fn handle_param_after_literal_backtracks() {
+        assert_eq!(handle_body(&players(), "/players/me/inventory"), Ok(Bytes::from_static(b"id=me")));
+    }
+
+    #[test]
+    fn handle_wildcard() {
+        assert_eq!(handle_body(&players(), "/files/maps/level%201.map"), Ok(Bytes::from_static(b"rest=maps/level 1.map")));
+    }
+
+    #[test]
+    fn handle_wildcard_empty() {
+        assert_eq!(handle_body(&players(), "/files/"), Ok(Bytes::from_static(b"rest=")));
+    }
+
+    #[test]
+    fn handle_wildcard_encoded_slash() {
+        assert_eq!(handle_body(&players(), "/files/maps%2Flevel1.map"), Err(StatusCode::NOT_FOUND));
+        assert_eq!(handle_body(&players(), "/files/maps%5Clevel1.map"), Err(StatusCode::NOT_FOUND));
+        assert_eq!(handle_body(&players(), "/files//etc/passwd"), Err(StatusCode::NOT_FOUND));
+    }
+
+    #[test]
+    fn handle_wildcard_dot_dot() {
+        assert_eq!(handle_body(&players(), "/files/maps/../../secret"), Err(StatusCode::NOT_FOUND));
+        assert_eq!(handle_body(&players(), "/files/maps/%2E%2E/%2e%2e/secret"), Err(StatusCode::NOT_FOUND));
+        assert_eq!(handle_body(&players(), "/files/maps/..level1.map"), Ok(Bytes::from_static(b"rest=maps/..level1.map")));
+    }
+
+    fn method_request(method: Method, uri: &str) -> Request<Bytes> {
+        return Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Bytes::from_static(b""))
+            .unwrap();
+    }
+
+    fn players_methods() -> HttpRequestHandler {
+        return HttpRequestHandler::dir("/")
+            .add_child(
+                HttpRequestHandler::dir("players")
+                    .get(test_handler_ok)
+                    .post(test_handler_error)
+            )
+            .add_child(
+                HttpRequestHandler::new("any", test_handler_error)
+                    .get(test_handler_ok)
+            );
+    }
+
+    #[test]
+    fn handle_method_get() {
+        let mut world = World::new();
+        let response = players_methods().handle(&mut world, "/", &method_request(Method::GET, "/players")).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn handle_method_post() {
+        let mut world = World::new();
+        let response = players_methods().handle(&mut world, "/", &method_request(Method::POST, "/players")).unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn handle_method_not_allowed() {
+        let mut world = World::new();
+        let response = players_methods().handle(&mut world, "/", &method_request(Method::DELETE, "/players")).unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers().get("Allow").unwrap(), "GET, HEAD, POST, OPTIONS");
+        assert_eq!(response.headers().contains_key("Connection"), false);
+    }
+
+    #[test]
+    fn handle_method_options() {
+        let mut world = World::new();
+        let response = players_methods().handle(&mut world, "/", &method_request(Method::OPTIONS, "/players")).unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers().get("Allow").unwrap(), "GET, HEAD, POST, OPTIONS");
+    }
+
+    #[test]
+    fn handle_method_head() {
+        let mut world = World::new();
+        let response = players_methods().handle(&mut world, "/", &method_request(Method::HEAD, "/players")).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = players_methods().handle(&mut world, "/", &method_request(Method::HEAD, "/any")).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let handler = HttpRequestHandler::dir("/").get(test_handler_ok).method(Method::HEAD, test_handler_error);
+        let response = handler.handle(&mut world, "/", &method_request(Method::HEAD, "/")).unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn handle_method_head_without_get() {
+        let mut world = World::new();
+        let handler = HttpRequestHandler::dir("/").post(test_handler_ok);
+        let response = handler.handle(&mut world, "/", &method_request(Method::HEAD, "/")).unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers().get("Allow").unwrap(), "POST, OPTIONS");
+    }
+
+    #[test]
+    fn handle_method_fallback() {
+        let mut world = World::new();
+        let handler = players_methods();
+        let response = handler.handle(&mut world, "/", &method_request(Method::GET, "/any")).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = handler.handle(&mut world, "/", &method_request(Method::PUT, "/any")).unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn handle_dir_without_methods() {
+        let mut world = World::new();
+        match players_methods().handle(&mut world, "/", &method_request(Method::GET, "/")) {
+            Err(status) => { assert_eq!(status, StatusCode::NOT_FOUND); }
+            Ok(_) => { panic!("handler should have returned 404 Not Found"); }
+        }
+    }
+
+    #[test]
+    fn handle_closure() {
+        let greeting = String::from("hello");
+        // Names the error type, see closure_handler in http_handler.rs
+        let handler = HttpRequestHandler::new("/", move |_world: &mut World, _request: &Request<Bytes>, _params: &HttpPathParams| {
+            return Ok::<_, StatusCode>(Response::builder().status(StatusCode::OK).body(Bytes::from(greeting.clone())).unwrap());
+        });
+        assert_eq!(handle_body(&handler.clone(), "/"), Ok(Bytes::from_static(b"hello")));
+    }
+
+    struct DenyMiddleware;
+
+    impl Middleware for DenyMiddleware {
+        fn before(&self, _world: &mut World, request: &Request<Bytes>) -> Result<Option<Response<Bytes>>, HttpError> {
+            if request.headers().contains_key("Authorization") { return Ok(None); }
+            return Err(HttpError::new(StatusCode::UNAUTHORIZED).with_header("WWW-Authenticate", "Bearer"));
+        }
+    }
+
+    struct HeaderMiddleware(&'static str);
+
+    impl Middleware for HeaderMiddleware {
+        fn after(&self, _world: &mut World, _request: &Request<Bytes>, response: &mut Response<Bytes>) {
+            response.headers_mut().append("X-Middleware", HeaderValue::from_static(self.0));
+        }
+    }
+
+    fn players_middleware() -> HttpRequestHandler {
+        return HttpRequestHandler::dir("/")
+            .add_child(HttpRequestHandler::new("public", test_handler_ok))
+            .add_child(
+                HttpRequestHandler::dir("players")
+                    .with_middleware(HeaderMiddleware("players"))
+                    .with_middleware(DenyMiddleware)
+                    .get(test_handler_ok)
+                    .add_child(
+                        HttpRequestHandler::new(":id", test_handler_params)
+                            .with_middleware(HeaderMiddleware("player"))
+                    )
+                    .add_child(HttpRequestHandler::new("*rest", test_handler_error))
+            );
+    }
+
+    #[test]
+    fn respond_middleware_outside_branch() {
+        let mut world = World::new();
+        let response = players_middleware().respond(&mut world, "/", &method_request(Method::GET, "/public"));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().contains_key("X-Middleware"), false);
+    }
+
+    #[test]
+    fn respond_middleware_short_circuit() {
+        let mut world = World::new();
+        let response = players_middleware().respond(&mut world, "/", &method_request(Method::GET, "/players/42"));
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers().get("WWW-Authenticate").unwrap(), "Bearer");
+        assert_eq!(response.headers().get("X-Middleware").unwrap(), "players");
+    }
+
+    #[test]
+    fn respond_middleware_nested() {
+        let mut world = World::new();
+        let mut request = method_request(Method::GET, "/players/42");
+        request.headers_mut().insert("Authorization", HeaderValue::from_static("Bearer 42"));
+        let response = players_middleware().respond(&mut world, "/", &request);
+        assert_eq!(response.status(), StatusCode::OK);
+        let names: Vec<&str> = response.headers().get_all("X-Middleware").iter().map(|value| value.to_str().unwrap()).collect();
+        assert_eq!(names, vec!["player", "players"]);
+        assert_eq!(response.into_body(), Bytes::from_static(b"id=42"));
+    }
+
+    #[test]
+    fn respond_middleware_wildcard() {
+        let mut world = World::new();
+        let response = players_middleware().respond(&mut world, "/", &method_request(Method::GET, "/players/42/inventory"));
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn respond_middleware_not_found() {
+        let mut world = World::new();
+        let handler = HttpRequestHandler::new("/", test_handler_ok)
+            .add_child(HttpRequestHandler::dir("players").with_middleware(DenyMiddleware));
+        let response = handler.respond(&mut world, "/", &method_request(Method::GET, "/players"));
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = handler.respond(&mut world, "/", &method_request(Method::GET, "/players/missing"));
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    fn test_handler_teapot(_world: &mut World, _request: &Request<Bytes>, _params: &HttpPathParams) -> Result<Response<Bytes>, StatusCode> {
+        return Err(StatusCode::IM_A_TEAPOT);
+    }
+
+    fn teapot_middleware() -> HttpRequestHandler {
+        return HttpRequestHandler::new("/", test_handler_ok)
+            .with_middleware(HeaderMiddleware("root"))
+            .add_child(HttpRequestHandler::new("teapot", test_handler_teapot));
+    }
+
+    #[test]
+    fn respond_error() {
+        let mut world = World::new();
+        let response = teapot_middleware().respond(&mut world, "/", &method_request(Method::GET, "/teapot"));
+        assert_eq!(response.status(), StatusCode::IM_A_TEAPOT);
+        assert_eq!(response.headers().get("X-Middleware").unwrap(), "root");
+    }
+
+    #[test]
+    fn handle_without_middleware() {
+        let mut world = World::new();
+        let response = teapot_middleware().handle(&mut world, "/", &method_request(Method::GET, "/")).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().contains_key("X-Middleware"), false);
+        let error = teapot_middleware().handle(&mut world, "/", &method_request(Method::GET, "/teapot")).unwrap_err();
+        assert_eq!(error, StatusCode::IM_A_TEAPOT);
+        let error = teapot_middleware().handle(&mut world, "/", &method_request(Method::GET, "/missing")).unwrap_err();
+        assert_eq!(error, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn handle_http_error() {
+        let mut world = World::new();
+        let handler = HttpRequestHandler::new("/", |_world: &mut World, _request: &Request<Bytes>, params: &HttpPathParams| {
+            let id: u32 = params.get("id").unwrap_or("none").parse()?;
+            return Ok::<_, HttpError>(Response::new(Bytes::from(id.to_string())));
+        });
+        let error = handler.handle(&mut world, "/", &method_request(Method::GET, "/")).unwrap_err();
+        assert_eq!(error, StatusCode::BAD_REQUEST);
+        let response = handler.error_response(error);
+        assert_eq!(response.into_body(), Bytes::from_static(b"400 Bad Request: invalid digit found in string"));
+        assert_eq!(handler.error_response(StatusCode::NOT_FOUND).into_body(), Bytes::from_static(b"404 Not Found"));
+    }
+
+    #[test]
+    #[should_panic]
+    fn add_child_to_wildcard() {
+        let _handler = HttpRequestHandler::new("*rest", test_handler_ok)
+            .add_child(HttpRequestHandler::new("foo", test_handler_ok));
+    }
+
+    #[test]
+    #[should_panic]
+    fn add_child_without_param_name() {
+        let _handler = HttpRequestHandler::new("/", test_handler_ok)
+            .add_child(HttpRequestHandler::new(":", test_handler_ok));
+    }
+
 }
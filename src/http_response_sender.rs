@@ -0,0 +1,136 @@
+/*
+An HttpResponseSender is attached to every request by HttpConnectionServer
+(in the request extensions) and can be used to answer the request later,
+from any system or frame, instead of returning the response right away:
+
+    fn slow_handler(world: &mut World, request: &Request<Bytes>, _params: &HttpPathParams) -> Result<Response<Bytes>, StatusCode> {
+        let sender = HttpResponseSender::from_request(request).ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+        world.spawn(sender); // Some other system will query for HttpResponseSender and call .send()
+        return Ok(HttpResponseSender::deferred());
+    }
+
+If no response is sent before the response timeout configured on
+HttpServerPlugin, the client gets 504 Gateway Timeout and the sender expires.
+
+Middleware after() is not called for deferred requests, the response passed
+to .send() is written exactly as given. See Middleware.
+
+See also: HttpConnectionServer
+*/
+
+use bevy::prelude::*;
+use smol::channel::Sender;
+
+use http::{Request, Response};
+use bytes::Bytes;
+
+
+// Marker placed in the extensions of the response returned by deferred()
+struct HttpDeferred;
+
+
+#[derive(Component, Clone)]
+pub struct HttpResponseSender {
+    sender: Sender<Response<Bytes>>,
+}
+
+
+impl HttpResponseSender {
+
+    // The sender must belong to a channel with room for exactly one response
+    pub fn new(sender: Sender<Response<Bytes>>) -> Self {
+        HttpResponseSender {
+            sender,
+        }
+    }
+
+    pub fn from_request(request: &Request<Bytes>) -> Option<Self> {
+        return request.extensions().get::<HttpResponseSender>().cloned();
+    }
+
+    // Return this from a handler to leave the request unanswered for now
+    pub fn deferred() -> Response<Bytes> {
+        let mut response = Response::new(Bytes::new());
+        response.extensions_mut().insert(HttpDeferred);
+        return response;
+    }
+
+    pub fn is_deferred(response: &Response<Bytes>) -> bool {
+        return response.extensions().get::<HttpDeferred>().is_some();
+    }
+
+    // True if the request was answered, timed out or the connection is gone
+    pub fn is_expired(&self) -> bool {
+        return self.sender.is_full() || self.sender.is_closed();
+    }
+
+    // Returns false if the response could not be delivered because the sender has expired
+    pub fn send(&self, response: Response<Bytes>) -> bool {
+        return self.sender.try_send(response).is_ok();
+    }
+
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use super::*;
+    use http::StatusCode;
+
+    fn ok() -> Response<Bytes> {
+        return Response::builder().status(StatusCode::OK).body(Bytes::from_static(b"")).unwrap();
+    }
+
+    #[test]
+    fn send() {
+        let (sender, receiver) = smol::channel::bounded(1);
+        let sender = HttpResponseSender::new(sender);
+        assert_eq!(sender.is_expired(), false);
+        assert_eq!(sender.send(ok()), true);
+        assert_eq!(receiver.try_recv().is_ok(), true);
+    }
+
+    #[test]
+    fn send_twice() {
+        let (sender, _receiver) = smol::channel::bounded(1);
+        let sender = HttpResponseSender::new(sender);
+        assert_eq!(sender.send(ok()), true);
+        assert_eq!(sender.is_expired(), true);
+        assert_eq!(sender.send(ok()), false);
+    }
+
+    #[test]
+    fn send_expired() {
+        let (sender, receiver) = smol::channel::bounded(1);
+        let sender = HttpResponseSender::new(sender);
+        receiver.close();
+        assert_eq!(sender.is_expired(), true);
+        assert_eq!(sender.send(ok()), false);
+    }
+
+    #[test]
+    fn send_receiver_dropped() {
+        let (sender, receiver) = smol::channel::bounded(1);
+        let sender = HttpResponseSender::new(sender);
+        drop(receiver);
+        assert_eq!(sender.is_expired(), true);
+        assert_eq!(sender.send(ok()), false);
+    }
+
+    #[test]
+    fn from_request() {
+        let sender = HttpResponseSender::new(smol::channel::bounded(1).0);
+        let mut request = Request::builder().body(Bytes::from_static(b"")).unwrap();
+        assert_eq!(HttpResponseSender::from_request(&request).is_none(), true);
+        request.extensions_mut().insert(sender);
+        assert_eq!(HttpResponseSender::from_request(&request).is_some(), true);
+    }
+
+    #[test]
+    fn deferred() {
+        assert_eq!(HttpResponseSender::is_deferred(&HttpResponseSender::deferred()), true);
+        assert_eq!(HttpResponseSender::is_deferred(&ok()), false);
+    }
+
+}
@@ -0,0 +1,28 @@
+
+// Events sent by the App to control HttpServerPlugin, e.g.
+//
+//    fn stop_server(mut commands: EventWriter<HttpServerCommand>) {
+//        commands.send(HttpServerCommand::Stop(String::from("admin")));
+//    }
+//
+// Stopping closes the listeners right away, answers the requests already
+// received and closes keep-alive connections after their next response.
+// Connections still open after the shutdown grace period are dropped.
+// AppExit triggers the same shutdown, see http_server_shutdown.
+//
+// See also: HttpServerControl, which queues the same commands
+
+use super::HttpBindAddress;
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HttpServerCommand {
+    // Bind the listeners of the named server, does nothing if already listening
+    Start(String),
+    // Close the listeners and the connections, the server can be started again
+    Stop(String),
+    // Move the listeners to new addresses, see HttpServerControl::rebind()
+    Rebind(String, Vec<HttpBindAddress>),
+    // Stop every server
+    Shutdown,
+}
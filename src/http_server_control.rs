@@ -0,0 +1,206 @@
+
+// Starts, stops and rebinds the listeners of each server instance while the
+// App runs, e.g. from an in-game settings menu:
+//
+//    fn settings_menu(mut control: ResMut<HttpServerControl>) {
+//        control.rebind("admin", vec!["127.0.0.1:8080".parse().unwrap()]);
+//        control.start("admin");
+//    }
+//
+// The same can be done by sending HttpServerCommand events. Commands are
+// carried out by http_server_control at the start of the next frame, after
+// which state() tells if the listeners are bound or why binding failed.
+//
+// Stopping closes connections gracefully, see HttpServerCommand::Shutdown.
+
+use std::time::Instant;
+
+use bevy::prelude::*;
+
+use super::HttpBindAddress;
+use super::HttpServerCommand;
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HttpServerState {
+    Stopped,
+    // The local addresses actually bound, useful when binding to port 0
+    Listening(Vec<HttpBindAddress>),
+    // Binding the listeners failed, see HttpErrorPolicy. start() or rebind()
+    // may be used to retry
+    Failed(String),
+}
+
+
+// Control state of one server instance
+struct HttpServerControlEntry {
+    bind_addresses: Vec<HttpBindAddress>,
+    state: HttpServerState,
+    retry_at: Option<Instant>,
+}
+
+
+#[derive(Resource, Default)]
+pub struct HttpServerControl {
+    servers: Vec<(String, HttpServerControlEntry)>,
+    commands: Vec<HttpServerCommand>,
+}
+
+
+impl HttpServerControl {
+
+    pub fn new() -> Self {
+        HttpServerControl {
+            servers: Vec::new(),
+            commands: Vec::new(),
+        }
+    }
+
+    // Bind the listeners to bind_addresses(), does nothing if already listening
+    pub fn start(&mut self, name: &str) {
+        self.commands.push(HttpServerCommand::Start(String::from(name)));
+    }
+
+    // Close the listeners and the connections, the server can be started again
+    pub fn stop(&mut self, name: &str) {
+        self.commands.push(HttpServerCommand::Stop(String::from(name)));
+    }
+
+    // Move the listeners to new addresses. Connections already accepted are
+    // not affected, and neither is a listener whose local address is asked for
+    // again. If the server is stopped, only the addresses are changed. If
+    // binding fails while listening, the old listeners and addresses are kept.
+    pub fn rebind(&mut self, name: &str, bind_addresses: Vec<HttpBindAddress>) {
+        self.commands.push(HttpServerCommand::Rebind(String::from(name), bind_addresses));
+    }
+
+    // Names of all server instances, in the order they were added
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        return self.servers.iter().map(|(name, _)| name.as_str());
+    }
+
+    // None if there is no server instance with this name
+    pub fn bind_addresses(&self, name: &str) -> Option<&Vec<HttpBindAddress>> {
+        return self.entry(name).map(|entry| &entry.bind_addresses);
+    }
+
+    // None if there is no server instance with this name
+    pub fn state(&self, name: &str) -> Option<&HttpServerState> {
+        return self.entry(name).map(|entry| &entry.state);
+    }
+
+    pub fn is_listening(&self, name: &str) -> bool {
+        return matches!(self.state(name), Some(HttpServerState::Listening(_)));
+    }
+
+    // The local addresses actually bound, None unless listening
+    pub fn local_addrs(&self, name: &str) -> Option<&Vec<HttpBindAddress>> {
+        match self.state(name) {
+            Some(HttpServerState::Listening(local_addrs)) => return Some(local_addrs),
+            _ => return None,
+        }
+    }
+
+    pub(crate) fn add(&mut self, name: &str, bind_addresses: Vec<HttpBindAddress>) {
+        if self.entry(name).is_some() {
+            panic!("http server {:?} was already added", name);
+        }
+        self.servers.push((String::from(name), HttpServerControlEntry {
+            bind_addresses,
+            state: HttpServerState::Stopped,
+            retry_at: None,
+        }));
+    }
+
+    pub(crate) fn take_commands(&mut self) -> Vec<HttpServerCommand> {
+        return std::mem::take(&mut self.commands);
+    }
+
+    pub(crate) fn set_bind_addresses(&mut self, name: &str, bind_addresses: Vec<HttpBindAddress>) {
+        if let Some(entry) = self.entry_mut(name) { entry.bind_addresses = bind_addresses; }
+    }
+
+    pub(crate) fn set_state(&mut self, name: &str, state: HttpServerState) {
+        if let Some(entry) = self.entry_mut(name) { entry.state = state; }
+    }
+
+    // When to bind again after a failure, see HttpErrorPolicy::Retry
+    pub(crate) fn retry_at(&self, name: &str) -> Option<Instant> {
+        return self.entry(name).and_then(|entry| entry.retry_at);
+    }
+
+    pub(crate) fn set_retry_at(&mut self, name: &str, retry_at: Option<Instant>) {
+        if let Some(entry) = self.entry_mut(name) { entry.retry_at = retry_at; }
+    }
+
+    fn entry(&self, name: &str) -> Option<&HttpServerControlEntry> {
+        return self.servers.iter().find(|(n, _)| n == name).map(|(_, entry)| entry);
+    }
+
+    fn entry_mut(&mut self, name: &str) -> Option<&mut HttpServerControlEntry> {
+        return self.servers.iter_mut().find(|(n, _)| n == name).map(|(_, entry)| entry);
+    }
+
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use super::*;
+
+    fn addr() -> HttpBindAddress {
+        return "127.0.0.1:8080".parse().unwrap();
+    }
+
+    #[test]
+    fn add() {
+        let mut control = HttpServerControl::new();
+        control.add("public", vec![addr()]);
+        assert_eq!(control.bind_addresses("public"), Some(&vec![addr()]));
+        assert_eq!(control.state("public"), Some(&HttpServerState::Stopped));
+        assert_eq!(control.is_listening("public"), false);
+        assert_eq!(control.local_addrs("public"), None);
+        assert_eq!(control.state("admin"), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn add_twice() {
+        let mut control = HttpServerControl::new();
+        control.add("public", vec![addr()]);
+        control.add("public", vec![addr()]);
+    }
+
+    #[test]
+    fn names() {
+        let mut control = HttpServerControl::new();
+        control.add("public", vec![addr()]);
+        control.add("admin", vec![addr()]);
+        assert_eq!(control.names().collect::<Vec<&str>>(), vec!["public", "admin"]);
+    }
+
+    #[test]
+    fn commands_queued_in_order() {
+        let mut control = HttpServerControl::new();
+        control.stop("public");
+        control.rebind("public", vec!["127.0.0.1:0".parse().unwrap()]);
+        control.start("public");
+        assert_eq!(control.take_commands(), vec![
+            HttpServerCommand::Stop(String::from("public")),
+            HttpServerCommand::Rebind(String::from("public"), vec!["127.0.0.1:0".parse().unwrap()]),
+            HttpServerCommand::Start(String::from("public")),
+        ]);
+        assert_eq!(control.take_commands().len(), 0);
+    }
+
+    #[test]
+    fn listening() {
+        let mut control = HttpServerControl::new();
+        control.add("public", vec![addr()]);
+        control.set_state("public", HttpServerState::Listening(vec![addr()]));
+        assert_eq!(control.is_listening("public"), true);
+        assert_eq!(control.local_addrs("public"), Some(&vec![addr()]));
+    }
+
+}
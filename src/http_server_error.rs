@@ -0,0 +1,78 @@
+
+// Errors reported by HttpServerPlugin as Bevy events instead of panicking:
+//
+//    fn log_errors(mut errors: EventReader<HttpServerError>) {
+//        for error in errors.iter() {
+//            error!("{}", error);
+//        }
+//    }
+//
+// What happens next for BindFailed and AcceptFailed is decided by the
+// HttpErrorPolicy in HttpServerSettings. BadRequest and ConnectionFailed only
+// affect one client, so they are reported and the server keeps running regardless.
+
+use std::time::Duration;
+
+use http::StatusCode;
+
+use super::HttpBindAddress;
+use super::HttpClientAddress;
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HttpServerError {
+    BindFailed { server: String, address: HttpBindAddress, message: String },
+    AcceptFailed { server: String, message: String },
+    // The client sent a request that could not be read and was answered with status
+    BadRequest { server: String, peer: HttpClientAddress, status: StatusCode },
+    ConnectionFailed { server: String, peer: HttpClientAddress, message: String },
+}
+
+
+impl std::fmt::Display for HttpServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpServerError::BindFailed { server, address, message } => write!(f, "http server {:?} can't listen on {}: {}", server, address, message),
+            HttpServerError::AcceptFailed { server, message } => write!(f, "accept() on http server {:?} listener returned {}", server, message),
+            HttpServerError::BadRequest { server, peer, status } => write!(f, "{} sent a bad request to http server {:?}: {}", peer, server, status),
+            HttpServerError::ConnectionFailed { server, peer, message } => write!(f, "HttpConnectionTask of http server {:?} for {} crashed: {}", server, peer, message),
+        }
+    }
+}
+
+
+// What to do after the listener fails to bind or accept
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HttpErrorPolicy {
+    // Bind again, or resume accepting, once the backoff has passed
+    Retry { backoff: Duration },
+    // Keep the App running; a failed bind leaves the server stopped
+    // until started again with HttpServerControl
+    #[default]
+    Continue,
+    // Send AppExit
+    Exit,
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        let error = HttpServerError::BindFailed {
+            server: String::from("default"),
+            address: HttpBindAddress::Tcp("127.0.0.1:80".parse().unwrap()),
+            message: String::from("Address already in use"),
+        };
+        assert_eq!(format!("{}", error), "http server \"default\" can't listen on 127.0.0.1:80: Address already in use");
+    }
+
+    #[test]
+    fn default_policy() {
+        assert_eq!(HttpErrorPolicy::default(), HttpErrorPolicy::Continue);
+    }
+
+}
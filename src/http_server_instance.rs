@@ -0,0 +1,157 @@
+
+// One named server instance added by HttpServerPlugin, with its own
+// listeners, handler tree, middleware and settings. All instances are kept in
+// HttpServerResource, connections accepted by an instance are tagged
+// with its HttpServerName.
+
+use std::time::Instant;
+use smol::channel::{Receiver, Sender};
+
+use super::HttpListener;
+use super::HttpRequestHandler;
+use super::HttpRequestMode;
+use super::HttpServerSettings;
+use super::http_middleware::SharedMiddleware;
+#[cfg(feature = "tls")]
+use super::HttpTlsConfig;
+
+
+pub struct HttpServerInstance {
+    name: String,
+    listeners: Vec<HttpListener>,
+    root: HttpRequestHandler,
+    middleware: Vec<SharedMiddleware>,
+    settings: HttpServerSettings,
+    request_mode: HttpRequestMode,
+    // Nothing is ever sent, dropping the Sender wakes every HttpConnectionServer
+    shutdown_sender: Option<Sender<()>>,
+    shutdown_receiver: Receiver<()>,
+    shutdown_deadline: Option<Instant>,
+    #[cfg(feature = "tls")]
+    tls: Option<HttpTlsConfig>,
+}
+
+impl HttpServerInstance {
+
+    // The listeners are bound later, see start() and HttpServerControl
+    pub fn new(name: &str, root: HttpRequestHandler) -> Self {
+        if root.dir_name() != "/" {
+            panic!("root handler dir_name must be {:?}, not {:?}", String::from("/"), root.dir_name());
+        }
+        let (_shutdown_sender, shutdown_receiver) = smol::channel::bounded(1);
+        HttpServerInstance {
+            name: String::from(name),
+            listeners: Vec::new(),
+            root,
+            middleware: Vec::new(),
+            settings: HttpServerSettings::default(),
+            request_mode: HttpRequestMode::default(),
+            shutdown_sender: None,
+            shutdown_receiver,
+            shutdown_deadline: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    pub fn with_settings(mut self, settings: HttpServerSettings) -> Self {
+        self.settings = settings;
+        return self;
+    }
+
+    pub(crate) fn with_middleware(mut self, middleware: Vec<SharedMiddleware>) -> Self {
+        self.middleware = middleware;
+        return self;
+    }
+
+    pub fn with_request_mode(mut self, request_mode: HttpRequestMode) -> Self {
+        self.request_mode = request_mode;
+        return self;
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, tls: Option<HttpTlsConfig>) -> Self {
+        self.tls = tls;
+        return self;
+    }
+
+    pub fn name(&self) -> &str {
+        return &self.name;
+    }
+
+    // Empty unless the server is listening
+    pub fn listeners(&self) -> &Vec<HttpListener> {
+        return &self.listeners;
+    }
+
+    pub fn root(&self) -> &HttpRequestHandler {
+        return &self.root;
+    }
+
+    // Wraps every request handled by root, see Middleware
+    pub(crate) fn middleware(&self) -> &Vec<SharedMiddleware> {
+        return &self.middleware;
+    }
+
+    pub fn settings(&self) -> &HttpServerSettings {
+        return &self.settings;
+    }
+
+    pub fn request_mode(&self) -> HttpRequestMode {
+        return self.request_mode;
+    }
+
+    // None unless the server speaks HTTPS
+    #[cfg(feature = "tls")]
+    pub fn tls(&self) -> Option<&HttpTlsConfig> {
+        return self.tls.as_ref();
+    }
+
+    // Closed when the server starts shutting down, see HttpConnectionServer
+    pub fn shutdown_signal(&self) -> Receiver<()> {
+        return self.shutdown_receiver.clone();
+    }
+
+    // Start accepting connections, or replace the listeners if already started.
+    // Connections accepted earlier are not affected.
+    pub fn start(&mut self, listeners: Vec<HttpListener>) {
+        if listeners.is_empty() { panic!("can not start() without listeners"); }
+        if self.shutdown_sender.is_none() { // Not started yet, or shut down
+            let (shutdown_sender, shutdown_receiver) = smol::channel::bounded(1);
+            self.shutdown_sender = Some(shutdown_sender);
+            self.shutdown_receiver = shutdown_receiver;
+            self.shutdown_deadline = None;
+        }
+        self.listeners = listeners;
+    }
+
+    // Take the listeners to hand those still wanted back to start(), used by
+    // http_server_control for a rebind. Connections are not signalled.
+    pub(crate) fn take_listeners(&mut self) -> Vec<HttpListener> {
+        return std::mem::take(&mut self.listeners);
+    }
+
+    // Close the listeners and signal every connection to close after its
+    // current request. Does nothing unless the server is listening.
+    pub fn shutdown(&mut self) {
+        if !self.is_listening() { return; }
+        self.listeners.clear();
+        self.shutdown_sender = None;
+        self.shutdown_deadline = Some(Instant::now() + self.settings.shutdown_grace_period());
+    }
+
+    pub fn is_listening(&self) -> bool {
+        return !self.listeners.is_empty();
+    }
+
+    // True from shutdown() until start() is called again
+    pub fn is_shutting_down(&self) -> bool {
+        return self.shutdown_deadline.is_some();
+    }
+
+    // When connections still open are dropped, None unless shutting down
+    pub fn shutdown_deadline(&self) -> Option<Instant> {
+        return self.shutdown_deadline;
+    }
+
+}
@@ -0,0 +1,16 @@
+
+use bevy::prelude::*;
+
+
+// Inserted next to HttpClientAddress on every connection, and on every
+// HttpPendingRequest, to tell which server instance accepted it
+#[derive(Component, Clone, Debug, PartialEq, Eq)]
+pub struct HttpServerName(pub String);
+
+
+impl HttpServerName {
+
+    // Name of the instance added by a HttpServerPlugin without with_name()
+    pub const DEFAULT: &'static str = "default";
+
+}
@@ -1,27 +1,126 @@
 
-use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use bevy::prelude::*;
 use bevy::app::App;
 
+use super::HttpBindAddress;
+use super::HttpRequestEvent;
+use super::HttpServerCommand;
+use super::HttpServerControl;
+use super::HttpServerError;
 use super::HttpRequestHandler;
+use super::HttpServerInstance;
+use super::HttpServerName;
 use super::HttpServerResource;
+use super::HttpServerSettings;
+use super::HttpSseChannels;
+use super::Middleware;
+use super::http_middleware::SharedMiddleware;
+#[cfg(feature = "tls")]
+use super::HttpTlsConfig;
+use super::WsClosed;
+use super::WsMessageReceived;
+use super::WsSend;
+
+
+// How requests are delivered to the App
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HttpRequestMode {
+    // Requests are routed through the HttpRequestHandler tree by http_request_responder
+    #[default]
+    Handlers,
+    // Requests are spawned as HttpPendingRequest entities, the handler tree is not used
+    Entities,
+}
+
 
+// Each HttpServerPlugin adds one named server instance. Several can be added
+// to the same App as long as their names differ, e.g. a public API on one port
+// and an admin API on localhost only.
 pub struct HttpServerPlugin {
-    bind_address: SocketAddr,
+    name: String,
+    bind_addresses: Vec<HttpBindAddress>,
     root: HttpRequestHandler,
+    middleware: Vec<SharedMiddleware>,
+    settings: HttpServerSettings,
+    request_mode: HttpRequestMode,
+    autostart: bool,
+    #[cfg(feature = "tls")]
+    tls: Option<HttpTlsConfig>,
 }
 
 
 impl HttpServerPlugin {
 
-    pub fn new(bind_address: SocketAddr, root: HttpRequestHandler) -> Self {
+    // bind_address is e.g. "[::]:80".parse().unwrap() or HttpBindAddress::unix(path)
+    pub fn new(bind_address: HttpBindAddress, root: HttpRequestHandler) -> Self {
         HttpServerPlugin {
-            bind_address,
+            name: String::from(HttpServerName::DEFAULT),
+            bind_addresses: vec![bind_address],
             root,
+            middleware: Vec::new(),
+            settings: HttpServerSettings::default(),
+            request_mode: HttpRequestMode::default(),
+            autostart: true,
+            #[cfg(feature = "tls")]
+            tls: None,
         }
     }
 
+    // Required when adding more than one server instance, see HttpServerName
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = String::from(name);
+        return self;
+    }
+
+    // Listen on this address too, e.g. both an IPv4 and an IPv6 address
+    pub fn with_bind_address(mut self, bind_address: HttpBindAddress) -> Self {
+        self.bind_addresses.push(bind_address);
+        return self;
+    }
+
+    // With autostart disabled, the server does not listen until started
+    // with HttpServerControl::start() or HttpServerCommand::Start
+    pub fn with_autostart(mut self, autostart: bool) -> Self {
+        self.autostart = autostart;
+        return self;
+    }
+
+    pub fn with_request_mode(mut self, request_mode: HttpRequestMode) -> Self {
+        self.request_mode = request_mode;
+        return self;
+    }
+
+    // Wrap every request to this server, including those no handler was
+    // found for. Runs before any middleware of the handler tree, see Middleware.
+    // Can not be used with HttpRequestMode::Entities, building the plugin panics.
+    pub fn with_middleware(mut self, middleware: impl Middleware + Send + Sync + 'static) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        return self;
+    }
+
+    // Timeouts and limits for client connections, see HttpServerSettings
+    pub fn with_settings(mut self, settings: HttpServerSettings) -> Self {
+        self.settings = settings;
+        return self;
+    }
+
+    // Shorthand for with_settings(settings.with_response_timeout(response_timeout))
+    pub fn with_response_timeout(mut self, response_timeout: Duration) -> Self {
+        self.settings = self.settings.with_response_timeout(response_timeout);
+        return self;
+    }
+
+    // Speak HTTPS on every address of this server instance. Keep a clone of
+    // the HttpTlsConfig to swap certificates later.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, tls: HttpTlsConfig) -> Self {
+        self.tls = Some(tls);
+        return self;
+    }
+
 }
 
 
@@ -41,21 +140,48 @@ impl Plugin for HttpServerPlugin {
 
     // Configures the App to which this plugin is added.
     fn build(&self, app: &mut App) {
+        // Requests are answered by the App's own systems, nothing could call the middleware
+        if self.request_mode == HttpRequestMode::Entities && !self.middleware.is_empty() {
+            panic!("http server {:?} has middleware, which HttpRequestMode::Entities does not use", self.name);
+        }
 
-        let listener = vebb::listener(self.bind_address).unwrap();
-        listener.set_nonblocking(true).expect("can't set nonblocking = true");
-
-        let config = HttpServerResource::new(
-            listener, 
-            self.root.clone(),
-        );
+        // The systems are shared by all server instances, add them only once
+        if !app.world.contains_resource::<HttpServerResource>() {
+            app
+                .insert_resource(HttpServerResource::new())
+                .insert_resource(HttpServerControl::new())
+                .init_resource::<HttpSseChannels>()
+                .add_event::<HttpServerCommand>()
+                .add_event::<HttpServerError>()
+                .add_event::<HttpRequestEvent>()
+                .add_event::<WsMessageReceived>()
+                .add_event::<WsSend>()
+                .add_event::<WsClosed>()
+                .add_system(super::http_server_control.in_base_set(CoreSet::First))
+                .add_system(super::http_accept_connections)
+                .add_system(super::http_connection_status)
+                .add_system(super::http_request_responder)
+                .add_system(super::http_request_dispatcher.in_base_set(CoreSet::PreUpdate))
+                .add_system(super::http_response_collector.in_base_set(CoreSet::PostUpdate))
+                .add_system(super::http_server_shutdown.in_base_set(CoreSet::Last))
+                .add_system(super::http_sse_heartbeat.in_base_set(CoreSet::PostUpdate))
+                .add_system(super::http_websocket_receiver.in_base_set(CoreSet::PreUpdate))
+                .add_system(super::http_websocket_sender.in_base_set(CoreSet::PostUpdate))
+            ;
+        }
 
-        app
-            .insert_resource(config)
-            .add_system(super::http_accept_connections)
-            .add_system(super::http_connection_status)
-            .add_system(super::http_request_responder)
-        ;
+        // The listeners are bound by http_server_control in the first frame,
+        // see HttpServerControl for starting, stopping and rebinding them later
+        let instance = HttpServerInstance::new(&self.name, self.root.clone())
+            .with_middleware(self.middleware.clone())
+            .with_settings(self.settings)
+            .with_request_mode(self.request_mode);
+        #[cfg(feature = "tls")]
+        let instance = instance.with_tls(self.tls.clone());
+        app.world.resource_mut::<HttpServerResource>().insert(instance);
+        let mut control = app.world.resource_mut::<HttpServerControl>();
+        control.add(&self.name, self.bind_addresses.clone());
+        if self.autostart { control.start(&self.name); }
     }
 
     // Runs after all plugins are built, but before the app runner is called. 
@@ -75,8 +201,29 @@ impl Plugin for HttpServerPlugin {
     // If the plugin can be meaningfully instantiated several times in an App, 
     // override this method to return false.
     fn is_unique(&self) -> bool {
-        return true;
+        return false;
     }
 
 }
 
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use super::*;
+
+    struct NoopMiddleware;
+
+    impl Middleware for NoopMiddleware {}
+
+    #[test]
+    #[should_panic]
+    fn entities_with_middleware() {
+        let plugin = HttpServerPlugin::new("127.0.0.1:0".parse().unwrap(), HttpRequestHandler::dir("/"))
+            .with_request_mode(HttpRequestMode::Entities)
+            .with_middleware(NoopMiddleware);
+        App::new().add_plugin(plugin);
+    }
+
+}
@@ -1,34 +1,44 @@
 
-use std::net::TcpListener;
 use bevy::prelude::*;
 
-use super::HttpRequestHandler;
+use super::HttpServerInstance;
 
 
-#[derive(Resource)]
+// Every server instance added by HttpServerPlugin, in the order they were added
+#[derive(Resource, Default)]
 pub struct HttpServerResource {
-    listener: TcpListener,
-    root: HttpRequestHandler,
+    instances: Vec<HttpServerInstance>,
 }
 
 impl HttpServerResource {
 
-    pub fn new(listener: TcpListener, root: HttpRequestHandler) -> Self {
-        if root.dir_name() != "/" { 
-            panic!("root handler dir_name must be {:?}, not {:?}", String::from("/"), root.dir_name()); 
-        }
+    pub fn new() -> Self {
         HttpServerResource {
-            listener,
-            root,
+            instances: Vec::new(),
+        }
+    }
+
+    pub fn insert(&mut self, instance: HttpServerInstance) {
+        if self.get(instance.name()).is_some() {
+            panic!("http server {:?} was already added", instance.name());
         }
+        self.instances.push(instance);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&HttpServerInstance> {
+        return self.instances.iter().find(|instance| instance.name() == name);
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut HttpServerInstance> {
+        return self.instances.iter_mut().find(|instance| instance.name() == name);
     }
 
-    pub fn listener(&self) -> &TcpListener {
-        return &self.listener;
+    pub fn iter(&self) -> impl Iterator<Item = &HttpServerInstance> {
+        return self.instances.iter();
     }
 
-    pub fn root(&self) -> &HttpRequestHandler {
-        return &self.root;
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut HttpServerInstance> {
+        return self.instances.iter_mut();
     }
 
 }
@@ -0,0 +1,423 @@
+
+// Connection limits enforced by HttpConnectionServer and
+// http_accept_connections, configured with HttpServerPlugin::with_settings().
+// Timeouts are measured with smol timers since the sockets are non-blocking,
+// see HttpClientConnection.
+
+use std::time::Duration;
+
+use super::HttpErrorPolicy;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HttpServerSettings {
+    header_timeout: Duration,
+    body_timeout: Duration,
+    idle_timeout: Duration,
+    write_timeout: Duration,
+    response_timeout: Duration,
+    shutdown_grace_period: Duration,
+    max_requests: usize,
+    max_pipeline_depth: usize,
+    max_request_line: usize,
+    max_headers: usize,
+    max_header_bytes: usize,
+    max_body_bytes: usize,
+    stream_body_threshold: Option<usize>,
+    ws_ping_interval: Duration,
+    max_ws_message_bytes: usize,
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    max_accepts_per_frame: usize,
+    overload_policy: HttpOverloadPolicy,
+    error_policy: HttpErrorPolicy,
+}
+
+
+// What to do with connections beyond max_connections or max_connections_per_ip
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HttpOverloadPolicy {
+    // Stop accepting while at max_connections, clients wait in the listen
+    // backlog. Connections beyond max_connections_per_ip are accepted but
+    // wait unanswered until an earlier one from the same address closes, for
+    // at most header_timeout. As many may wait as max_connections_per_ip, any
+    // more from that address are closed.
+    #[default]
+    Backlog,
+    // Accept and answer 503 Service Unavailable with a Retry-After header.
+    // Accepting pauses while max_accepts_per_frame of these are being written.
+    Reject { retry_after: Duration },
+}
+
+
+impl HttpServerSettings {
+
+    pub fn new() -> Self {
+        HttpServerSettings {
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            response_timeout: Duration::from_secs(30),
+            shutdown_grace_period: Duration::from_secs(5),
+            max_requests: 1000,
+            max_pipeline_depth: 8,
+            max_request_line: 8 * 1024,
+            max_headers: 100,
+            max_header_bytes: 64 * 1024,
+            max_body_bytes: 1024 * 1024,
+            stream_body_threshold: None,
+            ws_ping_interval: Duration::from_secs(30),
+            max_ws_message_bytes: 1024 * 1024,
+            max_connections: None,
+            max_connections_per_ip: None,
+            max_accepts_per_frame: 64,
+            overload_policy: HttpOverloadPolicy::default(),
+            error_policy: HttpErrorPolicy::default(),
+        }
+    }
+
+    // How long a client may take to send the request line and headers,
+    // counted from the first byte, or from the connection being accepted for
+    // the first request. The client gets 408 Request Timeout.
+    pub fn with_header_timeout(mut self, header_timeout: Duration) -> Self {
+        self.header_timeout = header_timeout;
+        return self;
+    }
+
+    // How long a client may take to send the request body after the headers.
+    // The client gets 408 Request Timeout. A streamed body may take longer,
+    // as long as it never stalls for this long.
+    pub fn with_body_timeout(mut self, body_timeout: Duration) -> Self {
+        self.body_timeout = body_timeout;
+        return self;
+    }
+
+    // How long a connection may sit idle waiting for the next request,
+    // advertised to clients in the Keep-Alive header
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        return self;
+    }
+
+    // How long writing a response may take before the connection is dropped
+    pub fn with_write_timeout(mut self, write_timeout: Duration) -> Self {
+        self.write_timeout = write_timeout;
+        return self;
+    }
+
+    // How long a request may wait for its response, including deferred
+    // responses, before the client gets 504 Gateway Timeout. Counted from the
+    // end of a streamed request body.
+    pub fn with_response_timeout(mut self, response_timeout: Duration) -> Self {
+        self.response_timeout = response_timeout;
+        return self;
+    }
+
+    // How long connections may keep running after shutdown was requested,
+    // to finish requests in flight, before they are dropped
+    pub fn with_shutdown_grace_period(mut self, shutdown_grace_period: Duration) -> Self {
+        self.shutdown_grace_period = shutdown_grace_period;
+        return self;
+    }
+
+    // How many requests are served on one connection before it is closed,
+    // advertised to clients in the Keep-Alive header. Must be at least 1.
+    pub fn with_max_requests(mut self, max_requests: usize) -> Self {
+        if max_requests == 0 { panic!("max_requests must be at least 1"); }
+        self.max_requests = max_requests;
+        return self;
+    }
+
+    // How many requests are read ahead of the response being written, when
+    // a client sends several requests without waiting (HTTP pipelining). They
+    // are sent to the App as soon as they are read and may be handled in the
+    // same frame, the responses are always written in request order. 1 turns
+    // pipelining off. Must be at least 1.
+    pub fn with_max_pipeline_depth(mut self, max_pipeline_depth: usize) -> Self {
+        if max_pipeline_depth == 0 { panic!("max_pipeline_depth must be at least 1"); }
+        self.max_pipeline_depth = max_pipeline_depth;
+        return self;
+    }
+
+    // Longest request line accepted, not counting the line ending.
+    // Longer requests get 414 URI Too Long.
+    pub fn with_max_request_line(mut self, max_request_line: usize) -> Self {
+        self.max_request_line = max_request_line;
+        return self;
+    }
+
+    // Most headers accepted in one request, more get 431 Request Header Fields Too Large
+    pub fn with_max_headers(mut self, max_headers: usize) -> Self {
+        self.max_headers = max_headers;
+        return self;
+    }
+
+    // Most bytes accepted for all header lines of one request, including line
+    // endings. More get 431 Request Header Fields Too Large.
+    pub fn with_max_header_bytes(mut self, max_header_bytes: usize) -> Self {
+        self.max_header_bytes = max_header_bytes;
+        return self;
+    }
+
+    // Largest request body accepted, larger requests get 413 Payload Too Large.
+    // A Content-Length over the limit is refused before the body is read, a
+    // chunked body as soon as a chunk would take it over the limit.
+    pub fn with_max_body_bytes(mut self, max_body_bytes: usize) -> Self {
+        self.max_body_bytes = max_body_bytes;
+        return self;
+    }
+
+    // Request bodies larger than this, and all chunked bodies, are handed to
+    // the handler as they arrive instead of being read before the request is
+    // sent, see HttpBodyReceiver. Not streamed by default.
+    pub fn with_stream_body_threshold(mut self, stream_body_threshold: usize) -> Self {
+        self.stream_body_threshold = Some(stream_body_threshold);
+        return self;
+    }
+
+    // How long a WebSocket may go without traffic from the server before it is
+    // pinged. A client that has not answered by the next ping is disconnected.
+    pub fn with_ws_ping_interval(mut self, ws_ping_interval: Duration) -> Self {
+        self.ws_ping_interval = ws_ping_interval;
+        return self;
+    }
+
+    // Largest WebSocket message accepted, after putting fragments together.
+    // Larger messages close the socket with code 1009.
+    pub fn with_max_ws_message_bytes(mut self, max_ws_message_bytes: usize) -> Self {
+        self.max_ws_message_bytes = max_ws_message_bytes;
+        return self;
+    }
+
+    // How many connections may be open at the same time, unlimited by default.
+    // Must be at least 1.
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        if max_connections == 0 { panic!("max_connections must be at least 1"); }
+        self.max_connections = Some(max_connections);
+        return self;
+    }
+
+    // How many connections one IP address may have open at the same time,
+    // unlimited by default. Unix socket clients are not counted. Must be at least 1.
+    pub fn with_max_connections_per_ip(mut self, max_connections_per_ip: usize) -> Self {
+        if max_connections_per_ip == 0 { panic!("max_connections_per_ip must be at least 1"); }
+        self.max_connections_per_ip = Some(max_connections_per_ip);
+        return self;
+    }
+
+    // How many connections are accepted per frame, the rest wait for the next
+    // frame so a flood of connections does not stall the App. Must be at least 1.
+    pub fn with_max_accepts_per_frame(mut self, max_accepts_per_frame: usize) -> Self {
+        if max_accepts_per_frame == 0 { panic!("max_accepts_per_frame must be at least 1"); }
+        self.max_accepts_per_frame = max_accepts_per_frame;
+        return self;
+    }
+
+    pub fn with_overload_policy(mut self, overload_policy: HttpOverloadPolicy) -> Self {
+        self.overload_policy = overload_policy;
+        return self;
+    }
+
+    // What to do when the listener fails to bind or accept, see HttpServerError
+    pub fn with_error_policy(mut self, error_policy: HttpErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        return self;
+    }
+
+    pub fn header_timeout(&self) -> Duration {
+        return self.header_timeout;
+    }
+
+    pub fn body_timeout(&self) -> Duration {
+        return self.body_timeout;
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        return self.idle_timeout;
+    }
+
+    pub fn write_timeout(&self) -> Duration {
+        return self.write_timeout;
+    }
+
+    pub fn response_timeout(&self) -> Duration {
+        return self.response_timeout;
+    }
+
+    pub fn shutdown_grace_period(&self) -> Duration {
+        return self.shutdown_grace_period;
+    }
+
+    pub fn max_requests(&self) -> usize {
+        return self.max_requests;
+    }
+
+    pub fn max_pipeline_depth(&self) -> usize {
+        return self.max_pipeline_depth;
+    }
+
+    pub fn max_request_line(&self) -> usize {
+        return self.max_request_line;
+    }
+
+    pub fn max_headers(&self) -> usize {
+        return self.max_headers;
+    }
+
+    pub fn max_header_bytes(&self) -> usize {
+        return self.max_header_bytes;
+    }
+
+    pub fn max_body_bytes(&self) -> usize {
+        return self.max_body_bytes;
+    }
+
+    pub fn stream_body_threshold(&self) -> Option<usize> {
+        return self.stream_body_threshold;
+    }
+
+    pub fn ws_ping_interval(&self) -> Duration {
+        return self.ws_ping_interval;
+    }
+
+    pub fn max_ws_message_bytes(&self) -> usize {
+        return self.max_ws_message_bytes;
+    }
+
+    pub fn max_connections(&self) -> Option<usize> {
+        return self.max_connections;
+    }
+
+    pub fn max_connections_per_ip(&self) -> Option<usize> {
+        return self.max_connections_per_ip;
+    }
+
+    pub fn max_accepts_per_frame(&self) -> usize {
+        return self.max_accepts_per_frame;
+    }
+
+    pub fn overload_policy(&self) -> HttpOverloadPolicy {
+        return self.overload_policy;
+    }
+
+    pub fn error_policy(&self) -> HttpErrorPolicy {
+        return self.error_policy;
+    }
+
+    // Value of the Keep-Alive response header when `remaining` more requests
+    // are allowed. The timeout is rounded up to whole seconds.
+    pub fn keep_alive_header(&self, remaining: usize) -> String {
+        let mut timeout = self.idle_timeout.as_secs();
+        if self.idle_timeout.subsec_nanos() > 0 { timeout += 1; }
+        return format!("timeout={}, max={}", timeout, remaining);
+    }
+
+}
+
+
+impl Default for HttpServerSettings {
+
+    fn default() -> Self {
+        return HttpServerSettings::new();
+    }
+
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default() {
+        let settings = HttpServerSettings::default();
+        assert_eq!(settings.max_requests(), 1000);
+        assert_eq!(settings.max_pipeline_depth(), 8);
+        assert_eq!(settings.max_body_bytes(), 1024 * 1024);
+        assert_eq!(settings.stream_body_threshold(), None);
+        assert_eq!(settings.ws_ping_interval(), Duration::from_secs(30));
+        assert_eq!(settings.max_ws_message_bytes(), 1024 * 1024);
+        assert_eq!(settings.max_connections(), None);
+        assert_eq!(settings.max_connections_per_ip(), None);
+        assert_eq!(settings.overload_policy(), HttpOverloadPolicy::Backlog);
+        assert_eq!(settings.keep_alive_header(1000), "timeout=30, max=1000");
+    }
+
+    #[test]
+    fn keep_alive_header_rounded_up() {
+        let settings = HttpServerSettings::new().with_idle_timeout(Duration::from_millis(500));
+        assert_eq!(settings.keep_alive_header(1), "timeout=1, max=1");
+        let settings = HttpServerSettings::new().with_idle_timeout(Duration::from_millis(2001));
+        assert_eq!(settings.keep_alive_header(1), "timeout=3, max=1");
+    }
+
+    #[test]
+    fn builders() {
+        let settings = HttpServerSettings::new()
+            .with_header_timeout(Duration::from_secs(1))
+            .with_body_timeout(Duration::from_secs(2))
+            .with_idle_timeout(Duration::from_secs(3))
+            .with_write_timeout(Duration::from_secs(4))
+            .with_response_timeout(Duration::from_secs(5))
+            .with_shutdown_grace_period(Duration::from_secs(7))
+            .with_max_requests(6)
+            .with_max_pipeline_depth(19)
+            .with_max_request_line(12)
+            .with_max_headers(13)
+            .with_max_header_bytes(14)
+            .with_max_body_bytes(15)
+            .with_stream_body_threshold(16)
+            .with_ws_ping_interval(Duration::from_secs(17))
+            .with_max_ws_message_bytes(18)
+            .with_max_connections(8)
+            .with_max_connections_per_ip(9)
+            .with_max_accepts_per_frame(10)
+            .with_overload_policy(HttpOverloadPolicy::Reject { retry_after: Duration::from_secs(11) });
+        assert_eq!(settings.header_timeout(), Duration::from_secs(1));
+        assert_eq!(settings.body_timeout(), Duration::from_secs(2));
+        assert_eq!(settings.idle_timeout(), Duration::from_secs(3));
+        assert_eq!(settings.write_timeout(), Duration::from_secs(4));
+        assert_eq!(settings.response_timeout(), Duration::from_secs(5));
+        assert_eq!(settings.shutdown_grace_period(), Duration::from_secs(7));
+        assert_eq!(settings.keep_alive_header(5), "timeout=3, max=5");
+        assert_eq!(settings.max_pipeline_depth(), 19);
+        assert_eq!(settings.max_request_line(), 12);
+        assert_eq!(settings.max_headers(), 13);
+        assert_eq!(settings.max_header_bytes(), 14);
+        assert_eq!(settings.max_body_bytes(), 15);
+        assert_eq!(settings.stream_body_threshold(), Some(16));
+        assert_eq!(settings.ws_ping_interval(), Duration::from_secs(17));
+        assert_eq!(settings.max_ws_message_bytes(), 18);
+        assert_eq!(settings.max_connections(), Some(8));
+        assert_eq!(settings.max_connections_per_ip(), Some(9));
+        assert_eq!(settings.max_accepts_per_frame(), 10);
+        assert_eq!(settings.overload_policy(), HttpOverloadPolicy::Reject { retry_after: Duration::from_secs(11) });
+    }
+
+    #[test]
+    #[should_panic]
+    fn max_requests_zero() {
+        let _settings = HttpServerSettings::new().with_max_requests(0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn max_pipeline_depth_zero() {
+        let _settings = HttpServerSettings::new().with_max_pipeline_depth(0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn max_connections_zero() {
+        let _settings = HttpServerSettings::new().with_max_connections(0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn max_accepts_per_frame_zero() {
+        let _settings = HttpServerSettings::new().with_max_accepts_per_frame(0);
+    }
+
+}
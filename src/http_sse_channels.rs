@@ -0,0 +1,244 @@
+/*
+HttpSseChannels holds the named Server-Sent Event channels. Browsers subscribe
+through a route added with HttpRequestHandler::sse() and any system can publish:
+
+    fn score_changed(mut channels: ResMut<HttpSseChannels>, score: Res<Score>) {
+        if score.is_changed() {
+            channels.publish("score", HttpSseEvent::new(&score.to_string()).with_event("score"));
+        }
+    }
+
+Bevy events can be forwarded to a channel with http_sse_forward(). Every event
+gets the next id of its channel and the most recent ones are kept, so a client
+reconnecting with Last-Event-ID gets the events it missed. Idle streams get a
+comment line every heartbeat interval so proxies do not time them out.
+
+Each subscriber queues only a limited number of events. A client that falls
+that far behind is disconnected, and catches up from the replay buffer when it
+reconnects. To change the defaults, insert the resource before adding
+HttpServerPlugin:
+
+    app.insert_resource(HttpSseChannels::new().with_replay_buffer(1000));
+
+See also: HttpSseEvent, HttpBodySender
+*/
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+
+use http::Response;
+use bytes::Bytes;
+
+use super::HttpBodySender;
+use super::HttpSseEvent;
+use super::http_protocol;
+
+
+// Subscribers and recent events of one channel
+struct HttpSseChannel {
+    next_id: u64,
+    recent: VecDeque<(u64, Bytes)>,
+    subscribers: Vec<HttpBodySender>,
+    last_sent: Instant,
+}
+
+
+#[derive(Resource)]
+pub struct HttpSseChannels {
+    channels: HashMap<String, HttpSseChannel>,
+    replay_buffer: usize,
+    subscriber_capacity: usize,
+    heartbeat_interval: Duration,
+}
+
+
+impl HttpSseChannels {
+
+    pub fn new() -> Self {
+        HttpSseChannels {
+            channels: HashMap::new(),
+            replay_buffer: 100,
+            subscriber_capacity: 64,
+            heartbeat_interval: Duration::from_secs(15),
+        }
+    }
+
+    // How many recent events per channel are kept for Last-Event-ID replay
+    pub fn with_replay_buffer(mut self, replay_buffer: usize) -> Self {
+        self.replay_buffer = replay_buffer;
+        return self;
+    }
+
+    // How many events may be queued for one subscriber before it is
+    // disconnected for being too slow. Must be at least 1.
+    pub fn with_subscriber_capacity(mut self, subscriber_capacity: usize) -> Self {
+        if subscriber_capacity == 0 { panic!("subscriber_capacity must be at least 1"); }
+        self.subscriber_capacity = subscriber_capacity;
+        return self;
+    }
+
+    pub fn with_heartbeat_interval(mut self, heartbeat_interval: Duration) -> Self {
+        self.heartbeat_interval = heartbeat_interval;
+        return self;
+    }
+
+    // Send an event to every subscriber of the channel, returns its id
+    pub fn publish(&mut self, channel: &str, event: HttpSseEvent) -> u64 {
+        let replay_buffer = self.replay_buffer;
+        let channel = self.channel_mut(channel);
+        let id = channel.next_id;
+        channel.next_id += 1;
+        let bytes = event.to_bytes(id);
+        channel.recent.push_back((id, bytes.clone()));
+        while channel.recent.len() > replay_buffer {
+            channel.recent.pop_front();
+        }
+        channel.send(bytes);
+        return id;
+    }
+
+    // A text/event-stream response subscribed to the channel, starting with
+    // the buffered events after last_event_id if given
+    pub fn subscribe(&mut self, channel: &str, last_event_id: Option<u64>) -> Response<Bytes> {
+        let subscriber_capacity = self.subscriber_capacity;
+        let channel = self.channel_mut(channel);
+        let mut replay = Vec::new();
+        if let Some(last_event_id) = last_event_id {
+            for (id, bytes) in channel.recent.iter() {
+                if *id > last_event_id { replay.extend_from_slice(bytes); }
+            }
+        }
+        let mut response = Response::new(Bytes::from(replay));
+        http_protocol::header_if_missing(&mut response, "Content-Type", "text/event-stream");
+        http_protocol::header_if_missing(&mut response, "Cache-Control", "no-cache");
+        channel.subscribers.push(HttpBodySender::streaming(&mut response, subscriber_capacity));
+        return response;
+    }
+
+    // Number of connected subscribers, as of the last publish or heartbeat
+    pub fn subscribers(&self, channel: &str) -> usize {
+        return self.channels.get(channel).map_or(0, |channel| channel.subscribers.len());
+    }
+
+    // Id of the last event published to the channel
+    pub fn last_event_id(&self, channel: &str) -> Option<u64> {
+        return self.channels.get(channel).and_then(|channel| channel.recent.back().map(|(id, _)| *id));
+    }
+
+    // Used by http_sse_heartbeat
+    pub(crate) fn heartbeat(&mut self, now: Instant) {
+        for channel in self.channels.values_mut() {
+            channel.subscribers.retain(|subscriber| !subscriber.is_closed());
+            if now.duration_since(channel.last_sent) < self.heartbeat_interval { continue; }
+            channel.send(Bytes::from_static(b":\n\n"));
+            channel.last_sent = now;
+        }
+    }
+
+    fn channel_mut(&mut self, channel: &str) -> &mut HttpSseChannel {
+        return self.channels.entry(String::from(channel)).or_insert_with(|| HttpSseChannel {
+            next_id: 1,
+            recent: VecDeque::new(),
+            subscribers: Vec::new(),
+            last_sent: Instant::now(),
+        });
+    }
+
+}
+
+
+impl HttpSseChannel {
+
+    // Subscribers that are gone or too slow are dropped, which ends their stream
+    fn send(&mut self, bytes: Bytes) {
+        self.subscribers.retain(|subscriber| subscriber.try_send(bytes.clone()).is_ok());
+        self.last_sent = Instant::now();
+    }
+
+}
+
+
+impl Default for HttpSseChannels {
+
+    fn default() -> Self {
+        return HttpSseChannels::new();
+    }
+
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use smol::channel::Receiver;
+
+    use super::*;
+
+    fn subscribe(channels: &mut HttpSseChannels, last_event_id: Option<u64>) -> (Response<Bytes>, Receiver<Bytes>) {
+        let mut response = channels.subscribe("test", last_event_id);
+        let stream = HttpBodySender::take_stream(&mut response).unwrap();
+        return (response, stream);
+    }
+
+    #[test]
+    fn subscribe_headers() {
+        let mut channels = HttpSseChannels::new();
+        let (response, _stream) = subscribe(&mut channels, None);
+        assert_eq!(response.headers().get("Content-Type").unwrap(), "text/event-stream");
+        assert_eq!(response.headers().get("Cache-Control").unwrap(), "no-cache");
+        assert_eq!(response.body().len(), 0);
+        assert_eq!(channels.subscribers("test"), 1);
+    }
+
+    #[test]
+    fn publish() {
+        let mut channels = HttpSseChannels::new();
+        let (_response, stream) = subscribe(&mut channels, None);
+        assert_eq!(channels.publish("test", HttpSseEvent::new("one")), 1);
+        assert_eq!(channels.publish("test", HttpSseEvent::new("two")), 2);
+        assert_eq!(channels.publish("other", HttpSseEvent::new("three")), 1);
+        assert_eq!(stream.try_recv().unwrap(), Bytes::from_static(b"id: 1\ndata: one\n\n"));
+        assert_eq!(stream.try_recv().unwrap(), Bytes::from_static(b"id: 2\ndata: two\n\n"));
+        assert_eq!(stream.is_empty(), true);
+        assert_eq!(channels.last_event_id("test"), Some(2));
+    }
+
+    #[test]
+    fn replay() {
+        let mut channels = HttpSseChannels::new().with_replay_buffer(2);
+        for data in ["one", "two", "three"] {
+            channels.publish("test", HttpSseEvent::new(data));
+        }
+        let (response, _stream) = subscribe(&mut channels, Some(2));
+        assert_eq!(response.body(), &Bytes::from_static(b"id: 3\ndata: three\n\n"));
+        let (response, _stream) = subscribe(&mut channels, Some(0));
+        assert_eq!(response.body(), &Bytes::from_static(b"id: 2\ndata: two\n\nid: 3\ndata: three\n\n"));
+    }
+
+    #[test]
+    fn slow_subscriber_dropped() {
+        let mut channels = HttpSseChannels::new().with_subscriber_capacity(1);
+        let (_response, stream) = subscribe(&mut channels, None);
+        channels.publish("test", HttpSseEvent::new("one"));
+        channels.publish("test", HttpSseEvent::new("two"));
+        assert_eq!(channels.subscribers("test"), 0);
+        assert_eq!(stream.try_recv().unwrap(), Bytes::from_static(b"id: 1\ndata: one\n\n"));
+        assert_eq!(stream.is_closed(), true);
+    }
+
+    #[test]
+    fn heartbeat() {
+        let mut channels = HttpSseChannels::new().with_heartbeat_interval(Duration::from_secs(15));
+        let (_response, stream) = subscribe(&mut channels, None);
+        let (_gone, gone_stream) = subscribe(&mut channels, None);
+        drop(gone_stream);
+        channels.heartbeat(Instant::now());
+        assert_eq!(channels.subscribers("test"), 1);
+        assert_eq!(stream.is_empty(), true);
+        channels.heartbeat(Instant::now() + Duration::from_secs(15));
+        assert_eq!(stream.try_recv().unwrap(), Bytes::from_static(b":\n\n"));
+    }
+
+}
@@ -0,0 +1,105 @@
+
+// One Server-Sent Event, published to a named channel with
+// HttpSseChannels::publish(). The id is assigned by the channel so clients
+// can resume with Last-Event-ID after reconnecting.
+//
+//    channels.publish("chat", HttpSseEvent::new("hello").with_event("message"));
+
+use std::time::Duration;
+
+use bytes::Bytes;
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HttpSseEvent {
+    event: Option<String>,
+    data: String,
+    retry: Option<Duration>,
+}
+
+
+impl HttpSseEvent {
+
+    // Data may span several lines, each is sent as a separate data field
+    pub fn new(data: &str) -> Self {
+        HttpSseEvent {
+            event: None,
+            data: String::from(data),
+            retry: None,
+        }
+    }
+
+    // Event type, dispatched to addEventListener(event) instead of onmessage
+    pub fn with_event(mut self, event: &str) -> Self {
+        if event.contains(['\r', '\n']) {
+            panic!("event {:?} cannot contain line breaks", event);
+        }
+        self.event = Some(String::from(event));
+        return self;
+    }
+
+    // How long the client should wait before reconnecting
+    pub fn with_retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        return self;
+    }
+
+    pub fn event(&self) -> Option<&str> {
+        return self.event.as_deref();
+    }
+
+    pub fn data(&self) -> &str {
+        return self.data.as_str();
+    }
+
+    pub fn retry(&self) -> Option<Duration> {
+        return self.retry;
+    }
+
+    // The event in text/event-stream format, ending with an empty line
+    pub fn to_bytes(&self, id: u64) -> Bytes {
+        let mut text = format!("id: {}\n", id);
+        if let Some(event) = &self.event {
+            text.push_str(&format!("event: {}\n", event));
+        }
+        if let Some(retry) = self.retry {
+            text.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        for line in self.data.split('\n') {
+            text.push_str(&format!("data: {}\n", line.strip_suffix('\r').unwrap_or(line)));
+        }
+        text.push('\n');
+        return Bytes::from(text);
+    }
+
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_bytes() {
+        let event = HttpSseEvent::new("hello");
+        assert_eq!(event.to_bytes(7), Bytes::from_static(b"id: 7\ndata: hello\n\n"));
+    }
+
+    #[test]
+    fn to_bytes_all_fields() {
+        let event = HttpSseEvent::new("one\r\ntwo\n")
+            .with_event("score")
+            .with_retry(Duration::from_secs(3));
+        assert_eq!(event.event(), Some("score"));
+        let facit = b"id: 1\nevent: score\nretry: 3000\ndata: one\ndata: two\ndata: \n\n";
+        assert_eq!(String::from_utf8_lossy(&event.to_bytes(1)), String::from_utf8_lossy(facit));
+    }
+
+    #[test]
+    #[should_panic]
+    fn event_line_break() {
+        let _event = HttpSseEvent::new("").with_event("a\nb");
+    }
+
+}
@@ -0,0 +1,74 @@
+
+// Answers a request with a text/event-stream subscribed to one channel of
+// HttpSseChannels, resuming after the Last-Event-ID header if the client sent
+// one. Usually added with HttpRequestHandler::sse().
+
+use bevy::prelude::*;
+use http::{Request, Response};
+use bytes::Bytes;
+
+use super::Handler;
+use super::HttpError;
+use super::HttpPathParams;
+use super::HttpSseChannels;
+
+
+pub struct HttpSseHandler {
+    channel: String,
+}
+
+
+impl HttpSseHandler {
+
+    pub fn new(channel: &str) -> Self {
+        HttpSseHandler {
+            channel: String::from(channel),
+        }
+    }
+
+}
+
+
+impl Handler for HttpSseHandler {
+    fn call(&self, world: &mut World, request: &Request<Bytes>, _params: &HttpPathParams) -> Result<Response<Bytes>, HttpError> {
+        let last_event_id = request.headers().get("Last-Event-ID")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok());
+        let mut channels = world.get_resource_mut::<HttpSseChannels>()
+            .ok_or_else(|| HttpError::internal("HttpSseChannels resource missing"))?;
+        return Ok(channels.subscribe(&self.channel, last_event_id));
+    }
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use crate::HttpBodySender;
+    use crate::HttpSseEvent;
+
+    use super::*;
+    use http::StatusCode;
+
+    #[test]
+    fn subscribe() {
+        let mut world = World::new();
+        world.insert_resource(HttpSseChannels::new());
+        world.resource_mut::<HttpSseChannels>().publish("scores", HttpSseEvent::new("one"));
+        world.resource_mut::<HttpSseChannels>().publish("scores", HttpSseEvent::new("two"));
+        let request = Request::builder().uri("/events").header("Last-Event-ID", "1").body(Bytes::new()).unwrap();
+        let response = HttpSseHandler::new("scores").call(&mut world, &request, &HttpPathParams::new()).unwrap();
+        assert_eq!(HttpBodySender::is_streaming(&response), true);
+        assert_eq!(response.body(), &Bytes::from_static(b"id: 2\ndata: two\n\n"));
+        assert_eq!(world.resource::<HttpSseChannels>().subscribers("scores"), 1);
+    }
+
+    #[test]
+    fn no_resource() {
+        let mut world = World::new();
+        let request = Request::builder().uri("/events").body(Bytes::new()).unwrap();
+        let result = HttpSseHandler::new("scores").call(&mut world, &request, &HttpPathParams::new());
+        assert_eq!(result.unwrap_err(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+}
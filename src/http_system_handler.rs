@@ -0,0 +1,249 @@
+
+// Handlers written like Bevy systems: the first argument is a request
+// extractor (see FromHttpRequest), the remaining arguments are SystemParams,
+// and the return value is anything implementing IntoResponse:
+//
+//    fn player(request: HttpRequest, players: Query<&Player>, score: Res<Score>) -> impl IntoResponse
+//
+// The SystemState for each handler is created on first use and cached across
+// frames, deferred Commands are applied right after the handler returns.
+// Modeled on bevy::ecs::system::SystemParamFunction.
+
+use std::marker::PhantomData;
+use std::sync::Mutex;
+
+use bevy::prelude::*;
+use bevy::ecs::system::{SystemParam, SystemParamItem, SystemState};
+use http::{Request, Response};
+use bytes::Bytes;
+
+use super::{FromHttpRequest, Handler, HttpError, HttpPathParams, IntoHandler, IntoResponse};
+
+
+pub trait SystemHandlerFunction<Marker>: Send + Sync + 'static {
+    type Request: FromHttpRequest;
+    type Param: SystemParam + 'static;
+    fn run(&self, request: Self::Request, param_value: SystemParamItem<Self::Param>) -> Result<Response<Bytes>, HttpError>;
+}
+
+
+pub struct SystemHandler<F, Marker>
+where
+    F: SystemHandlerFunction<Marker>
+{
+    function: F,
+    state: Mutex<Option<SystemState<F::Param>>>,
+    marker: PhantomData<fn() -> Marker>,
+}
+
+
+impl<F, Marker> SystemHandler<F, Marker>
+where
+    F: SystemHandlerFunction<Marker>
+{
+
+    pub fn new(function: F) -> Self {
+        SystemHandler {
+            function,
+            state: Mutex::new(None),
+            marker: PhantomData,
+        }
+    }
+
+}
+
+
+impl<F, Marker> Handler for SystemHandler<F, Marker>
+where
+    F: SystemHandlerFunction<Marker>
+{
+    fn call(&self, world: &mut World, request: &Request<Bytes>, params: &HttpPathParams) -> Result<Response<Bytes>, HttpError> {
+        let request = F::Request::from_http_request(request, params)?;
+
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            // An earlier call panicked, possibly halfway through using the
+            // SystemState, so start over with a new one
+            Err(poisoned) => {
+                self.state.clear_poison();
+                let mut state = poisoned.into_inner();
+                *state = None;
+                state
+            }
+        };
+        if !state.as_ref().map_or(false, |state| state.matches_world(world)) {
+            *state = Some(SystemState::new(world));
+        }
+        let state = state.as_mut().unwrap();
+
+        let response = self.function.run(request, state.get_mut(world));
+        state.apply(world);
+        return response;
+    }
+}
+
+
+#[doc(hidden)]
+pub struct IsSystemHandler;
+
+
+impl<F, Marker> IntoHandler<(IsSystemHandler, Marker)> for F
+where
+    F: SystemHandlerFunction<Marker>,
+    Marker: 'static,
+{
+    type Handler = SystemHandler<F, Marker>;
+    fn into_handler(self) -> Self::Handler {
+        return SystemHandler::new(self);
+    }
+}
+
+
+macro_rules! impl_system_handler_function {
+    ($($param: ident),*) => {
+        #[allow(non_snake_case)]
+        impl<Func, Req, Out, $($param: SystemParam + 'static),*> SystemHandlerFunction<fn(Req, $($param,)*) -> Out> for Func
+        where
+            Func: Send + Sync + 'static,
+            for <'a> &'a Func:
+                Fn(Req, $($param),*) -> Out +
+                Fn(Req, $(SystemParamItem<$param>),*) -> Out,
+            Req: FromHttpRequest,
+            Out: IntoResponse,
+        {
+            type Request = Req;
+            type Param = ($($param,)*);
+            fn run(&self, request: Req, param_value: SystemParamItem<($($param,)*)>) -> Result<Response<Bytes>, HttpError> {
+                #[allow(clippy::too_many_arguments)]
+                fn call_inner<Req, Out, $($param,)*>(
+                    f: impl Fn(Req, $($param,)*) -> Out,
+                    request: Req,
+                    $($param: $param,)*
+                ) -> Out {
+                    f(request, $($param,)*)
+                }
+                let ($($param,)*) = param_value;
+                return call_inner(self, request, $($param),*).into_response();
+            }
+        }
+    };
+}
+
+impl_system_handler_function!();
+impl_system_handler_function!(P0);
+impl_system_handler_function!(P0, P1);
+impl_system_handler_function!(P0, P1, P2);
+impl_system_handler_function!(P0, P1, P2, P3);
+impl_system_handler_function!(P0, P1, P2, P3, P4);
+impl_system_handler_function!(P0, P1, P2, P3, P4, P5);
+impl_system_handler_function!(P0, P1, P2, P3, P4, P5, P6);
+impl_system_handler_function!(P0, P1, P2, P3, P4, P5, P6, P7);
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use super::*;
+    use http::StatusCode;
+    use crate::{HttpRequest, HttpRequestHandler};
+
+    #[derive(Component)]
+    struct Player(u32);
+
+    #[derive(Resource)]
+    struct Score(u32);
+
+    fn players(request: HttpRequest, players: Query<&Player>, score: Res<Score>) -> impl IntoResponse {
+        return format!("{} {} {}", request.uri().path(), players.iter().count(), score.0);
+    }
+
+    fn player(params: HttpPathParams, players: Query<&Player>) -> Result<String, StatusCode> {
+        let id: u32 = params.get("id").unwrap().parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+        let player = players.iter().find(|player| player.0 == id).ok_or(StatusCode::NOT_FOUND)?;
+        return Ok(format!("player {}", player.0));
+    }
+
+    fn spawn_player(_request: HttpRequest, mut commands: Commands) -> StatusCode {
+        commands.spawn(Player(99));
+        return StatusCode::CREATED;
+    }
+
+    fn counter(_request: HttpRequest, mut count: Local<u32>) -> String {
+        *count += 1;
+        return format!("{}", *count);
+    }
+
+    #[derive(Resource)]
+    struct Crash(bool);
+
+    fn fragile(_request: HttpRequest, crash: Res<Crash>) -> &'static str {
+        if crash.0 { panic!("handler crashed"); }
+        return "ok";
+    }
+
+    fn test_world() -> World {
+        let mut world = World::new();
+        world.insert_resource(Score(7));
+        world.spawn(Player(1));
+        world.spawn(Player(2));
+        return world;
+    }
+
+    fn handle(handler: &HttpRequestHandler, world: &mut World, uri: &str) -> Result<Response<Bytes>, HttpError> {
+        let request = Request::builder().uri(uri).body(Bytes::from_static(b"")).unwrap();
+        return handler.handle(world, "/", &request);
+    }
+
+    #[test]
+    fn system_params() {
+        let handler = HttpRequestHandler::new("/", players);
+        let response = handle(&handler, &mut test_world(), "/").unwrap();
+        assert_eq!(response.into_body(), Bytes::from_static(b"/ 2 7"));
+    }
+
+    #[test]
+    fn path_params() {
+        let handler = HttpRequestHandler::dir("/")
+            .add_child(HttpRequestHandler::dir(":id").get(player));
+        let mut world = test_world();
+        assert_eq!(handle(&handler, &mut world, "/2").unwrap().into_body(), Bytes::from_static(b"player 2"));
+        assert_eq!(handle(&handler, &mut world, "/3").unwrap_err(), StatusCode::NOT_FOUND);
+        assert_eq!(handle(&handler, &mut world, "/x").unwrap_err(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn commands_applied() {
+        let handler = HttpRequestHandler::new("/", spawn_player);
+        let mut world = test_world();
+        let response = handle(&handler, &mut world, "/").unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(world.query::<&Player>().iter(&world).count(), 3);
+    }
+
+    #[test]
+    fn state_cached() {
+        let handler = HttpRequestHandler::new("/", counter);
+        let mut world = test_world();
+        assert_eq!(handle(&handler, &mut world, "/").unwrap().into_body(), Bytes::from_static(b"1"));
+        assert_eq!(handle(&handler.clone(), &mut world, "/").unwrap().into_body(), Bytes::from_static(b"2"));
+    }
+
+    #[test]
+    fn recover_after_panic() {
+        let handler = HttpRequestHandler::new("/", fragile);
+        let mut world = test_world();
+        world.insert_resource(Crash(true));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| handle(&handler, &mut world, "/")));
+        assert_eq!(result.is_err(), true);
+        world.insert_resource(Crash(false));
+        assert_eq!(handle(&handler, &mut world, "/").unwrap().into_body(), Bytes::from_static(b"ok"));
+    }
+
+    #[test]
+    fn state_per_world() {
+        let handler = HttpRequestHandler::new("/", counter);
+        assert_eq!(handle(&handler, &mut test_world(), "/").unwrap().into_body(), Bytes::from_static(b"1"));
+        assert_eq!(handle(&handler, &mut test_world(), "/").unwrap().into_body(), Bytes::from_static(b"1"));
+    }
+
+}
@@ -1,49 +1,488 @@
 
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
 
 use bevy::prelude::*;
-use bevy::tasks::AsyncComputeTaskPool;
+use bevy::app::AppExit;
+use bevy::tasks::IoTaskPool;
+use smol::channel::Receiver;
+use http::Request;
+use bytes::Bytes;
 
 use crate::HttpClientAddress;
 use crate::HttpClientConnection;
 use crate::HttpConnectionServer;
 use crate::HttpConnectionTask;
+use crate::HttpErrorPolicy;
+use crate::HttpListener;
+use crate::HttpOverloadPolicy;
+use crate::HttpServerError;
+use crate::HttpServerInstance;
+use crate::HttpServerName;
 use crate::HttpServerResource;
+use crate::HttpServerSettings;
 
 
 pub fn http_accept_connections(
-    server: Res<HttpServerResource>,
+    servers: Res<HttpServerResource>,
+    connections: Query<(&HttpClientAddress, &HttpServerName), With<HttpConnectionTask>>,
+    mut states: Local<HashMap<String, HttpAcceptState>>,
+    mut errors: EventWriter<HttpServerError>,
+    mut exit: EventWriter<AppExit>,
     mut commands: Commands,
 ) {
+    for server in servers.iter() {
+        let state = states.entry(String::from(server.name())).or_default();
+
+        // Connections waiting for their turn are closed along with the listeners
+        if !server.is_listening() { state.waiting.clear(); }
+
+        // Accepting is paused for a while after an error with HttpErrorPolicy::Retry
+        if let Some(instant) = state.paused_until {
+            if Instant::now() < instant { continue; }
+            state.paused_until = None;
+        }
+
+        // Connections still open, checked against the limits in HttpServerSettings
+        let mut count = HttpConnectionCount::default();
+        for (peer, name) in connections.iter() {
+            if name.0 == server.name() { count.add(peer); }
+        }
+        let mut budget = server.settings().max_accepts_per_frame();
+        serve_waiting(server, state, &mut count, &mut budget, &mut commands);
+
+        // There are no listeners while the server is stopped
+        for listener in server.listeners() {
+            if let Err(error) = accept_connections(server, listener, state, &mut count, &mut budget, &mut commands) {
+                warn!("{}", error);
+                errors.send(error);
+                match server.settings().error_policy() {
+                    HttpErrorPolicy::Retry { backoff } => state.paused_until = Some(Instant::now() + backoff),
+                    HttpErrorPolicy::Continue => {}
+                    HttpErrorPolicy::Exit => exit.send(AppExit),
+                }
+                break;
+            }
+        }
+    }
+}
+
+
+// Accepting state of one server instance, kept between frames in a Local
+#[derive(Default)]
+pub struct HttpAcceptState {
+    paused_until: Option<Instant>,
+    // Accepted beyond max_connections_per_ip with HttpOverloadPolicy::Backlog,
+    // served once an earlier connection from the same address has closed.
+    // Each is kept along with when it was accepted, see serve_waiting().
+    waiting: VecDeque<(HttpClientConnection, Instant)>,
+    refusals: HttpRefusals,
+}
+
+
+impl HttpAcceptState {
+
+    // Number of connections waiting from the address of peer
+    fn waiting_from(&self, peer: &HttpClientAddress) -> usize {
+        return self.waiting.iter().filter(|(connection, _)| connection.peer().ip() == peer.ip()).count();
+    }
+
+}
+
+
+// Open connections of one server instance
+#[derive(Default)]
+struct HttpConnectionCount {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+
+impl HttpConnectionCount {
+
+    fn add(&mut self, peer: &HttpClientAddress) {
+        self.total += 1;
+        if let Some(ip) = peer.ip() { *self.per_ip.entry(ip).or_insert(0) += 1; }
+    }
+
+    fn per_ip(&self, peer: &HttpClientAddress) -> usize {
+        return peer.ip().and_then(|ip| self.per_ip.get(&ip).copied()).unwrap_or(0);
+    }
+
+    fn ip_at_limit(&self, settings: &HttpServerSettings, peer: &HttpClientAddress) -> bool {
+        return settings.max_connections_per_ip().map_or(false, |max| self.per_ip(peer) >= max);
+    }
+
+    fn at_limit(&self, settings: &HttpServerSettings) -> bool {
+        return settings.max_connections().map_or(false, |max| self.total >= max);
+    }
+
+}
+
+
+// Number of 503 responses still being written by HttpOverloadPolicy::Reject,
+// each held by the task writing it
+#[derive(Clone, Default)]
+struct HttpRefusals(Arc<AtomicUsize>);
+
+
+// Decrements HttpRefusals when dropped, i.e. when the refusing task ends
+struct HttpRefusal(Arc<AtomicUsize>);
+
+
+impl HttpRefusals {
+
+    fn in_progress(&self) -> usize {
+        return self.0.load(Ordering::Acquire);
+    }
+
+    fn start(&self) -> HttpRefusal {
+        self.0.fetch_add(1, Ordering::AcqRel);
+        return HttpRefusal(self.0.clone());
+    }
+
+}
+
+
+impl Drop for HttpRefusal {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+
+// Helper function for http_accept_connections(), starts the connections
+// waiting for their address to get below max_connections_per_ip, oldest first.
+// A connection waiting longer than header_timeout is closed, as it would have
+// been had it been served and not sent a request in that time.
+fn serve_waiting(
+    server: &HttpServerInstance,
+    state: &mut HttpAcceptState,
+    count: &mut HttpConnectionCount,
+    budget: &mut usize,
+    commands: &mut Commands,
+) {
+    let settings = server.settings();
+    let mut still_waiting = VecDeque::new();
+    while let Some((connection, accepted)) = state.waiting.pop_front() {
+        if accepted.elapsed() >= settings.header_timeout() {
+            info!("{} closed by http server {:?}, timed out waiting", connection.peer(), server.name());
+            continue;
+        }
+        if *budget == 0 || count.at_limit(settings) || count.ip_at_limit(settings, &connection.peer()) {
+            still_waiting.push_back((connection, accepted));
+            continue;
+        }
+        *budget -= 1;
+        spawn_connection(server, connection, count, commands);
+    }
+    state.waiting = still_waiting;
+}
+
+
+// Helper function for http_accept_connections()
+fn accept_connections(
+    server: &HttpServerInstance,
+    listener: &HttpListener,
+    state: &mut HttpAcceptState,
+    count: &mut HttpConnectionCount,
+    budget: &mut usize,
+    commands: &mut Commands,
+) -> Result<(), HttpServerError> {
+    let settings = server.settings();
     loop {
-        match server.listener().accept() {
+        // The rest are accepted in the next frame
+        if *budget == 0 { return Ok(()); }
+        let at_limit = count.at_limit(settings);
+        if at_limit && settings.overload_policy() == HttpOverloadPolicy::Backlog { return Ok(()); }
+        // At most max_accepts_per_frame refusals at a time, so a flood of
+        // connections waits in the listen backlog rather than in tasks
+        let refusing = matches!(settings.overload_policy(), HttpOverloadPolicy::Reject { .. });
+        if refusing && state.refusals.in_progress() >= settings.max_accepts_per_frame() { return Ok(()); }
+
+        match listener.accept() {
             Err(os_error) => {
                 // WouldBlock means no connections waiting; come back later
-                if os_error.kind() == std::io::ErrorKind::WouldBlock { break; }
-                // Any other error means something went wrong
-                panic!("accept() on http listener returned {}", os_error);
+                if os_error.kind() == std::io::ErrorKind::WouldBlock { return Ok(()); }
+                // Any other error means something went wrong, e.g. out of file descriptors
+                return Err(HttpServerError::AcceptFailed { server: String::from(server.name()), message: format!("{}", os_error) });
             }
-            Ok((stream, peer)) => {
-                info!("{:?} connected", peer);    
-                stream.set_nonblocking(false).expect("can't set non_blocking = false");
-                let request = Arc::new(Mutex::new(None));
-                let response = Arc::new(Mutex::new(None));
-                let mut connserv = HttpConnectionServer::new(
-                    HttpClientConnection::new(stream, peer),
-                    request.clone(),
-                    response.clone(),
-                );
-            
-                let pool = AsyncComputeTaskPool::get();
-
-                let task = pool.spawn(async move {
-                    return connserv.run();
-                });
-
-                commands
-                    .spawn(HttpConnectionTask::new(task, request, response))
-                    .insert(HttpClientAddress(peer));
+            Ok(connection) => {
+                *budget -= 1;
+                let peer = connection.peer();
+                if !at_limit && !count.ip_at_limit(settings, &peer) {
+                    spawn_connection(server, connection, count, commands);
+                    continue;
+                }
+                match settings.overload_policy() {
+                    HttpOverloadPolicy::Backlog => {
+                        // The address is only known once accepted, so wait here
+                        // rather than in the listen backlog. As many connections
+                        // may wait as are open, the rest are closed.
+                        let max_waiting = settings.max_connections_per_ip().unwrap_or(0);
+                        if state.waiting_from(&peer) >= max_waiting {
+                            info!("{} closed by http server {:?}, too many connections from this address", peer, server.name());
+                            continue;
+                        }
+                        info!("{} waiting for http server {:?}, too many connections from this address", peer, server.name());
+                        state.waiting.push_back((connection, Instant::now()));
+                    }
+                    HttpOverloadPolicy::Reject { retry_after } => {
+                        info!("{} refused by http server {:?}, too many connections", peer, server.name());
+                        let refusal = state.refusals.start();
+                        let mut connserv = connection_server(server, connection).0;
+                        IoTaskPool::get().spawn(async move {
+                            let _ = connserv.refuse(retry_after).await;
+                            drop(refusal);
+                        }).detach();
+                    }
+                }
             }
         }
     }
 }
+
+
+// Helper function for accept_connections() and serve_waiting()
+fn spawn_connection(server: &HttpServerInstance, connection: HttpClientConnection, count: &mut HttpConnectionCount, commands: &mut Commands) {
+    let peer = connection.peer();
+    info!("{} connected to http server {:?}", peer, server.name());
+    count.add(&peer);
+    let (mut connserv, receiver) = connection_server(server, connection);
+    let task = IoTaskPool::get().spawn(async move {
+        return connserv.run().await;
+    });
+
+    commands
+        .spawn(HttpConnectionTask::new(task, receiver))
+        .insert(peer)
+        .insert(HttpServerName(String::from(server.name())));
+}
+
+
+// Helper function for spawn_connection() and accept_connections()
+fn connection_server(server: &HttpServerInstance, connection: HttpClientConnection) -> (HttpConnectionServer, Receiver<Request<Bytes>>) {
+    let settings = server.settings();
+    let (sender, receiver) = smol::channel::bounded(settings.max_pipeline_depth());
+    #[allow(unused_mut)]
+    let mut connserv = HttpConnectionServer::new(connection, sender)
+        .with_settings(*settings)
+        .with_shutdown_signal(server.shutdown_signal());
+    #[cfg(feature = "tls")]
+    { connserv = connserv.with_tls(server.tls().map(|tls| tls.server_config())); }
+    return (connserv, receiver);
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use std::io::Read;
+    use std::net::{SocketAddr, TcpStream};
+    use std::time::Duration;
+
+    use bevy::ecs::schedule::ExecutorKind;
+    use bevy::tasks::TaskPool;
+
+    use crate::HttpBindAddress;
+    use crate::HttpRequestHandler;
+
+    use super::*;
+
+    fn tcp(address: &str) -> HttpClientAddress {
+        return HttpClientAddress::Tcp(address.parse().unwrap());
+    }
+
+    // A world with one listening server, and the schedule keeping the Local state
+    fn test_world(settings: HttpServerSettings) -> (World, Schedule, SocketAddr) {
+        return test_world_at(settings, "127.0.0.1:0");
+    }
+
+    fn test_world_at(settings: HttpServerSettings, bind_address: &str) -> (World, Schedule, SocketAddr) {
+        IoTaskPool::init(TaskPool::new);
+        let listener = HttpListener::bind(&bind_address.parse().unwrap()).unwrap();
+        let address = match listener.local_addr().unwrap() {
+            HttpBindAddress::Tcp(address) => address,
+            _ => panic!("expected a tcp address"),
+        };
+        let mut server = HttpServerInstance::new("public", HttpRequestHandler::dir("/")).with_settings(settings);
+        server.start(vec![listener]);
+        let mut servers = HttpServerResource::new();
+        servers.insert(server);
+
+        let mut world = World::new();
+        world.insert_resource(servers);
+        world.init_resource::<Events<HttpServerError>>();
+        world.init_resource::<Events<AppExit>>();
+        let mut schedule = Schedule::new();
+        schedule.set_executor_kind(ExecutorKind::SingleThreaded);
+        schedule.add_system(http_accept_connections);
+        return (world, schedule, address);
+    }
+
+    fn peers(world: &mut World) -> Vec<HttpClientAddress> {
+        return world.query_filtered::<&HttpClientAddress, With<HttpConnectionTask>>().iter(world).cloned().collect();
+    }
+
+    fn close_all(world: &mut World) {
+        let entities: Vec<Entity> = world.query_filtered::<Entity, With<HttpConnectionTask>>().iter(world).collect();
+        for entity in entities { world.despawn(entity); }
+    }
+
+    // Nothing was answered and the connection is still open
+    fn is_waiting(client: &mut TcpStream) -> bool {
+        client.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let mut buffer = [0u8; 1];
+        return client.read(&mut buffer).is_err();
+    }
+
+    // Closed by the server without an answer
+    fn is_closed(client: &mut TcpStream) -> bool {
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let mut buffer = [0u8; 1];
+        match client.read(&mut buffer) {
+            Ok(count) => return count == 0,
+            Err(error) => return error.kind() == std::io::ErrorKind::ConnectionReset,
+        }
+    }
+
+    #[test]
+    fn connection_count() {
+        let mut count = HttpConnectionCount::default();
+        count.add(&tcp("10.0.0.1:1000"));
+        count.add(&tcp("10.0.0.1:1001"));
+        count.add(&tcp("10.0.0.2:1000"));
+        count.add(&HttpClientAddress::Unix(None));
+        assert_eq!(count.total, 4);
+        assert_eq!(count.per_ip(&tcp("10.0.0.1:2000")), 2);
+        assert_eq!(count.per_ip(&tcp("10.0.0.2:2000")), 1);
+        assert_eq!(count.per_ip(&tcp("10.0.0.3:2000")), 0);
+        assert_eq!(count.per_ip(&HttpClientAddress::Unix(None)), 0);
+        let settings = HttpServerSettings::new().with_max_connections_per_ip(2);
+        assert_eq!(count.ip_at_limit(&settings, &tcp("10.0.0.1:2000")), true);
+        assert_eq!(count.ip_at_limit(&settings, &tcp("10.0.0.2:2000")), false);
+        assert_eq!(count.ip_at_limit(&HttpServerSettings::new(), &tcp("10.0.0.1:2000")), false);
+    }
+
+    #[test]
+    fn refusals() {
+        let refusals = HttpRefusals::default();
+        let first = refusals.start();
+        let second = refusals.clone().start();
+        assert_eq!(refusals.in_progress(), 2);
+        drop(first);
+        assert_eq!(refusals.in_progress(), 1);
+        drop(second);
+        assert_eq!(refusals.in_progress(), 0);
+    }
+
+    #[test]
+    fn accepts_per_frame() {
+        let (mut world, mut schedule, address) = test_world(HttpServerSettings::new().with_max_accepts_per_frame(2));
+        let _clients: Vec<TcpStream> = (0..5).map(|_| TcpStream::connect(address).unwrap()).collect();
+        schedule.run(&mut world);
+        assert_eq!(peers(&mut world).len(), 2);
+        schedule.run(&mut world);
+        assert_eq!(peers(&mut world).len(), 4);
+        schedule.run(&mut world);
+        assert_eq!(peers(&mut world).len(), 5);
+    }
+
+    #[test]
+    fn backlog_max_connections() {
+        let (mut world, mut schedule, address) = test_world(HttpServerSettings::new().with_max_connections(1));
+        let first = TcpStream::connect(address).unwrap();
+        let mut second = TcpStream::connect(address).unwrap();
+        schedule.run(&mut world);
+        schedule.run(&mut world);
+        assert_eq!(peers(&mut world), vec![HttpClientAddress::Tcp(first.local_addr().unwrap())]);
+        assert_eq!(is_waiting(&mut second), true);
+        close_all(&mut world);
+        schedule.run(&mut world);
+        assert_eq!(peers(&mut world), vec![HttpClientAddress::Tcp(second.local_addr().unwrap())]);
+    }
+
+    #[test]
+    fn backlog_max_connections_per_ip() {
+        let (mut world, mut schedule, address) = test_world(HttpServerSettings::new().with_max_connections_per_ip(1));
+        let first = TcpStream::connect(address).unwrap();
+        let mut second = TcpStream::connect(address).unwrap();
+        schedule.run(&mut world);
+        schedule.run(&mut world);
+        assert_eq!(peers(&mut world), vec![HttpClientAddress::Tcp(first.local_addr().unwrap())]);
+        assert_eq!(is_waiting(&mut second), true);
+        close_all(&mut world);
+        schedule.run(&mut world);
+        assert_eq!(peers(&mut world), vec![HttpClientAddress::Tcp(second.local_addr().unwrap())]);
+    }
+
+    #[test]
+    fn backlog_waiting_per_ip_limited() {
+        let (mut world, mut schedule, address) = test_world(HttpServerSettings::new().with_max_connections_per_ip(2));
+        let mut clients: Vec<TcpStream> = (0..6).map(|_| TcpStream::connect(address).unwrap()).collect();
+        schedule.run(&mut world);
+        assert_eq!(peers(&mut world).len(), 2);
+        // As many wait as are open, the rest are closed
+        assert_eq!(is_waiting(&mut clients[2]), true);
+        assert_eq!(is_waiting(&mut clients[3]), true);
+        assert_eq!(is_closed(&mut clients[4]), true);
+        assert_eq!(is_closed(&mut clients[5]), true);
+        close_all(&mut world);
+        schedule.run(&mut world);
+        let served = vec![HttpClientAddress::Tcp(clients[2].local_addr().unwrap()), HttpClientAddress::Tcp(clients[3].local_addr().unwrap())];
+        assert_eq!(peers(&mut world), served);
+    }
+
+    #[test]
+    fn backlog_waiting_timed_out() {
+        let settings = HttpServerSettings::new().with_max_connections_per_ip(1).with_header_timeout(Duration::from_millis(50));
+        let (mut world, mut schedule, address) = test_world(settings);
+        let _first = TcpStream::connect(address).unwrap();
+        let mut second = TcpStream::connect(address).unwrap();
+        schedule.run(&mut world);
+        assert_eq!(is_waiting(&mut second), true);
+        std::thread::sleep(Duration::from_millis(100));
+        schedule.run(&mut world);
+        assert_eq!(is_closed(&mut second), true);
+        close_all(&mut world);
+        schedule.run(&mut world);
+        assert_eq!(peers(&mut world).len(), 0);
+    }
+
+    #[test]
+    fn backlog_waiting_does_not_block_other_ips() {
+        // Dual-stack, so IPv4 clients come from ::ffff:127.0.0.1 and IPv6 clients from ::1
+        let settings = HttpServerSettings::new().with_max_connections(2).with_max_connections_per_ip(1);
+        let (mut world, mut schedule, address) = test_world_at(settings, "[::]:0");
+        let _flood: Vec<TcpStream> = (0..4).map(|_| TcpStream::connect(("127.0.0.1", address.port())).unwrap()).collect();
+        schedule.run(&mut world);
+        schedule.run(&mut world);
+        assert_eq!(peers(&mut world).len(), 1);
+        let other = TcpStream::connect(("::1", address.port())).unwrap();
+        schedule.run(&mut world);
+        let peers = peers(&mut world);
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers.contains(&HttpClientAddress::Tcp(other.local_addr().unwrap())), true);
+    }
+
+    #[test]
+    fn reject() {
+        let settings = HttpServerSettings::new()
+            .with_max_connections(1)
+            .with_overload_policy(HttpOverloadPolicy::Reject { retry_after: Duration::from_secs(3) });
+        let (mut world, mut schedule, address) = test_world(settings);
+        let first = TcpStream::connect(address).unwrap();
+        let mut second = TcpStream::connect(address).unwrap();
+        schedule.run(&mut world);
+        assert_eq!(peers(&mut world), vec![HttpClientAddress::Tcp(first.local_addr().unwrap())]);
+        second.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let mut response = String::new();
+        second.read_to_string(&mut response).unwrap();
+        assert_eq!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), true);
+        assert_eq!(response.to_lowercase().contains("retry-after: 3\r\n"), true);
+    }
+
+}
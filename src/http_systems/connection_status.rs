@@ -3,16 +3,20 @@ use bevy::prelude::*;
 use smol::future;
 
 use crate::HttpClientAddress;
+use crate::HttpConnectionError;
 use crate::HttpConnectionTask;
+use crate::HttpServerError;
+use crate::HttpServerName;
 
 
 pub fn http_connection_status(
-    mut query: Query<(Entity, &mut HttpConnectionTask, &HttpClientAddress)>,
+    mut query: Query<(Entity, &mut HttpConnectionTask, &HttpClientAddress, &HttpServerName)>,
+    mut errors: EventWriter<HttpServerError>,
     mut commands: Commands,
 ) {
     // Check status of async tasks
-    for (entity, mut conntask, peer) in query.iter_mut() {
-        check_conntask_status(entity, &mut conntask, peer, &mut commands);
+    for (entity, mut conntask, peer, server) in query.iter_mut() {
+        check_conntask_status(entity, &mut conntask, peer, server, &mut errors, &mut commands);
     }
 }
 
@@ -22,12 +26,23 @@ fn check_conntask_status(
     task_entity: Entity,
     handle: &mut HttpConnectionTask,
     peer: &HttpClientAddress,
+    server: &HttpServerName,
+    errors: &mut EventWriter<HttpServerError>,
     commands: &mut Commands,
 ) {
     if let Some(result) = future::block_on(future::poll_once(handle.get_mut_task())) {
         match result {
-            Ok(_) => info!("{:?} disconnected", peer.0),
-            Err(msg) => warn!("HttpConnectionTask crashed: {}", msg),
+            Ok(_) => info!("{} disconnected", peer),
+            Err(HttpConnectionError::BadRequest(status)) => {
+                let error = HttpServerError::BadRequest { server: server.0.clone(), peer: peer.clone(), status };
+                warn!("{}", error);
+                errors.send(error);
+            }
+            Err(HttpConnectionError::Failed(message)) => {
+                let error = HttpServerError::ConnectionFailed { server: server.0.clone(), peer: peer.clone(), message };
+                warn!("{}", error);
+                errors.send(error);
+            }
         }
         commands
             .entity(task_entity)
@@ -36,3 +51,56 @@ fn check_conntask_status(
 }
 
 
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use std::time::Duration;
+
+    use bevy::ecs::schedule::ExecutorKind;
+    use bevy::tasks::{IoTaskPool, TaskPool};
+    use http::StatusCode;
+
+    use super::*;
+
+    // Run http_connection_status until the task of the only connection has ended
+    fn run_until_despawned(world: &mut World) {
+        let mut schedule = Schedule::new();
+        schedule.set_executor_kind(ExecutorKind::SingleThreaded);
+        schedule.add_system(http_connection_status);
+        for _ in 0..100 {
+            schedule.run(world);
+            if world.query::<&HttpConnectionTask>().iter(world).next().is_none() { return; }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the connection task did not end");
+    }
+
+    fn spawn_connection(world: &mut World, result: Result<(), HttpConnectionError>) {
+        let task = IoTaskPool::init(TaskPool::new).spawn(async move { return result; });
+        world.spawn(HttpConnectionTask::new(task, smol::channel::bounded(1).1))
+            .insert(HttpClientAddress::Unix(None))
+            .insert(HttpServerName(String::from("public")));
+    }
+
+    #[test]
+    fn request_timeout() {
+        let mut world = World::new();
+        world.init_resource::<Events<HttpServerError>>();
+        spawn_connection(&mut world, Err(HttpConnectionError::BadRequest(StatusCode::REQUEST_TIMEOUT)));
+        run_until_despawned(&mut world);
+        let errors: Vec<HttpServerError> = world.resource_mut::<Events<HttpServerError>>().drain().collect();
+        let expected = HttpServerError::BadRequest { server: String::from("public"), peer: HttpClientAddress::Unix(None), status: StatusCode::REQUEST_TIMEOUT };
+        assert_eq!(errors, vec![expected]);
+    }
+
+    #[test]
+    fn failed() {
+        let mut world = World::new();
+        world.init_resource::<Events<HttpServerError>>();
+        spawn_connection(&mut world, Err(HttpConnectionError::Failed(String::from("broken pipe"))));
+        run_until_despawned(&mut world);
+        let errors: Vec<HttpServerError> = world.resource_mut::<Events<HttpServerError>>().drain().collect();
+        assert_eq!(matches!(errors.as_slice(), [HttpServerError::ConnectionFailed { .. }]), true);
+    }
+
+}
@@ -2,7 +2,23 @@
 mod accept_connections;
 mod connection_status;
 mod request_responder;
+mod request_dispatcher;
+mod response_collector;
+mod server_control;
+mod server_shutdown;
+mod sse_forward;
+mod sse_heartbeat;
+mod websocket_receiver;
+mod websocket_sender;
 
 pub use accept_connections::*;
 pub use connection_status::*;
 pub use request_responder::*;
+pub use request_dispatcher::*;
+pub use response_collector::*;
+pub use server_control::*;
+pub use server_shutdown::*;
+pub use sse_forward::*;
+pub use sse_heartbeat::*;
+pub use websocket_receiver::*;
+pub use websocket_sender::*;
@@ -0,0 +1,133 @@
+
+use bevy::prelude::*;
+
+use crate::HttpClientAddress;
+use crate::HttpConnectionTask;
+use crate::HttpPendingRequest;
+use crate::HttpRequestEvent;
+use crate::HttpRequestMode;
+use crate::HttpResponseSender;
+use crate::HttpServerName;
+use crate::HttpServerResource;
+
+
+// Used instead of http_request_responder for servers using HttpRequestMode::Entities
+pub fn http_request_dispatcher(
+    servers: Res<HttpServerResource>,
+    mut query: Query<(&mut HttpConnectionTask, &HttpClientAddress, &HttpServerName)>,
+    mut events: EventWriter<HttpRequestEvent>,
+    mut commands: Commands,
+) {
+    for (mut conntask, peer, name) in query.iter_mut() {
+        if !conntask.has_request() { continue; }
+        let request_mode = servers.get(&name.0).map(|server| server.request_mode());
+        if request_mode != Some(HttpRequestMode::Entities) { continue; }
+        // Pipelined requests are all spawned, in order
+        while conntask.has_request() {
+            let request = conntask.take_request();
+            match HttpResponseSender::from_request(&request) {
+                None => warn!("{} request without HttpResponseSender dropped", peer),
+                Some(sender) => {
+                    let entity = commands
+                        .spawn(HttpPendingRequest::new(request, sender))
+                        .insert(peer.clone())
+                        .insert(name.clone())
+                        .id();
+                    events.send(HttpRequestEvent { entity });
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use bevy::ecs::schedule::ExecutorKind;
+    use bevy::tasks::{AsyncComputeTaskPool, TaskPool};
+    use smol::channel::{Receiver, Sender};
+    use http::{Request, Response};
+use bytes::Bytes;
+
+    use crate::HttpRequestHandler;
+    use crate::HttpResponse;
+    use crate::HttpServerInstance;
+    use crate::http_response_collector;
+
+    use super::*;
+
+    fn run_system<Params>(world: &mut World, system: impl IntoSystemConfig<Params>) {
+        let mut schedule = Schedule::new();
+        schedule.set_executor_kind(ExecutorKind::SingleThreaded);
+        schedule.add_system(system);
+        schedule.run(world);
+    }
+
+    fn test_world(request_mode: HttpRequestMode) -> World {
+        let mut world = World::new();
+        let mut servers = HttpServerResource::new();
+        servers.insert(HttpServerInstance::new(HttpServerName::DEFAULT, HttpRequestHandler::dir("/")).with_request_mode(request_mode));
+        world.insert_resource(servers);
+        world.init_resource::<Events<HttpRequestEvent>>();
+        return world;
+    }
+
+    // A connection with two requests queued, and the receiving end for their responses
+    fn spawn_connection(world: &mut World) -> (Sender<Request<Bytes>>, Receiver<Response<Bytes>>) {
+        let pool = AsyncComputeTaskPool::init(TaskPool::new);
+        let (request_sender, request_receiver) = smol::channel::bounded(2);
+        let (response_sender, response_receiver) = smol::channel::bounded(2);
+        for uri in ["/one", "/two"] {
+            let mut request = Request::builder().uri(uri).body(Bytes::new()).unwrap();
+            request.extensions_mut().insert(HttpResponseSender::new(response_sender.clone()));
+            request_sender.try_send(request).unwrap();
+        }
+        world.spawn((
+            HttpConnectionTask::new(pool.spawn(async { Ok(()) }), request_receiver),
+            HttpClientAddress::Unix(None),
+            HttpServerName(String::from(HttpServerName::DEFAULT)),
+        ));
+        return (request_sender, response_receiver);
+    }
+
+    #[test]
+    fn spawn_and_answer() {
+        let mut world = test_world(HttpRequestMode::Entities);
+        let (_requests, responses) = spawn_connection(&mut world);
+        run_system(&mut world, http_request_dispatcher);
+
+        let events = world.resource::<Events<HttpRequestEvent>>();
+        let entities: Vec<Entity> = events.get_reader().iter(events).map(|event| event.entity).collect();
+        assert_eq!(entities.len(), 2);
+        let uris: Vec<String> = entities.iter().map(|entity| world.get::<HttpPendingRequest>(*entity).unwrap().request().uri().to_string()).collect();
+        assert_eq!(uris, vec!["/one", "/two"]);
+        assert_eq!(world.get::<HttpClientAddress>(entities[0]), Some(&HttpClientAddress::Unix(None)));
+
+        world.entity_mut(entities[1]).insert(HttpResponse(Response::new(Bytes::from_static(b"two"))));
+        run_system(&mut world, http_response_collector);
+        assert_eq!(responses.try_recv().unwrap().into_body(), Bytes::from_static(b"two"));
+        assert_eq!(world.get_entity(entities[1]).is_none(), true);
+        assert_eq!(world.get_entity(entities[0]).is_some(), true);
+    }
+
+    #[test]
+    fn expired_request_despawned() {
+        let mut world = test_world(HttpRequestMode::Entities);
+        let (_requests, responses) = spawn_connection(&mut world);
+        run_system(&mut world, http_request_dispatcher);
+        drop(responses); // The connection is gone
+        run_system(&mut world, http_response_collector);
+        assert_eq!(world.query::<&HttpPendingRequest>().iter(&world).count(), 0);
+    }
+
+    #[test]
+    fn handlers_mode_ignored() {
+        let mut world = test_world(HttpRequestMode::Handlers);
+        let (_requests, _responses) = spawn_connection(&mut world);
+        run_system(&mut world, http_request_dispatcher);
+        assert_eq!(world.query::<&HttpPendingRequest>().iter(&world).count(), 0);
+        assert_eq!(world.query::<&HttpConnectionTask>().single(&world).has_request(), true);
+    }
+
+}
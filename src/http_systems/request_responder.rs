@@ -1,10 +1,16 @@
 
 use bevy::prelude::*;
 
-use vebb::*;
+use http::Request;
+use bytes::Bytes;
 
 use crate::HttpConnectionTask;
+use crate::HttpRequestHandler;
+use crate::HttpRequestMode;
+use crate::HttpResponseSender;
+use crate::HttpServerName;
 use crate::HttpServerResource;
+use crate::http_middleware::{apply_middleware, SharedMiddleware};
 
 
 // This system has World access, which means it can read/write any entity, component or resource
@@ -14,31 +20,38 @@ pub fn http_request_responder(
     // https://docs.rs/bevy/latest/bevy/ecs/system/struct.SystemState.html
     let mut system_state: bevy::ecs::system::SystemState<(
         Res<HttpServerResource>,
-        Query<(Entity, &mut HttpConnectionTask)>,
+        Query<(Entity, &mut HttpConnectionTask, &HttpServerName)>,
     )> = bevy::ecs::system::SystemState::new(world);
 
-    // Clone the server root request handler
-    let (server, mut query) = system_state.get_mut(world);
-    let server_root = server.root().clone();
-
     // For any HttpConnectionTask that has a request pending, get the request
+    // along with a clone of the root request handler and middleware of the server that accepted it.
     // This borrows &mut World only temporarily because the requests are taken, not borrowed
-    let mut requests = Vec::<(Entity, Request<Bytes>)>::new();
-    for (entity, mut conntask) in query.iter_mut() {
-        if conntask.has_request() {
-            requests.push((entity, conntask.take_request()));
+    let (servers, mut query) = system_state.get_mut(world);
+    let mut requests = Vec::<(Entity, HttpRequestHandler, Vec<SharedMiddleware>, Request<Bytes>)>::new();
+    // Pipelined requests are all taken, in order
+    for (entity, mut conntask, name) in query.iter_mut() {
+        if !conntask.has_request() { continue; }
+        let server = match servers.get(&name.0) {
+            None => continue,
+            Some(server) => server,
+        };
+        // Requests to a server using HttpRequestMode::Entities are left for http_request_dispatcher
+        if server.request_mode() != HttpRequestMode::Handlers { continue; }
+        while conntask.has_request() {
+            requests.push((entity, server.root().clone(), server.middleware().clone(), conntask.take_request()));
         }
     }
 
-    // Handle each request and put each response back into each HttpConnectionTask
-    for (entity, request) in requests {
-        let response = match server_root.handle(world, "/", &request) {
-            Err(status) => server_root.error_response(status),
-            Ok(mut response) => {
-                finalize_response(&request, &mut response);
-                response
-            }
-        };
+    // Handle each request and put each response back into each HttpConnectionTask,
+    // unless the handler deferred it to be sent later using HttpResponseSender
+    for (entity, server_root, middleware, request) in requests {
+        let response = apply_middleware(&middleware, world, &request, |world| Ok(server_root.respond(world, "/", &request)));
+        if HttpResponseSender::is_deferred(&response) { continue; }
+        // The sender drops the response if the request has timed out meanwhile
+        if let Some(sender) = HttpResponseSender::from_request(&request) {
+            sender.send(response);
+            continue;
+        }
         match world.entity_mut(entity).get_mut::<HttpConnectionTask>() {
             None => {} // Entity and/or HttpConnectionTask is gone, drop response
             Some(mut conntask) => { 
@@ -49,16 +62,117 @@ pub fn http_request_responder(
 }
 
 
-// Helper function for http_request_responder()
-fn finalize_response(request: &Request<Bytes>, response: &mut Response<Bytes>) {
-    if vebb::keep_alive_requested(request) && !vebb::keep_alive_denied(response) {
-        vebb::header_if_missing(response, "Connection", "keep-alive");
-        vebb::header_if_missing(response, "Keep-Alive", "timeout=30, max=1000");
-    } else {
-        vebb::header_if_missing(response, "Connection", "close");
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use std::sync::Arc;
+
+    use bevy::tasks::{AsyncComputeTaskPool, TaskPool};
+    use smol::channel::Receiver;
+
+    use crate::HttpClientAddress;
+    use crate::HttpError;
+    use crate::HttpPathParams;
+    use crate::HttpServerInstance;
+    use crate::Middleware;
+
+    use super::*;
+    use http::{HeaderValue, Response, StatusCode};
+
+    struct ServerMiddleware;
+
+    impl Middleware for ServerMiddleware {
+        fn before(&self, _world: &mut World, request: &Request<Bytes>) -> Result<Option<Response<Bytes>>, HttpError> {
+            if request.uri().path() == "/denied" { return Err(HttpError::new(StatusCode::FORBIDDEN)); }
+            return Ok(None);
+        }
+
+        fn after(&self, _world: &mut World, _request: &Request<Bytes>, response: &mut Response<Bytes>) {
+            response.headers_mut().insert("X-Server", HeaderValue::from_static("yes"));
+        }
     }
-    let len = format!("{}", response.body().len());
-    header_if_missing(response, "Content-Length", len.as_str());
-    header_if_missing(response, "Content-Type", "text/html; charset=utf-8");
-}
 
+    struct HandlerMiddleware;
+
+    impl Middleware for HandlerMiddleware {
+        fn after(&self, _world: &mut World, _request: &Request<Bytes>, response: &mut Response<Bytes>) {
+            // Runs before the server middleware's after()
+            let inner = !response.headers().contains_key("X-Server");
+            response.headers_mut().insert("X-Handler", HeaderValue::from_static(if inner { "inner" } else { "outer" }));
+        }
+    }
+
+    fn ok(_world: &mut World, _request: &Request<Bytes>, _params: &HttpPathParams) -> Result<Response<Bytes>, StatusCode> {
+        return Ok(Response::new(Bytes::from_static(b"ok")));
+    }
+
+    fn later(world: &mut World, request: &Request<Bytes>, _params: &HttpPathParams) -> Result<Response<Bytes>, StatusCode> {
+        world.spawn(HttpResponseSender::from_request(request).unwrap());
+        return Ok(HttpResponseSender::deferred());
+    }
+
+    // Queues the requests for uris to a server with ServerMiddleware, and
+    // returns the receiver of their responses
+    fn test_world(uris: &[&str]) -> (World, Receiver<Response<Bytes>>) {
+        let mut world = World::new();
+        let root = HttpRequestHandler::dir("/")
+            .add_child(HttpRequestHandler::new("game", ok).with_middleware(HandlerMiddleware))
+            .add_child(HttpRequestHandler::new("later", later).with_middleware(HandlerMiddleware));
+        let server = HttpServerInstance::new(HttpServerName::DEFAULT, root)
+            .with_middleware(vec![Arc::new(ServerMiddleware)]);
+        let mut servers = HttpServerResource::new();
+        servers.insert(server);
+        world.insert_resource(servers);
+
+        let pool = AsyncComputeTaskPool::init(TaskPool::new);
+        let (request_sender, request_receiver) = smol::channel::bounded(uris.len());
+        let (response_sender, response_receiver): (_, Receiver<Response<Bytes>>) = smol::channel::bounded(uris.len());
+        for uri in uris {
+            let mut request = Request::builder().uri(*uri).body(Bytes::new()).unwrap();
+            request.extensions_mut().insert(HttpResponseSender::new(response_sender.clone()));
+            request_sender.try_send(request).unwrap();
+        }
+        world.spawn((
+            HttpConnectionTask::new(pool.spawn(async { Ok(()) }), request_receiver),
+            HttpClientAddress::Unix(None),
+            HttpServerName(String::from(HttpServerName::DEFAULT)),
+        ));
+        return (world, response_receiver);
+    }
+
+    // Answers the requests for uris right away
+    fn respond(uris: &[&str]) -> Vec<Response<Bytes>> {
+        let (mut world, response_receiver) = test_world(uris);
+        http_request_responder(&mut world);
+        return uris.iter().map(|_| response_receiver.try_recv().unwrap()).collect();
+    }
+
+    #[test]
+    fn server_middleware() {
+        let responses = respond(&["/game", "/missing", "/denied"]);
+        assert_eq!(responses[0].status(), StatusCode::OK);
+        assert_eq!(responses[0].headers().get("X-Handler").unwrap(), "inner");
+        assert_eq!(responses[0].headers().get("X-Server").unwrap(), "yes");
+        // Requests no handler was found for are wrapped too
+        assert_eq!(responses[1].status(), StatusCode::NOT_FOUND);
+        assert_eq!(responses[1].headers().get("X-Server").unwrap(), "yes");
+        assert_eq!(responses[2].status(), StatusCode::FORBIDDEN);
+        assert_eq!(responses[2].headers().get("X-Server").unwrap(), "yes");
+        assert_eq!(responses[2].headers().contains_key("X-Handler"), false);
+    }
+
+    #[test]
+    fn deferred_skips_after() {
+        let (mut world, response_receiver) = test_world(&["/later"]);
+        http_request_responder(&mut world);
+        assert_eq!(response_receiver.is_empty(), true); // Left for the sender
+
+        let sender = world.query::<&HttpResponseSender>().single(&world).clone();
+        assert_eq!(sender.send(Response::new(Bytes::from_static(b"done"))), true);
+        let response = response_receiver.try_recv().unwrap();
+        assert_eq!(response.headers().contains_key("X-Server"), false); // Sent as given
+        assert_eq!(response.into_body(), Bytes::from_static(b"done"));
+    }
+
+}
@@ -0,0 +1,29 @@
+
+use bevy::prelude::*;
+
+use http::Response;
+use bytes::Bytes;
+
+use crate::HttpPendingRequest;
+use crate::HttpResponse;
+
+
+// Used with HttpRequestMode::Entities to send the HttpResponse components
+// inserted by other systems, and to clean up requests that have timed out
+pub fn http_response_collector(
+    mut query: Query<(Entity, &HttpPendingRequest, Option<&mut HttpResponse>)>,
+    mut commands: Commands,
+) {
+    for (entity, pending, response) in query.iter_mut() {
+        match response {
+            Some(mut response) => {
+                let response = std::mem::replace(&mut response.0, Response::new(Bytes::new()));
+                pending.sender().send(response);
+            }
+            None => {
+                if !pending.sender().is_expired() { continue; }
+            }
+        }
+        commands.entity(entity).despawn();
+    }
+}
@@ -0,0 +1,317 @@
+
+use std::time::Instant;
+
+use bevy::prelude::*;
+use bevy::app::AppExit;
+
+use crate::HttpBindAddress;
+use crate::HttpErrorPolicy;
+use crate::HttpListener;
+use crate::HttpServerCommand;
+use crate::HttpServerControl;
+use crate::HttpServerError;
+use crate::HttpServerInstance;
+use crate::HttpServerResource;
+use crate::HttpServerState;
+
+
+// Carries out the commands queued in HttpServerControl and those sent as
+// HttpServerCommand events, in that order. Also binds again after a failure
+// once the backoff of HttpErrorPolicy::Retry has passed.
+pub fn http_server_control(
+    mut events: EventReader<HttpServerCommand>,
+    mut control: ResMut<HttpServerControl>,
+    mut servers: ResMut<HttpServerResource>,
+    mut errors: EventWriter<HttpServerError>,
+    mut exit: EventWriter<AppExit>,
+) {
+    let mut commands = Vec::<HttpServerCommand>::new();
+    for server in servers.iter() {
+        if control.retry_at(server.name()).map_or(false, |retry_at| Instant::now() >= retry_at) {
+            control.set_retry_at(server.name(), None);
+            commands.push(HttpServerCommand::Start(String::from(server.name())));
+        }
+    }
+    commands.extend(control.take_commands());
+    commands.extend(events.iter().cloned());
+
+    for command in commands {
+        match command {
+            HttpServerCommand::Start(name) => {
+                let server = match find_server(&mut servers, &name) { None => continue, Some(server) => server };
+                if server.is_listening() { continue; }
+                let bind_addresses = control.bind_addresses(&name).cloned().unwrap_or_default();
+                bind_listeners(server, bind_addresses, &mut control, &mut errors, &mut exit);
+            }
+            HttpServerCommand::Stop(name) => {
+                let server = match find_server(&mut servers, &name) { None => continue, Some(server) => server };
+                stop_server(server, &mut control);
+            }
+            HttpServerCommand::Shutdown => {
+                for server in servers.iter_mut() {
+                    stop_server(server, &mut control);
+                }
+            }
+            HttpServerCommand::Rebind(name, bind_addresses) => {
+                let server = match find_server(&mut servers, &name) { None => continue, Some(server) => server };
+                if server.is_listening() {
+                    // Keep the old addresses along with the old listeners if binding fails
+                    if bind_listeners(server, bind_addresses.clone(), &mut control, &mut errors, &mut exit) {
+                        control.set_bind_addresses(&name, bind_addresses);
+                    }
+                    continue;
+                }
+                control.set_bind_addresses(&name, bind_addresses.clone());
+                if control.state(&name) == Some(&HttpServerState::Stopped) { continue; }
+                bind_listeners(server, bind_addresses, &mut control, &mut errors, &mut exit);
+            }
+        }
+    }
+}
+
+
+// Helper function for http_server_control()
+fn find_server<'a>(servers: &'a mut HttpServerResource, name: &str) -> Option<&'a mut HttpServerInstance> {
+    let server = servers.get_mut(name);
+    if server.is_none() { warn!("no http server named {:?}", name); }
+    return server;
+}
+
+
+// Helper function for http_server_control()
+fn stop_server(server: &mut HttpServerInstance, control: &mut HttpServerControl) {
+    if server.is_listening() { info!("http server {:?} shutting down", server.name()); }
+    server.shutdown();
+    control.set_state(server.name(), HttpServerState::Stopped);
+    control.set_retry_at(server.name(), None);
+}
+
+
+// Helper function for http_server_control(), binds all addresses or none.
+// A server already listening keeps the listeners of the addresses still asked
+// for, so a rebind can add an address next to one in use. The listeners no
+// longer asked for are closed once every new address is bound, a failure
+// leaves the listeners and the state as they were.
+fn bind_listeners(
+    server: &mut HttpServerInstance,
+    bind_addresses: Vec<HttpBindAddress>,
+    control: &mut HttpServerControl,
+    errors: &mut EventWriter<HttpServerError>,
+    exit: &mut EventWriter<AppExit>,
+) -> bool {
+    let name = String::from(server.name());
+    if bind_addresses.is_empty() {
+        warn!("http server {:?} has no addresses to listen on", name);
+        return false;
+    }
+
+    // Each address is either one of the current listeners, by index, or a new listener
+    let current: Vec<Option<HttpBindAddress>> = server.listeners().iter().map(|listener| listener.local_addr().ok()).collect();
+    let mut kept = Vec::<Option<usize>>::new();
+    let mut bound = Vec::<Option<HttpListener>>::new();
+    for bind_address in bind_addresses.iter() {
+        let existing = (0..current.len()).find(|index| current[*index].as_ref() == Some(bind_address) && !kept.contains(&Some(*index)));
+        if existing.is_some() {
+            kept.push(existing);
+            bound.push(None);
+            continue;
+        }
+        let listener = match HttpListener::bind(bind_address) {
+            Err(os_error) => {
+                let error = HttpServerError::BindFailed { server: name.clone(), address: bind_address.clone(), message: format!("{}", os_error) };
+                warn!("{}", error);
+                errors.send(error);
+                if server.is_listening() { return false; } // Still serving on the old listeners
+                control.set_state(&name, HttpServerState::Failed(format!("{}", os_error)));
+                match server.settings().error_policy() {
+                    HttpErrorPolicy::Retry { backoff } => control.set_retry_at(&name, Some(Instant::now() + backoff)),
+                    HttpErrorPolicy::Continue => {}
+                    HttpErrorPolicy::Exit => exit.send(AppExit),
+                }
+                return false;
+            }
+            Ok(listener) => listener,
+        };
+        info!("http server {:?} listening on {}", name, listener.local_addr().unwrap_or(bind_address.clone()));
+        kept.push(None);
+        bound.push(Some(listener));
+    }
+
+    let mut old: Vec<Option<HttpListener>> = server.take_listeners().into_iter().map(Some).collect();
+    let mut listeners = Vec::<HttpListener>::new();
+    let mut local_addrs = Vec::<HttpBindAddress>::new();
+    for ((bind_address, existing), new) in bind_addresses.into_iter().zip(kept).zip(bound) {
+        let listener = match existing {
+            Some(index) => old[index].take().unwrap(),
+            None => new.unwrap(),
+        };
+        local_addrs.push(listener.local_addr().unwrap_or(bind_address));
+        listeners.push(listener);
+    }
+    drop(old); // Closes the listeners no longer asked for
+    control.set_retry_at(&name, None);
+    server.start(listeners);
+    control.set_state(&name, HttpServerState::Listening(local_addrs));
+    return true;
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use bevy::ecs::schedule::ExecutorKind;
+
+    use crate::HttpRequestHandler;
+
+    use super::*;
+
+    fn run_system<Params>(world: &mut World, system: impl IntoSystemConfig<Params>) {
+        let mut schedule = Schedule::new();
+        schedule.set_executor_kind(ExecutorKind::SingleThreaded);
+        schedule.add_system(system);
+        schedule.run(world);
+    }
+
+    fn any_port() -> HttpBindAddress {
+        return "127.0.0.1:0".parse().unwrap();
+    }
+
+    fn test_world() -> World {
+        let mut world = World::new();
+        let mut servers = HttpServerResource::new();
+        servers.insert(HttpServerInstance::new("public", HttpRequestHandler::dir("/")));
+        world.insert_resource(servers);
+        let mut control = HttpServerControl::new();
+        control.add("public", vec![any_port()]);
+        world.insert_resource(control);
+        world.init_resource::<Events<HttpServerCommand>>();
+        world.init_resource::<Events<HttpServerError>>();
+        world.init_resource::<Events<AppExit>>();
+        return world;
+    }
+
+    fn send(world: &mut World, command: HttpServerCommand) {
+        world.resource_mut::<Events<HttpServerCommand>>().send(command);
+        run_system(world, http_server_control);
+    }
+
+    fn server(world: &World) -> &HttpServerInstance {
+        return world.resource::<HttpServerResource>().get("public").unwrap();
+    }
+
+    fn error_count(world: &World) -> usize {
+        return world.resource::<Events<HttpServerError>>().len();
+    }
+
+    #[test]
+    fn start() {
+        let mut world = test_world();
+        send(&mut world, HttpServerCommand::Start(String::from("public")));
+        assert_eq!(server(&world).is_listening(), true);
+        let local_addrs = world.resource::<HttpServerControl>().local_addrs("public").unwrap().clone();
+        assert_eq!(local_addrs.len(), 1);
+        assert_ne!(local_addrs[0], any_port());
+        assert_eq!(error_count(&world), 0);
+    }
+
+    #[test]
+    fn stop() {
+        let mut world = test_world();
+        send(&mut world, HttpServerCommand::Start(String::from("public")));
+        let shutdown_signal = server(&world).shutdown_signal();
+        send(&mut world, HttpServerCommand::Stop(String::from("public")));
+        assert_eq!(server(&world).is_listening(), false);
+        assert_eq!(server(&world).is_shutting_down(), true);
+        assert_eq!(shutdown_signal.is_closed(), true);
+        assert_eq!(world.resource::<HttpServerControl>().state("public"), Some(&HttpServerState::Stopped));
+    }
+
+    #[test]
+    fn rebind() {
+        let mut world = test_world();
+        send(&mut world, HttpServerCommand::Start(String::from("public")));
+        let shutdown_signal = server(&world).shutdown_signal();
+        let old_addrs = world.resource::<HttpServerControl>().local_addrs("public").unwrap().clone();
+        send(&mut world, HttpServerCommand::Rebind(String::from("public"), vec![any_port(), any_port()]));
+        let control = world.resource::<HttpServerControl>();
+        assert_eq!(control.bind_addresses("public"), Some(&vec![any_port(), any_port()]));
+        let new_addrs = control.local_addrs("public").unwrap();
+        assert_eq!(new_addrs.len(), 2);
+        assert_eq!(new_addrs.contains(&old_addrs[0]), false);
+        // Connections already accepted are not affected
+        assert_eq!(shutdown_signal.is_closed(), false);
+        assert_eq!(server(&world).is_shutting_down(), false);
+    }
+
+    #[test]
+    fn rebind_keeps_address() {
+        let mut world = test_world();
+        send(&mut world, HttpServerCommand::Start(String::from("public")));
+        let shutdown_signal = server(&world).shutdown_signal();
+        let current_addr = world.resource::<HttpServerControl>().local_addrs("public").unwrap()[0].clone();
+        send(&mut world, HttpServerCommand::Rebind(String::from("public"), vec![current_addr.clone(), any_port()]));
+        assert_eq!(error_count(&world), 0);
+        let control = world.resource::<HttpServerControl>();
+        assert_eq!(control.bind_addresses("public"), Some(&vec![current_addr.clone(), any_port()]));
+        let new_addrs = control.local_addrs("public").unwrap();
+        assert_eq!(new_addrs.len(), 2);
+        assert_eq!(new_addrs[0], current_addr);
+        assert_ne!(new_addrs[1], any_port());
+        assert_eq!(server(&world).listeners().len(), 2);
+        assert_eq!(shutdown_signal.is_closed(), false);
+
+        // Dropping the other address closes its listener
+        let dropped_addr = new_addrs[1].clone();
+        send(&mut world, HttpServerCommand::Rebind(String::from("public"), vec![current_addr.clone()]));
+        assert_eq!(error_count(&world), 0);
+        assert_eq!(world.resource::<HttpServerControl>().local_addrs("public"), Some(&vec![current_addr]));
+        assert_eq!(HttpListener::bind(&dropped_addr).is_ok(), true);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rebind_keeps_unix_socket() {
+        let path = std::env::temp_dir().join(format!("bevy_httpserver_rebind_unix_{}.sock", std::process::id()));
+        let mut world = test_world();
+        send(&mut world, HttpServerCommand::Rebind(String::from("public"), vec![HttpBindAddress::unix(&path)]));
+        send(&mut world, HttpServerCommand::Start(String::from("public")));
+        send(&mut world, HttpServerCommand::Rebind(String::from("public"), vec![HttpBindAddress::unix(&path), any_port()]));
+        assert_eq!(error_count(&world), 0);
+        assert_eq!(server(&world).listeners().len(), 2);
+        assert_eq!(std::os::unix::net::UnixStream::connect(&path).is_ok(), true);
+        send(&mut world, HttpServerCommand::Stop(String::from("public")));
+        assert_eq!(path.exists(), false);
+    }
+
+    #[test]
+    fn rebind_failed() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let taken_addr = HttpBindAddress::from(taken.local_addr().unwrap());
+        let mut world = test_world();
+        send(&mut world, HttpServerCommand::Start(String::from("public")));
+        let shutdown_signal = server(&world).shutdown_signal();
+        let old_addrs = world.resource::<HttpServerControl>().local_addrs("public").unwrap().clone();
+        send(&mut world, HttpServerCommand::Rebind(String::from("public"), vec![any_port(), taken_addr]));
+        assert_eq!(error_count(&world), 1);
+        assert_eq!(server(&world).is_listening(), true);
+        assert_eq!(shutdown_signal.is_closed(), false);
+        let control = world.resource::<HttpServerControl>();
+        assert_eq!(control.local_addrs("public"), Some(&old_addrs));
+        assert_eq!(control.bind_addresses("public"), Some(&vec![any_port()]));
+        assert_eq!(control.retry_at("public"), None);
+    }
+
+    #[test]
+    fn start_failed() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let taken_addr = HttpBindAddress::from(taken.local_addr().unwrap());
+        let mut world = test_world();
+        send(&mut world, HttpServerCommand::Rebind(String::from("public"), vec![taken_addr]));
+        send(&mut world, HttpServerCommand::Start(String::from("public")));
+        assert_eq!(error_count(&world), 1);
+        assert_eq!(server(&world).is_listening(), false);
+        let failed = matches!(world.resource::<HttpServerControl>().state("public"), Some(HttpServerState::Failed(_)));
+        assert_eq!(failed, true);
+    }
+
+}
@@ -0,0 +1,231 @@
+
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use bevy::app::AppExit;
+use bevy::ecs::system::SystemState;
+
+use http::StatusCode;
+
+use crate::HttpClientAddress;
+use crate::HttpConnectionTask;
+use crate::HttpPendingRequest;
+use crate::HttpRequestMode;
+use crate::HttpServerControl;
+use crate::HttpServerError;
+use crate::HttpServerName;
+use crate::HttpServerResource;
+use crate::HttpServerState;
+use crate::http_request_handler::status_response;
+
+use super::http_connection_status;
+use super::http_request_responder;
+
+
+// Starts shutting down every server on AppExit, and drops the connections of
+// a server still open once its grace period has passed (also after
+// HttpServerCommand::Stop, see http_server_control). There are no more frames
+// after AppExit, so in that case the requests in flight are answered and the
+// connections drained right here.
+pub fn http_server_shutdown(
+    world: &mut World,
+    events: &mut SystemState<EventReader<AppExit>>,
+) {
+    let exiting = events.get_mut(world).iter().count() > 0;
+
+    if exiting {
+        world.resource_scope(|world, mut servers: Mut<HttpServerResource>| {
+            let mut control = world.resource_mut::<HttpServerControl>();
+            for server in servers.iter_mut() {
+                if !server.is_listening() { continue; }
+                info!("http server {:?} shutting down", server.name());
+                server.shutdown();
+                control.set_state(server.name(), HttpServerState::Stopped);
+            }
+        });
+        let deadline = world.resource::<HttpServerResource>().iter()
+            .filter_map(|server| server.shutdown_deadline())
+            .max()
+            .unwrap_or(Instant::now());
+        drain_connections(world, deadline);
+        drop_connections(world, |_name| true);
+        return;
+    }
+
+    let now = Instant::now();
+    let expired: Vec<String> = world.resource::<HttpServerResource>().iter()
+        .filter(|server| server.shutdown_deadline().map_or(false, |deadline| now >= deadline))
+        .map(|server| String::from(server.name()))
+        .collect();
+    if expired.is_empty() { return; }
+    drop_connections(world, |name| expired.iter().any(|expired| expired == name));
+}
+
+
+// Helper function for http_server_shutdown()
+fn drain_connections(world: &mut World, deadline: Instant) {
+    let mut status: SystemState<(
+        Query<(Entity, &mut HttpConnectionTask, &HttpClientAddress, &HttpServerName)>,
+        EventWriter<HttpServerError>,
+        Commands,
+    )> = SystemState::new(world);
+
+    loop {
+        // Requests to servers using HttpRequestMode::Handlers are answered as usual
+        http_request_responder(world);
+        refuse_requests(world);
+
+        let (query, errors, commands) = status.get_mut(world);
+        http_connection_status(query, errors, commands);
+        status.apply(world);
+
+        if world.query::<&HttpConnectionTask>().iter(world).count() == 0 { break; }
+        if Instant::now() >= deadline { break; }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+
+// Helper function for drain_connections(), the systems that would answer
+// requests to servers using HttpRequestMode::Entities will not run again
+fn refuse_requests(world: &mut World) {
+    for pending in world.query::<&HttpPendingRequest>().iter(world) {
+        pending.sender().send(status_response(StatusCode::SERVICE_UNAVAILABLE));
+    }
+    world.resource_scope(|world, servers: Mut<HttpServerResource>| {
+        for (mut conntask, name) in world.query::<(&mut HttpConnectionTask, &HttpServerName)>().iter_mut(world) {
+            if !conntask.has_request() { continue; }
+            let request_mode = servers.get(&name.0).map(|server| server.request_mode());
+            if request_mode == Some(HttpRequestMode::Handlers) { continue; }
+            let _request = conntask.take_request();
+            conntask.set_response(Some(status_response(StatusCode::SERVICE_UNAVAILABLE)));
+        }
+    });
+}
+
+
+// Helper function for http_server_shutdown(), dropping a Task cancels it
+fn drop_connections(world: &mut World, filter: impl Fn(&str) -> bool) {
+    let entities: Vec<Entity> = world.query_filtered::<(Entity, &HttpServerName), With<HttpConnectionTask>>()
+        .iter(world)
+        .filter(|(_, name)| filter(&name.0))
+        .map(|(entity, _)| entity)
+        .collect();
+    for entity in entities {
+        warn!("HttpConnectionTask dropped after shutdown grace period");
+        world.despawn(entity);
+    }
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+
+    use crate::HttpBindAddress;
+    use crate::HttpPathParams;
+    use crate::HttpRequestHandler;
+    use crate::HttpServerCommand;
+    use crate::HttpServerPlugin;
+    use crate::HttpServerSettings;
+
+    use super::*;
+    use http::{Request, Response};
+    use bytes::Bytes;
+
+    fn ok(_world: &mut World, _request: &Request<Bytes>, _params: &HttpPathParams) -> Result<Response<Bytes>, StatusCode> {
+        return Ok(Response::new(Bytes::from_static(b"ok")));
+    }
+
+    fn test_app(settings: HttpServerSettings) -> (App, SocketAddr) {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.add_plugin(HttpServerPlugin::new("127.0.0.1:0".parse().unwrap(), HttpRequestHandler::new("/", ok)).with_settings(settings));
+        app.update();
+        let address = match &app.world.resource::<HttpServerControl>().local_addrs("default").unwrap()[0] {
+            HttpBindAddress::Tcp(address) => *address,
+            _ => panic!("expected a tcp address"),
+        };
+        return (app, address);
+    }
+
+    fn connection_count(app: &mut App) -> usize {
+        return app.world.query::<&HttpConnectionTask>().iter(&app.world).count();
+    }
+
+    // Waits for the HttpConnectionServer to pick up what the client wrote
+    fn update(app: &mut App) {
+        std::thread::sleep(Duration::from_millis(20));
+        app.update();
+    }
+
+    #[test]
+    fn app_exit() {
+        let (mut app, address) = test_app(HttpServerSettings::new());
+        let mut client = TcpStream::connect(address).unwrap();
+        update(&mut app);
+        assert_eq!(connection_count(&mut app), 1);
+
+        // Nothing is answered before AppExit, the request is in flight then
+        client.write_all(b"GET / HTTP/1.1\r\nConnection: keep-alive\r\n\r\n").unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        app.world.send_event(AppExit);
+        app.update();
+
+        assert_eq!(connection_count(&mut app), 0);
+        assert_eq!(app.world.resource::<HttpServerResource>().get("default").unwrap().is_listening(), false);
+        assert_eq!(app.world.resource::<HttpServerControl>().state("default"), Some(&HttpServerState::Stopped));
+        assert_eq!(TcpStream::connect(address).is_err(), true);
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert_eq!(response.starts_with("HTTP/1.1 200 OK\r\n"), true);
+        assert_eq!(response.ends_with("\r\n\r\nok"), true);
+    }
+
+    #[test]
+    fn shutdown_command() {
+        let (mut app, address) = test_app(HttpServerSettings::new());
+        let mut client = TcpStream::connect(address).unwrap();
+        update(&mut app);
+        assert_eq!(connection_count(&mut app), 1);
+
+        app.world.send_event(HttpServerCommand::Shutdown);
+        app.update();
+        assert_eq!(app.world.resource::<HttpServerResource>().get("default").unwrap().is_listening(), false);
+        assert_eq!(TcpStream::connect(address).is_err(), true);
+
+        // The idle keep-alive connection closes by itself
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let mut buffer = Vec::new();
+        assert_eq!(client.read_to_end(&mut buffer).unwrap(), 0);
+        update(&mut app);
+        assert_eq!(connection_count(&mut app), 0);
+    }
+
+    #[test]
+    fn shutdown_grace_period() {
+        let settings = HttpServerSettings::new()
+            .with_header_timeout(Duration::from_secs(10))
+            .with_shutdown_grace_period(Duration::from_millis(50));
+        let (mut app, address) = test_app(settings);
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\nHost: loc").unwrap();
+        update(&mut app);
+        assert_eq!(connection_count(&mut app), 1);
+
+        // Still reading the request, so only dropped after the grace period
+        app.world.send_event(HttpServerCommand::Shutdown);
+        update(&mut app);
+        assert_eq!(connection_count(&mut app), 1);
+        std::thread::sleep(Duration::from_millis(60));
+        app.update();
+        assert_eq!(connection_count(&mut app), 0);
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let mut buffer = Vec::new();
+        assert_eq!(client.read_to_end(&mut buffer).unwrap_or(0), 0);
+    }
+
+}
@@ -0,0 +1,23 @@
+
+use bevy::prelude::*;
+
+use crate::HttpSseChannels;
+use crate::HttpSseEvent;
+
+
+// Publishes every Bevy event of type E to a Server-Sent Event channel,
+// converted with From<&E> for HttpSseEvent:
+//
+//    app.add_system(http_sse_forward::<ScoreChanged>("score"));
+pub fn http_sse_forward<E>(channel: &str) -> impl FnMut(EventReader<E>, ResMut<HttpSseChannels>)
+where
+    E: Event,
+    for<'a> HttpSseEvent: From<&'a E>,
+{
+    let channel = String::from(channel);
+    return move |mut events: EventReader<E>, mut channels: ResMut<HttpSseChannels>| {
+        for event in events.iter() {
+            channels.publish(&channel, HttpSseEvent::from(event));
+        }
+    };
+}
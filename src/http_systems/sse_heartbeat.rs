@@ -0,0 +1,15 @@
+
+use std::time::Instant;
+
+use bevy::prelude::*;
+
+use crate::HttpSseChannels;
+
+
+// Sends a comment line to idle Server-Sent Event streams so proxies keep them
+// open, and forgets subscribers that have disconnected
+pub fn http_sse_heartbeat(
+    mut channels: ResMut<HttpSseChannels>,
+) {
+    channels.heartbeat(Instant::now());
+}
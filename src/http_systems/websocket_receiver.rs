@@ -0,0 +1,35 @@
+
+use bevy::prelude::*;
+use smol::channel::TryRecvError;
+
+use crate::HttpWebSocket;
+use crate::WsClosed;
+use crate::WsMessage;
+use crate::WsMessageReceived;
+
+
+// Sends the messages received on each HttpWebSocket as WsMessageReceived
+// events, and despawns sockets that have closed after sending WsClosed
+pub fn http_websocket_receiver(
+    query: Query<(Entity, &HttpWebSocket)>,
+    mut received: EventWriter<WsMessageReceived>,
+    mut closed: EventWriter<WsClosed>,
+    mut commands: Commands,
+) {
+    for (entity, socket) in query.iter() {
+        loop {
+            let (code, reason) = match socket.try_recv() {
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Closed) => (1006, String::new()), // Connection dropped without a close
+                Ok(WsMessage::Close(code, reason)) => (code, reason),
+                Ok(message) => {
+                    received.send(WsMessageReceived { entity, message });
+                    continue;
+                }
+            };
+            closed.send(WsClosed { entity, code, reason });
+            commands.entity(entity).despawn();
+            break;
+        }
+    }
+}
@@ -0,0 +1,23 @@
+
+use bevy::prelude::*;
+
+use crate::HttpWebSocket;
+use crate::WsSend;
+
+
+// Passes WsSend events to their HttpWebSocket. Messages for sockets that are
+// gone are dropped, as are messages for clients that are not keeping up.
+pub fn http_websocket_sender(
+    query: Query<&HttpWebSocket>,
+    mut events: EventReader<WsSend>,
+) {
+    for event in events.iter() {
+        let socket = match query.get(event.entity) {
+            Err(_) => continue,
+            Ok(socket) => socket,
+        };
+        if !socket.send(event.message.clone()) && !socket.is_closed() {
+            warn!("WebSocket {:?} is not keeping up, message dropped", event.entity);
+        }
+    }
+}
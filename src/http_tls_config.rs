@@ -0,0 +1,172 @@
+
+// Certificate and private key for HTTPS, requires the "tls" feature:
+//
+//    let tls = HttpTlsConfig::from_pem_files("cert.pem", "key.pem").unwrap();
+//    app.add_plugin(HttpServerPlugin::new(addr, root).with_tls(tls.clone()));
+//
+// HttpTlsConfig is a shared handle, so a clone kept by the App can swap in a
+// renewed certificate without restarting the server. New connections use the
+// new certificate, connections already established keep the old one.
+
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use futures_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+
+
+#[derive(Clone)]
+pub struct HttpTlsConfig {
+    server_config: Arc<RwLock<Arc<ServerConfig>>>,
+}
+
+
+impl HttpTlsConfig {
+
+    // PEM encoded certificate chain (leaf first) and private key
+    pub fn from_pem(cert_chain: &[u8], private_key: &[u8]) -> std::io::Result<Self> {
+        let server_config = server_config(cert_chain, private_key)?;
+        return Ok(HttpTlsConfig {
+            server_config: Arc::new(RwLock::new(Arc::new(server_config))),
+        });
+    }
+
+    pub fn from_pem_files(cert_chain: impl AsRef<Path>, private_key: impl AsRef<Path>) -> std::io::Result<Self> {
+        return HttpTlsConfig::from_pem(&std::fs::read(cert_chain)?, &std::fs::read(private_key)?);
+    }
+
+    // Replace the certificate, e.g. after renewal. On error the old one is kept.
+    pub fn reload_pem(&self, cert_chain: &[u8], private_key: &[u8]) -> std::io::Result<()> {
+        let server_config = server_config(cert_chain, private_key)?;
+        *self.server_config.write().unwrap() = Arc::new(server_config);
+        return Ok(());
+    }
+
+    pub fn reload_pem_files(&self, cert_chain: impl AsRef<Path>, private_key: impl AsRef<Path>) -> std::io::Result<()> {
+        return self.reload_pem(&std::fs::read(cert_chain)?, &std::fs::read(private_key)?);
+    }
+
+    // The configuration used for the next connection
+    pub fn server_config(&self) -> Arc<ServerConfig> {
+        return self.server_config.read().unwrap().clone();
+    }
+
+}
+
+
+impl std::fmt::Debug for HttpTlsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HttpTlsConfig").finish_non_exhaustive()
+    }
+}
+
+
+// Helper function for from_pem() and reload_pem()
+fn server_config(cert_chain: &[u8], private_key: &[u8]) -> std::io::Result<ServerConfig> {
+    let cert_chain: Vec<Certificate> = rustls_pemfile::certs(&mut &cert_chain[..])?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if cert_chain.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, "no certificate found in PEM data"));
+    }
+
+    let mut key = None;
+    for item in rustls_pemfile::read_all(&mut &private_key[..])? {
+        match item {
+            rustls_pemfile::Item::RSAKey(der) => key = Some(PrivateKey(der)),
+            rustls_pemfile::Item::PKCS8Key(der) => key = Some(PrivateKey(der)),
+            rustls_pemfile::Item::ECKey(der) => key = Some(PrivateKey(der)),
+            _ => continue,
+        }
+        break;
+    }
+    let key = match key {
+        None => return Err(Error::new(ErrorKind::InvalidData, "no private key found in PEM data")),
+        Some(key) => key,
+    };
+
+    let mut server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, key)
+        .map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    return Ok(server_config);
+}
+
+
+// Convenience function for testing, returns a self-signed certificate for
+// "localhost" and a client configuration that trusts it
+#[cfg(test)]
+pub fn self_signed() -> (HttpTlsConfig, futures_rustls::rustls::ClientConfig) {
+    let (cert_pem, key_pem, cert_der) = self_signed_pem();
+    let mut roots = futures_rustls::rustls::RootCertStore::empty();
+    roots.add(&Certificate(cert_der)).unwrap();
+    let client_config = futures_rustls::rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    return (HttpTlsConfig::from_pem(cert_pem.as_bytes(), key_pem.as_bytes()).unwrap(), client_config);
+}
+
+
+// Helper function for self_signed(), returns the certificate as PEM and DER
+#[cfg(test)]
+fn self_signed_pem() -> (String, String, Vec<u8>) {
+    let cert = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+    return (cert.serialize_pem().unwrap(), cert.serialize_private_key_pem(), cert.serialize_der().unwrap());
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_pem() {
+        let (cert_pem, key_pem, _) = self_signed_pem();
+        let tls = HttpTlsConfig::from_pem(cert_pem.as_bytes(), key_pem.as_bytes()).unwrap();
+        assert_eq!(tls.server_config().alpn_protocols, vec![b"http/1.1".to_vec()]);
+    }
+
+    #[test]
+    fn from_pem_no_certificate() {
+        let (_, key_pem, _) = self_signed_pem();
+        let error = HttpTlsConfig::from_pem(key_pem.as_bytes(), key_pem.as_bytes()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn from_pem_no_key() {
+        let (cert_pem, _, _) = self_signed_pem();
+        let error = HttpTlsConfig::from_pem(cert_pem.as_bytes(), cert_pem.as_bytes()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn from_pem_files_missing() {
+        let error = HttpTlsConfig::from_pem_files("no/such/cert.pem", "no/such/key.pem").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn reload_shared() {
+        let (tls, _) = self_signed();
+        let clone = tls.clone();
+        let before = clone.server_config();
+        let (cert_pem, key_pem, _) = self_signed_pem();
+        tls.reload_pem(cert_pem.as_bytes(), key_pem.as_bytes()).unwrap();
+        assert_eq!(Arc::ptr_eq(&before, &clone.server_config()), false);
+    }
+
+    #[test]
+    fn reload_error_keeps_old() {
+        let (tls, _) = self_signed();
+        let before = tls.server_config();
+        assert_eq!(tls.reload_pem(b"", b"").is_err(), true);
+        assert_eq!(Arc::ptr_eq(&before, &tls.server_config()), true);
+    }
+
+}
@@ -0,0 +1,231 @@
+
+/*
+WebSocket routes are added with HttpRequestHandler::websocket(). Every socket
+accepted there is spawned as an entity with an HttpWebSocket component and the
+HttpClientAddress of the client. Messages arrive as WsMessageReceived events
+and are sent with WsSend events:
+
+    fn echo(mut received: EventReader<WsMessageReceived>, mut send: EventWriter<WsSend>) {
+        for event in received.iter() {
+            send.send(WsSend { entity: event.entity, message: event.message.clone() });
+        }
+    }
+
+Pings and pongs are handled by HttpWebSocketServer. When a socket closes, from
+either side, a WsClosed event is sent and the entity is despawned. Send
+WsMessage::Close to close a socket from the App; despawning the entity closes
+it with 1001 Going Away.
+
+A handler can call HttpWebSocket::upgrade() itself instead, e.g. to check
+credentials first or to add components of its own to the entity. The
+request must have been answered with the returned 101 Switching Protocols
+response for HttpConnectionServer to hand the connection over.
+
+See also: HttpWebSocketServer
+*/
+
+use bevy::prelude::*;
+use smol::channel::{Receiver, Sender, TryRecvError};
+
+use http::{Request, Response, StatusCode, Method, HeaderMap, Version};
+use bytes::Bytes;
+
+use super::HttpClientAddress;
+use super::WsMessage;
+use super::http_websocket_protocol;
+
+
+// Messages queued in each direction before a socket applies backpressure
+const WS_CHANNEL_CAPACITY: usize = 64;
+
+
+#[derive(Component)]
+pub struct HttpWebSocket {
+    path: String,
+    outgoing: Sender<WsMessage>,
+    incoming: Receiver<WsMessage>,
+}
+
+
+// Placed in the extensions of the 101 Switching Protocols response, the ends
+// of the channels used by HttpWebSocketServer
+pub(crate) struct HttpWebSocketUpgrade {
+    pub incoming: Sender<WsMessage>,
+    pub outgoing: Receiver<WsMessage>,
+}
+
+
+impl HttpWebSocket {
+
+    // Accept a WebSocket handshake and spawn the socket entity, Err if the
+    // request is not a valid handshake
+    pub fn upgrade(world: &mut World, request: &Request<Bytes>) -> Result<(Response<Bytes>, Entity), StatusCode> {
+        let (response, socket) = HttpWebSocket::accept(request)?;
+        let mut entity = world.spawn(socket);
+        if let Some(peer) = request.extensions().get::<HttpClientAddress>() {
+            entity.insert(peer.clone());
+        }
+        return Ok((response, entity.id()));
+    }
+
+    // Same as upgrade(), but the caller spawns the component,
+    // e.g. with Commands from a system handler
+    pub fn accept(request: &Request<Bytes>) -> Result<(Response<Bytes>, Self), StatusCode> {
+        let key = HttpWebSocket::validate(request)?;
+        let (incoming_sender, incoming) = smol::channel::bounded(WS_CHANNEL_CAPACITY);
+        let (outgoing, outgoing_receiver) = smol::channel::bounded(WS_CHANNEL_CAPACITY);
+        let mut response = Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header("Upgrade", "websocket")
+            .header("Connection", "Upgrade")
+            .header("Sec-WebSocket-Accept", http_websocket_protocol::accept_key(&key))
+            .body(Bytes::new())
+            .unwrap();
+        response.extensions_mut().insert(HttpWebSocketUpgrade { incoming: incoming_sender, outgoing: outgoing_receiver });
+        let socket = HttpWebSocket {
+            path: String::from(request.uri().path()),
+            outgoing,
+            incoming,
+        };
+        return Ok((response, socket));
+    }
+
+    // True if the client asks to upgrade to a WebSocket
+    pub fn is_upgrade(request: &Request<Bytes>) -> bool {
+        return has_token(request.headers(), "Upgrade", "websocket");
+    }
+
+    // Used by HttpConnectionServer to take over the connection
+    pub(crate) fn take_upgrade(response: &mut Response<Bytes>) -> Option<HttpWebSocketUpgrade> {
+        return response.extensions_mut().remove::<HttpWebSocketUpgrade>();
+    }
+
+    // Path of the handshake request
+    pub fn path(&self) -> &str {
+        return &self.path;
+    }
+
+    // Queue a message without waiting. Returns false if the socket is closed
+    // or the client is not keeping up.
+    pub fn send(&self, message: WsMessage) -> bool {
+        return self.outgoing.try_send(message).is_ok();
+    }
+
+    // True once the connection is gone
+    pub fn is_closed(&self) -> bool {
+        return self.outgoing.is_closed();
+    }
+
+    // Used by http_websocket_receiver
+    pub(crate) fn try_recv(&self) -> Result<WsMessage, TryRecvError> {
+        return self.incoming.try_recv();
+    }
+
+    // Returns the Sec-WebSocket-Key of a valid handshake. Clients asking for
+    // another protocol version get 426 Upgrade Required.
+    fn validate(request: &Request<Bytes>) -> Result<String, StatusCode> {
+        if request.method() != Method::GET { return Err(StatusCode::METHOD_NOT_ALLOWED); }
+        if request.version() < Version::HTTP_11 { return Err(StatusCode::BAD_REQUEST); }
+        if !HttpWebSocket::is_upgrade(request) { return Err(StatusCode::UPGRADE_REQUIRED); }
+        if !has_token(request.headers(), "Connection", "upgrade") { return Err(StatusCode::BAD_REQUEST); }
+        match request.headers().get("Sec-WebSocket-Version").and_then(|value| value.to_str().ok()) {
+            Some(version) if version.trim() == "13" => {}
+            _ => return Err(StatusCode::UPGRADE_REQUIRED),
+        }
+        let key = request.headers().get("Sec-WebSocket-Key")
+            .and_then(|value| value.to_str().ok())
+            .ok_or(StatusCode::BAD_REQUEST)?;
+        if !http_websocket_protocol::is_valid_key(key) { return Err(StatusCode::BAD_REQUEST); }
+        return Ok(String::from(key.trim()));
+    }
+
+}
+
+
+// Helper function for HttpWebSocket::validate(), headers may list several
+// comma separated tokens in any case, e.g. "Connection: keep-alive, Upgrade"
+fn has_token(headers: &HeaderMap, name: &str, token: &str) -> bool {
+    return headers.get_all(name).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token));
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    fn handshake() -> Request<Bytes> {
+        return Request::builder()
+            .uri("/chat")
+            .header("Host", "localhost")
+            .header("Upgrade", "websocket")
+            .header("Connection", "keep-alive, Upgrade")
+            .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .header("Sec-WebSocket-Version", "13")
+            .body(Bytes::new())
+            .unwrap();
+    }
+
+    #[test]
+    fn upgrade() {
+        let mut world = World::new();
+        let mut request = handshake();
+        request.extensions_mut().insert(HttpClientAddress::Unix(None));
+        let (mut response, entity) = HttpWebSocket::upgrade(&mut world, &request).unwrap();
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(response.headers().get("Sec-WebSocket-Accept").unwrap(), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(world.get::<HttpWebSocket>(entity).unwrap().path(), "/chat");
+        assert_eq!(world.get::<HttpClientAddress>(entity), Some(&HttpClientAddress::Unix(None)));
+        assert_eq!(HttpWebSocket::take_upgrade(&mut response).is_some(), true);
+        assert_eq!(HttpWebSocket::take_upgrade(&mut response).is_some(), false);
+    }
+
+    #[test]
+    fn channels() {
+        let request = handshake();
+        let (mut response, socket) = HttpWebSocket::accept(&request).unwrap();
+        let upgrade = HttpWebSocket::take_upgrade(&mut response).unwrap();
+        assert_eq!(socket.send(WsMessage::text("out")), true);
+        assert_eq!(upgrade.outgoing.try_recv(), Ok(WsMessage::text("out")));
+        assert_eq!(upgrade.incoming.try_send(WsMessage::text("in")).is_ok(), true);
+        assert_eq!(socket.try_recv(), Ok(WsMessage::text("in")));
+        assert_eq!(socket.is_closed(), false);
+        drop(upgrade);
+        assert_eq!(socket.is_closed(), true);
+        assert_eq!(socket.send(WsMessage::text("out")), false);
+    }
+
+    #[test]
+    fn not_upgrade() {
+        let request = Request::builder().uri("/chat").body(Bytes::new()).unwrap();
+        assert_eq!(HttpWebSocket::is_upgrade(&request), false);
+        assert_eq!(HttpWebSocket::accept(&request).err(), Some(StatusCode::UPGRADE_REQUIRED));
+    }
+
+    #[test]
+    fn invalid_handshakes() {
+        let mut request = handshake();
+        *request.method_mut() = Method::POST;
+        assert_eq!(HttpWebSocket::accept(&request).err(), Some(StatusCode::METHOD_NOT_ALLOWED));
+        let mut request = handshake();
+        *request.version_mut() = Version::HTTP_10;
+        assert_eq!(HttpWebSocket::accept(&request).err(), Some(StatusCode::BAD_REQUEST));
+        let mut request = handshake();
+        request.headers_mut().insert("Connection", HeaderValue::from_static("keep-alive"));
+        assert_eq!(HttpWebSocket::accept(&request).err(), Some(StatusCode::BAD_REQUEST));
+        let mut request = handshake();
+        request.headers_mut().insert("Sec-WebSocket-Version", HeaderValue::from_static("8"));
+        assert_eq!(HttpWebSocket::accept(&request).err(), Some(StatusCode::UPGRADE_REQUIRED));
+        let mut request = handshake();
+        request.headers_mut().insert("Sec-WebSocket-Key", HeaderValue::from_static("short"));
+        assert_eq!(HttpWebSocket::accept(&request).err(), Some(StatusCode::BAD_REQUEST));
+        let mut request = handshake();
+        request.headers_mut().remove("Sec-WebSocket-Key");
+        assert_eq!(HttpWebSocket::accept(&request).err(), Some(StatusCode::BAD_REQUEST));
+    }
+
+}
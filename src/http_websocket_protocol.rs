@@ -0,0 +1,374 @@
+
+// Async reading and writing of WebSocket frames (RFC 6455) for
+// HttpWebSocketServer, this code only covers what the server side needs:
+// client frames must be masked, server frames are not, no extensions.
+
+use base64::Engine;
+use smol::io::{AsyncBufRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use bytes::Bytes;
+
+use super::WsMessage;
+
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+// Close codes, see RFC 6455 section 7.4.1
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_NO_STATUS: u16 = 1005; // Never sent, reported when a close frame has no code
+pub const CLOSE_ABNORMAL: u16 = 1006; // Never sent, reported when the connection was lost
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+
+// Sec-WebSocket-Accept header value answering a Sec-WebSocket-Key
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(key.trim().as_bytes());
+    sha1.update(b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
+    return base64::engine::general_purpose::STANDARD.encode(sha1.digest().bytes());
+}
+
+
+// True if a Sec-WebSocket-Key is a base64 encoded 16 byte nonce
+pub fn is_valid_key(key: &str) -> bool {
+    match base64::engine::general_purpose::STANDARD.decode(key.trim()) {
+        Ok(nonce) => return nonce.len() == 16,
+        Err(_) => return false,
+    }
+}
+
+
+// What the client sent; data messages are only returned once complete
+#[derive(Debug, PartialEq, Eq)]
+pub enum WsFrame {
+    Message(WsMessage),
+    Ping(Bytes),
+    Pong(Bytes),
+    Close(u16, String),
+}
+
+
+// Reads frames from the client, putting fragmented messages back together.
+// Control frames may arrive between the fragments of a message.
+pub struct WsDecoder {
+    partial: Option<(u8, Vec<u8>)>,
+    max_message_bytes: usize,
+}
+
+
+impl WsDecoder {
+
+    pub fn new(max_message_bytes: usize) -> Self {
+        WsDecoder {
+            partial: None,
+            max_message_bytes,
+        }
+    }
+
+    // Ok(None) means the connection was lost, Err(code) means the client broke
+    // the protocol and should get a close frame with that code
+    pub async fn next<R>(&mut self, reader: &mut R) -> Result<Option<WsFrame>, u16>
+    where
+        R: AsyncBufRead + Unpin
+    {
+        loop {
+            let mut head = [0; 2];
+            if reader.read_exact(&mut head).await.is_err() { return Ok(None); }
+            let fin = head[0] & 0x80 != 0;
+            let opcode = head[0] & 0x0F;
+            if head[0] & 0x70 != 0 { return Err(CLOSE_PROTOCOL_ERROR); } // No extensions were negotiated
+            if head[1] & 0x80 == 0 { return Err(CLOSE_PROTOCOL_ERROR); } // Client frames must be masked
+            let length = match head[1] & 0x7F {
+                126 => {
+                    let mut length = [0; 2];
+                    if reader.read_exact(&mut length).await.is_err() { return Ok(None); }
+                    u16::from_be_bytes(length) as u64
+                }
+                127 => {
+                    let mut length = [0; 8];
+                    if reader.read_exact(&mut length).await.is_err() { return Ok(None); }
+                    u64::from_be_bytes(length)
+                }
+                length => length as u64,
+            };
+            let control = opcode & 0x08 != 0;
+            if control && (!fin || length > 125) { return Err(CLOSE_PROTOCOL_ERROR); }
+            let buffered = self.partial.as_ref().map_or(0, |(_, data)| data.len());
+            if !control && length > (self.max_message_bytes - buffered) as u64 { return Err(CLOSE_TOO_BIG); }
+
+            let mut mask = [0; 4];
+            if reader.read_exact(&mut mask).await.is_err() { return Ok(None); }
+            let mut payload = vec![0; length as usize];
+            if reader.read_exact(&mut payload).await.is_err() { return Ok(None); }
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+
+            match opcode {
+                OPCODE_PING => return Ok(Some(WsFrame::Ping(Bytes::from(payload)))),
+                OPCODE_PONG => return Ok(Some(WsFrame::Pong(Bytes::from(payload)))),
+                OPCODE_CLOSE => return parse_close(payload).map(|(code, reason)| Some(WsFrame::Close(code, reason))),
+                OPCODE_TEXT | OPCODE_BINARY => {
+                    if self.partial.is_some() { return Err(CLOSE_PROTOCOL_ERROR); } // Previous message unfinished
+                    if fin { return parse_message(opcode, payload).map(|message| Some(WsFrame::Message(message))); }
+                    self.partial = Some((opcode, payload));
+                }
+                OPCODE_CONTINUATION => {
+                    match self.partial.as_mut() {
+                        None => return Err(CLOSE_PROTOCOL_ERROR), // Nothing to continue
+                        Some((_, data)) => data.extend_from_slice(&payload),
+                    }
+                    if fin {
+                        let (opcode, data) = self.partial.take().unwrap();
+                        return parse_message(opcode, data).map(|message| Some(WsFrame::Message(message)));
+                    }
+                }
+                _ => return Err(CLOSE_PROTOCOL_ERROR),
+            }
+        }
+    }
+
+}
+
+
+// Write a text, binary or close message as a single frame
+pub async fn write_message<W>(message: &WsMessage, writer: &mut W) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin
+{
+    match message {
+        WsMessage::Text(text) => return write_frame(OPCODE_TEXT, text.as_bytes(), writer).await,
+        WsMessage::Binary(data) => return write_frame(OPCODE_BINARY, data, writer).await,
+        WsMessage::Close(code, reason) => return write_close(*code, reason, writer).await,
+    }
+}
+
+
+pub async fn write_ping<W>(payload: &[u8], writer: &mut W) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin
+{
+    return write_frame(OPCODE_PING, payload, writer).await;
+}
+
+
+pub async fn write_pong<W>(payload: &[u8], writer: &mut W) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin
+{
+    return write_frame(OPCODE_PONG, payload, writer).await;
+}
+
+
+// The reason is cut short to fit in a control frame
+pub async fn write_close<W>(code: u16, reason: &str, writer: &mut W) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin
+{
+    if code == CLOSE_NO_STATUS || code == CLOSE_ABNORMAL { return write_frame(OPCODE_CLOSE, b"", writer).await; }
+    let mut end = reason.len().min(123);
+    while !reason.is_char_boundary(end) { end -= 1; }
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(&reason.as_bytes()[..end]);
+    return write_frame(OPCODE_CLOSE, &payload, writer).await;
+}
+
+
+async fn write_frame<W>(opcode: u8, payload: &[u8], writer: &mut W) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin
+{
+    let mut head = vec![0x80 | opcode];
+    if payload.len() < 126 {
+        head.push(payload.len() as u8);
+    } else if payload.len() <= 0xFFFF {
+        head.push(126);
+        head.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+        head.push(127);
+        head.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+    writer.write_all(&head).await?;
+    writer.write_all(payload).await?;
+    writer.flush().await?;
+    return Ok(());
+}
+
+
+fn parse_message(opcode: u8, payload: Vec<u8>) -> Result<WsMessage, u16> {
+    if opcode == OPCODE_BINARY { return Ok(WsMessage::Binary(Bytes::from(payload))); }
+    return String::from_utf8(payload).map(WsMessage::Text).map_err(|_| CLOSE_INVALID_DATA);
+}
+
+
+fn parse_close(payload: Vec<u8>) -> Result<(u16, String), u16> {
+    match payload.len() {
+        0 => return Ok((CLOSE_NO_STATUS, String::new())),
+        1 => return Err(CLOSE_PROTOCOL_ERROR),
+        _ => {
+            let code = u16::from_be_bytes([payload[0], payload[1]]);
+            let reason = String::from_utf8(payload[2..].to_vec()).map_err(|_| CLOSE_INVALID_DATA)?;
+            return Ok((code, reason));
+        }
+    }
+}
+
+
+// Client side counterpart, for testing
+#[cfg(test)]
+pub async fn write_masked_frame<W>(fin: bool, opcode: u8, payload: &[u8], writer: &mut W) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin
+{
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    let mut frame = vec![if fin { 0x80 | opcode } else { opcode }];
+    if payload.len() < 126 {
+        frame.push(0x80 | payload.len() as u8);
+    } else {
+        frame.push(0x80 | 126);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    }
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+    writer.write_all(&frame).await?;
+    writer.flush().await?;
+    return Ok(());
+}
+
+
+// Client side counterpart, for testing. Server frames are not masked.
+#[cfg(test)]
+pub async fn read_unmasked_frame<R>(reader: &mut R) -> std::io::Result<(u8, Vec<u8>)>
+where
+    R: AsyncBufRead + Unpin
+{
+    let mut head = [0; 2];
+    reader.read_exact(&mut head).await?;
+    let length = match head[1] & 0x7F {
+        126 => {
+            let mut length = [0; 2];
+            reader.read_exact(&mut length).await?;
+            u16::from_be_bytes(length) as usize
+        }
+        length => length as usize,
+    };
+    let mut payload = vec![0; length];
+    reader.read_exact(&mut payload).await?;
+    return Ok((head[0] & 0x0F, payload));
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use smol::future;
+    use smol::io::{BufReader, Cursor};
+
+    use super::*;
+
+    fn decode(frames: Vec<u8>, max_message_bytes: usize) -> Vec<Result<Option<WsFrame>, u16>> {
+        let mut reader = BufReader::new(Cursor::new(frames));
+        let mut decoder = WsDecoder::new(max_message_bytes);
+        let mut decoded = Vec::new();
+        loop {
+            let frame = future::block_on(decoder.next(&mut reader));
+            let last = !matches!(frame, Ok(Some(_)));
+            decoded.push(frame);
+            if last { return decoded; }
+        }
+    }
+
+    fn masked(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut writer = Cursor::new(Vec::<u8>::new());
+        future::block_on(write_masked_frame(fin, opcode, payload, &mut writer)).unwrap();
+        return writer.into_inner();
+    }
+
+    #[test]
+    fn accept_key() {
+        // Example from RFC 6455 section 1.3
+        assert_eq!(super::accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(is_valid_key("dGhlIHNhbXBsZSBub25jZQ=="), true);
+        assert_eq!(is_valid_key("dGhlIHNhbXBsZQ=="), false);
+    }
+
+    #[test]
+    fn read_text_and_binary() {
+        let mut frames = masked(true, OPCODE_TEXT, b"hello");
+        frames.extend(masked(true, OPCODE_BINARY, &[0, 1, 2]));
+        let decoded = decode(frames, 1024);
+        assert_eq!(decoded[0], Ok(Some(WsFrame::Message(WsMessage::text("hello")))));
+        assert_eq!(decoded[1], Ok(Some(WsFrame::Message(WsMessage::binary(&[0, 1, 2])))));
+        assert_eq!(decoded[2], Ok(None));
+    }
+
+    #[test]
+    fn read_fragmented() {
+        let mut frames = masked(false, OPCODE_TEXT, b"hel");
+        frames.extend(masked(true, OPCODE_PING, b"p"));
+        frames.extend(masked(true, OPCODE_CONTINUATION, b"lo"));
+        let decoded = decode(frames, 1024);
+        assert_eq!(decoded[0], Ok(Some(WsFrame::Ping(Bytes::from_static(b"p")))));
+        assert_eq!(decoded[1], Ok(Some(WsFrame::Message(WsMessage::text("hello")))));
+    }
+
+    #[test]
+    fn read_long() {
+        let payload = vec![7; 300];
+        let decoded = decode(masked(true, OPCODE_BINARY, &payload), 1024);
+        assert_eq!(decoded[0], Ok(Some(WsFrame::Message(WsMessage::binary(&payload)))));
+    }
+
+    #[test]
+    fn read_close() {
+        let decoded = decode(masked(true, OPCODE_CLOSE, b"\x03\xE8bye"), 1024);
+        assert_eq!(decoded[0], Ok(Some(WsFrame::Close(1000, String::from("bye")))));
+        let decoded = decode(masked(true, OPCODE_CLOSE, b""), 1024);
+        assert_eq!(decoded[0], Ok(Some(WsFrame::Close(CLOSE_NO_STATUS, String::new()))));
+    }
+
+    #[test]
+    fn read_protocol_errors() {
+        let unmasked = vec![0x81, 0x02, b'h', b'i'];
+        assert_eq!(decode(unmasked, 1024)[0], Err(CLOSE_PROTOCOL_ERROR));
+        assert_eq!(decode(masked(true, OPCODE_CONTINUATION, b"x"), 1024)[0], Err(CLOSE_PROTOCOL_ERROR));
+        assert_eq!(decode(masked(false, OPCODE_PING, b"x"), 1024)[0], Err(CLOSE_PROTOCOL_ERROR));
+        assert_eq!(decode(masked(true, 0x3, b"x"), 1024)[0], Err(CLOSE_PROTOCOL_ERROR));
+        assert_eq!(decode(masked(true, OPCODE_TEXT, &[0xFF]), 1024)[0], Err(CLOSE_INVALID_DATA));
+    }
+
+    #[test]
+    fn read_too_big() {
+        assert_eq!(decode(masked(true, OPCODE_BINARY, &[0; 5]), 4)[0], Err(CLOSE_TOO_BIG));
+        let mut frames = masked(false, OPCODE_BINARY, &[0; 3]);
+        frames.extend(masked(true, OPCODE_CONTINUATION, &[0; 2]));
+        assert_eq!(decode(frames, 4)[0], Err(CLOSE_TOO_BIG));
+    }
+
+    #[test]
+    fn write_frames() {
+        let mut writer = Cursor::new(Vec::<u8>::new());
+        future::block_on(write_message(&WsMessage::text("hi"), &mut writer)).unwrap();
+        future::block_on(write_message(&WsMessage::Close(1001, String::from("bye")), &mut writer)).unwrap();
+        future::block_on(write_pong(b"", &mut writer)).unwrap();
+        assert_eq!(writer.into_inner(), b"\x81\x02hi\x88\x05\x03\xE9bye\x8A\x00".to_vec());
+    }
+
+    #[test]
+    fn write_long() {
+        let mut writer = Cursor::new(Vec::<u8>::new());
+        future::block_on(write_message(&WsMessage::binary(&[1; 200]), &mut writer)).unwrap();
+        let mut reader = BufReader::new(Cursor::new(writer.into_inner()));
+        let (opcode, payload) = future::block_on(read_unmasked_frame(&mut reader)).unwrap();
+        assert_eq!(opcode, OPCODE_BINARY);
+        assert_eq!(payload, vec![1; 200]);
+    }
+
+}
@@ -0,0 +1,298 @@
+
+/*
+An HttpWebSocketServer takes over a connection from HttpConnectionServer once a
+WebSocket handshake has been answered with 101 Switching Protocols, see
+HttpWebSocket. When .run() is awaited it reads and writes at the same time...
+    1. frames from the client are put back together into messages and passed
+       to the HttpWebSocket, pings are answered with pongs
+    2. messages from the App are written as they arrive, and the client is
+       pinged whenever nothing was written for ws_ping_interval. A client that
+       has not answered by the next ping is disconnected.
+    3. the closing handshake is started by either side, or by the server when
+       the HttpWebSocket is despawned or the server shuts down (1001 Going
+       Away), or when the client breaks the protocol (1002, 1007 or 1009)
+
+Once one side has closed, the other gets write_timeout to finish the closing
+handshake. The last message passed to the HttpWebSocket is always
+WsMessage::Close, with code 1006 if the connection was lost.
+
+See also: HttpConnectionServer, HttpWebSocket
+*/
+
+use std::io::ErrorKind;
+
+use smol::channel::{Receiver, RecvError, Sender};
+use smol::io::{AsyncBufRead, AsyncWrite};
+use smol::{future, Timer};
+
+use bytes::Bytes;
+
+use super::HttpClientConnection;
+use super::HttpConnectionError;
+use super::HttpServerSettings;
+use super::WsMessage;
+use super::http_connection_server::{timeout, wait_for_shutdown};
+use super::http_websocket::HttpWebSocketUpgrade;
+use super::http_websocket_protocol;
+use super::http_websocket_protocol::{WsDecoder, WsFrame, CLOSE_ABNORMAL, CLOSE_GOING_AWAY};
+
+
+// Pongs and close replies queued for the writing half
+const WS_CONTROL_CAPACITY: usize = 16;
+
+
+// Frames the reading half needs the writing half to act on
+enum WsControl {
+    Pong(Bytes),
+    PongReceived,
+    Close(u16),
+}
+
+
+// Whichever comes first in write_frames()
+enum WsWriteEvent {
+    Control(Result<WsControl, RecvError>),
+    Outgoing(Result<WsMessage, RecvError>),
+    Ping,
+    Shutdown,
+}
+
+
+// Whichever half finishes first in HttpWebSocketServer::run()
+enum WsHalf {
+    Read((u16, String)),
+    Wrote(std::io::Result<()>),
+}
+
+
+pub(crate) struct HttpWebSocketServer {
+    upgrade: HttpWebSocketUpgrade,
+    settings: HttpServerSettings,
+    shutdown: Option<Receiver<()>>,
+}
+
+
+impl HttpWebSocketServer {
+
+    pub fn new(upgrade: HttpWebSocketUpgrade, settings: HttpServerSettings, shutdown: Option<Receiver<()>>) -> Self {
+        HttpWebSocketServer {
+            upgrade,
+            settings,
+            shutdown,
+        }
+    }
+
+    // Serve the socket until it closes. Closing the connection is up to the caller.
+    pub async fn run(&mut self, connection: &mut HttpClientConnection) -> Result<(), HttpConnectionError> {
+        let settings = self.settings;
+        let peer = connection.peer();
+        let ((code, reason), written) = {
+            let (reader, writer) = connection.split();
+            let (control_sender, control_receiver) = smol::channel::bounded(WS_CONTROL_CAPACITY);
+            let decoder = WsDecoder::new(settings.max_ws_message_bytes());
+            let reading = read_frames(reader, decoder, &self.upgrade.incoming, control_sender);
+            let writing = write_frames(writer, &self.upgrade.outgoing, control_receiver, settings, self.shutdown.clone());
+            smol::pin!(reading);
+            smol::pin!(writing);
+            let first = future::or(
+                async { WsHalf::Read((&mut reading).await) },
+                async { WsHalf::Wrote((&mut writing).await) },
+            ).await;
+            match first {
+                WsHalf::Read(closed) => {
+                    let written = timeout(settings.write_timeout(), writing).await;
+                    (closed, written.unwrap_or(Err(std::io::Error::from(ErrorKind::TimedOut))))
+                }
+                WsHalf::Wrote(Err(os_error)) => ((CLOSE_ABNORMAL, String::new()), Err(os_error)),
+                WsHalf::Wrote(Ok(())) => {
+                    let closed = timeout(settings.write_timeout(), reading).await;
+                    (closed.unwrap_or((CLOSE_ABNORMAL, String::new())), Ok(()))
+                }
+            }
+        };
+
+        // The HttpWebSocket may already be despawned, or not keeping up
+        let _ = timeout(settings.write_timeout(), self.upgrade.incoming.send(WsMessage::Close(code, reason))).await;
+
+        match written {
+            Err(os_error) => {
+                if os_error.kind() == ErrorKind::ConnectionAborted { return Ok(()); } // Connection closed by peer
+                if os_error.kind() == ErrorKind::ConnectionReset { return Ok(()); }
+                if os_error.kind() == ErrorKind::BrokenPipe { return Ok(()); }
+                if os_error.kind() == ErrorKind::TimedOut { return Err(HttpConnectionError::Failed(format!("{}: WebSocket timed out", peer))); }
+                return Err(HttpConnectionError::Failed(format!("WebSocket write returned {}", os_error)));
+            }
+            Ok(()) => return Ok(()),
+        }
+    }
+
+}
+
+
+// Helper function for HttpWebSocketServer::run(), returns the close code and
+// reason once the client has sent a close frame, broken the protocol or gone away
+async fn read_frames<R>(reader: &mut R, mut decoder: WsDecoder, incoming: &Sender<WsMessage>, control: Sender<WsControl>) -> (u16, String)
+where
+    R: AsyncBufRead + Unpin
+{
+    loop {
+        let frame = match decoder.next(reader).await {
+            Ok(None) => return (CLOSE_ABNORMAL, String::new()),
+            Err(code) => {
+                let _ = control.send(WsControl::Close(code)).await;
+                return (code, String::new());
+            }
+            Ok(Some(frame)) => frame,
+        };
+        // Send errors mean the other end is done, the closing handshake takes care of it
+        match frame {
+            WsFrame::Message(message) => { let _ = incoming.send(message).await; }
+            WsFrame::Ping(payload) => { let _ = control.send(WsControl::Pong(payload)).await; }
+            WsFrame::Pong(_) => { let _ = control.send(WsControl::PongReceived).await; }
+            WsFrame::Close(code, reason) => {
+                let _ = control.send(WsControl::Close(code)).await;
+                return (code, reason);
+            }
+        }
+    }
+}
+
+
+// Helper function for HttpWebSocketServer::run(), returns once a close frame
+// has been written or the reading half is done. Every write must finish
+// within write_timeout.
+async fn write_frames<W>(writer: &mut W, outgoing: &Receiver<WsMessage>, control: Receiver<WsControl>, settings: HttpServerSettings, shutdown: Option<Receiver<()>>) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin
+{
+    let write_timeout = settings.write_timeout();
+    let mut awaiting_pong = false;
+    loop {
+        let shutdown = shutdown.clone();
+        let next = future::or(
+            future::or(
+                async { WsWriteEvent::Control(control.recv().await) },
+                async { WsWriteEvent::Outgoing(outgoing.recv().await) },
+            ),
+            future::or(
+                async { Timer::after(settings.ws_ping_interval()).await; WsWriteEvent::Ping },
+                async { wait_for_shutdown(shutdown).await; WsWriteEvent::Shutdown },
+            ),
+        ).await;
+        match next {
+            WsWriteEvent::Control(Err(_)) => return Ok(()), // Reading half is done
+            WsWriteEvent::Control(Ok(WsControl::PongReceived)) => awaiting_pong = false,
+            WsWriteEvent::Control(Ok(WsControl::Pong(payload))) => {
+                written(timeout(write_timeout, http_websocket_protocol::write_pong(&payload, writer)).await)?;
+            }
+            WsWriteEvent::Control(Ok(WsControl::Close(code))) => {
+                return written(timeout(write_timeout, http_websocket_protocol::write_close(code, "", writer)).await);
+            }
+            WsWriteEvent::Outgoing(Ok(message)) => {
+                written(timeout(write_timeout, http_websocket_protocol::write_message(&message, writer)).await)?;
+                if message.is_close() { return Ok(()); }
+            }
+            WsWriteEvent::Outgoing(Err(_)) | WsWriteEvent::Shutdown => {
+                // The HttpWebSocket was despawned, or the server is shutting down
+                return written(timeout(write_timeout, http_websocket_protocol::write_close(CLOSE_GOING_AWAY, "", writer)).await);
+            }
+            WsWriteEvent::Ping => {
+                if awaiting_pong { return Err(std::io::Error::from(ErrorKind::TimedOut)); }
+                written(timeout(write_timeout, http_websocket_protocol::write_ping(b"", writer)).await)?;
+                awaiting_pong = true;
+            }
+        }
+    }
+}
+
+
+// Helper function for write_frames(), a write that took longer than
+// write_timeout is an error
+fn written(result: Option<std::io::Result<()>>) -> std::io::Result<()> {
+    match result {
+        None => return Err(std::io::Error::from(ErrorKind::TimedOut)),
+        Some(result) => return result,
+    }
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::http_websocket_protocol::{read_unmasked_frame, write_masked_frame};
+
+    fn serve(settings: HttpServerSettings) -> (HttpClientConnection, Sender<WsMessage>, Receiver<WsMessage>, thread::JoinHandle<Result<(), HttpConnectionError>>) {
+        let (server, mut client) = HttpClientConnection::loopback().unwrap();
+        let (incoming_sender, incoming) = smol::channel::bounded(16);
+        let (outgoing, outgoing_receiver) = smol::channel::bounded(16);
+        let upgrade = HttpWebSocketUpgrade { incoming: incoming_sender, outgoing: outgoing_receiver };
+        let handle = thread::spawn(move || future::block_on(HttpWebSocketServer::new(upgrade, settings, None).run(&mut client)));
+        return (server, outgoing, incoming, handle);
+    }
+
+    #[test]
+    fn echo_and_client_close() {
+        let (mut server, outgoing, incoming, handle) = serve(HttpServerSettings::new());
+        future::block_on(write_masked_frame(true, 0x1, b"hello", server.writer())).unwrap();
+        assert_eq!(future::block_on(incoming.recv()), Ok(WsMessage::text("hello")));
+        assert_eq!(outgoing.try_send(WsMessage::binary(b"world")).is_ok(), true);
+        assert_eq!(future::block_on(read_unmasked_frame(server.reader())).unwrap(), (0x2, b"world".to_vec()));
+        future::block_on(write_masked_frame(true, 0x9, b"p", server.writer())).unwrap();
+        assert_eq!(future::block_on(read_unmasked_frame(server.reader())).unwrap(), (0xA, b"p".to_vec()));
+        future::block_on(write_masked_frame(true, 0x8, b"\x03\xE8bye", server.writer())).unwrap();
+        assert_eq!(future::block_on(read_unmasked_frame(server.reader())).unwrap(), (0x8, b"\x03\xE8".to_vec()));
+        assert_eq!(future::block_on(incoming.recv()), Ok(WsMessage::Close(1000, String::from("bye"))));
+        assert_eq!(handle.join().unwrap().is_ok(), true);
+    }
+
+    #[test]
+    fn server_close() {
+        let (mut server, outgoing, incoming, handle) = serve(HttpServerSettings::new());
+        assert_eq!(outgoing.try_send(WsMessage::Close(4000, String::from("done"))).is_ok(), true);
+        assert_eq!(future::block_on(read_unmasked_frame(server.reader())).unwrap(), (0x8, b"\x0F\xA0done".to_vec()));
+        future::block_on(write_masked_frame(true, 0x8, b"\x0F\xA0", server.writer())).unwrap();
+        assert_eq!(future::block_on(incoming.recv()), Ok(WsMessage::Close(4000, String::new())));
+        assert_eq!(handle.join().unwrap().is_ok(), true);
+    }
+
+    #[test]
+    fn socket_despawned() {
+        let (mut server, outgoing, incoming, handle) = serve(HttpServerSettings::new());
+        drop(outgoing);
+        drop(incoming);
+        assert_eq!(future::block_on(read_unmasked_frame(server.reader())).unwrap(), (0x8, b"\x03\xE9".to_vec()));
+        future::block_on(write_masked_frame(true, 0x8, b"\x03\xE9", server.writer())).unwrap();
+        assert_eq!(handle.join().unwrap().is_ok(), true);
+    }
+
+    #[test]
+    fn protocol_error() {
+        let (mut server, _outgoing, incoming, handle) = serve(HttpServerSettings::new().with_max_ws_message_bytes(4));
+        future::block_on(write_masked_frame(true, 0x1, b"too long", server.writer())).unwrap();
+        assert_eq!(future::block_on(read_unmasked_frame(server.reader())).unwrap(), (0x8, b"\x03\xF1".to_vec()));
+        assert_eq!(future::block_on(incoming.recv()), Ok(WsMessage::Close(1009, String::new())));
+        assert_eq!(handle.join().unwrap().is_ok(), true);
+    }
+
+    #[test]
+    fn client_gone() {
+        let (mut server, _outgoing, incoming, handle) = serve(HttpServerSettings::new());
+        server.close().unwrap();
+        assert_eq!(future::block_on(incoming.recv()), Ok(WsMessage::Close(1006, String::new())));
+        let _ = handle.join().unwrap();
+    }
+
+    #[test]
+    fn ping_unanswered() {
+        let settings = HttpServerSettings::new().with_ws_ping_interval(Duration::from_millis(20));
+        let (mut server, _outgoing, incoming, handle) = serve(settings);
+        assert_eq!(future::block_on(read_unmasked_frame(server.reader())).unwrap(), (0x9, vec![]));
+        assert_eq!(future::block_on(incoming.recv()), Ok(WsMessage::Close(1006, String::new())));
+        assert_eq!(handle.join().unwrap().is_err(), true);
+    }
+
+}
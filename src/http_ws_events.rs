@@ -0,0 +1,32 @@
+
+// ECS events for HttpWebSocket entities. WsMessageReceived and WsClosed are
+// sent by http_websocket_receiver in PreUpdate, WsSend events are forwarded
+// by http_websocket_sender in PostUpdate.
+
+use bevy::prelude::*;
+
+use super::WsMessage;
+
+
+// A text or binary message from the client of a socket
+pub struct WsMessageReceived {
+    pub entity: Entity,
+    pub message: WsMessage,
+}
+
+
+// A message for the client of a socket. WsMessage::Close starts the closing
+// handshake, the socket is despawned once it completes.
+pub struct WsSend {
+    pub entity: Entity,
+    pub message: WsMessage,
+}
+
+
+// Sent once per socket, after which the entity is despawned. The code is the
+// one from the closing handshake, or 1006 if the connection was lost.
+pub struct WsClosed {
+    pub entity: Entity,
+    pub code: u16,
+    pub reason: String,
+}
@@ -0,0 +1,54 @@
+
+// A WebSocket message, received with WsMessageReceived or sent with WsSend.
+// Close ends the socket: received last from every socket, and sent to start
+// the closing handshake from the App. Close codes are listed in RFC 6455
+// section 7.4.1, e.g. 1000 for a normal closure.
+
+use bytes::Bytes;
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WsMessage {
+    Text(String),
+    Binary(Bytes),
+    Close(u16, String),
+}
+
+
+impl WsMessage {
+
+    pub fn text(text: &str) -> Self {
+        return WsMessage::Text(String::from(text));
+    }
+
+    pub fn binary(data: &[u8]) -> Self {
+        return WsMessage::Binary(Bytes::copy_from_slice(data));
+    }
+
+    // Normal closure
+    pub fn close() -> Self {
+        return WsMessage::Close(1000, String::new());
+    }
+
+    pub fn is_close(&self) -> bool {
+        return matches!(self, WsMessage::Close(_, _));
+    }
+
+}
+
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constructors() {
+        assert_eq!(WsMessage::text("hi"), WsMessage::Text(String::from("hi")));
+        assert_eq!(WsMessage::binary(b"hi"), WsMessage::Binary(Bytes::from_static(b"hi")));
+        assert_eq!(WsMessage::close(), WsMessage::Close(1000, String::new()));
+        assert_eq!(WsMessage::close().is_close(), true);
+        assert_eq!(WsMessage::text("hi").is_close(), false);
+    }
+
+}
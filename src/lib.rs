@@ -46,41 +46,377 @@
             )
         ));
 
-    Every handler function must have the same signature:
-    fn(&mut World, &Request<Bytes>) -> Result<Response<Bytes>, StatusCode>
+    A dir_name starting with ":" matches any single non-empty path segment,
+    a dir_name starting with "*" matches the rest of the path and can not
+    have children. Literal dir_names are always tried first:
+
+    HttpRequestHandler::new("/", my_handlers::root)
+        .add_child(HttpRequestHandler::new("players", my_handlers::players)
+            .add_child(HttpRequestHandler::new("me", my_handlers::player_me))
+            .add_child(HttpRequestHandler::new(":id", my_handlers::player)
+                .add_child(HttpRequestHandler::new("inventory", my_handlers::player_inventory))
+            )
+        )
+        .add_child(HttpRequestHandler::new("files", my_handlers::files)
+            .add_child(HttpRequestHandler::new("*path", my_handlers::file))
+        )
+
+    The captured values are percent-decoded and passed to the handler function,
+    e.g. params.get("id") == Some("42") for "/players/42/inventory". A wildcard
+    does not match a ".." segment, an encoded slash or backslash (%2F, %5C) or
+    an empty first segment, so its value can not climb out of a directory it
+    is joined onto.
+
+    Functions can also be registered per method on a single handler. A request
+    using a method with no function gets 405 Method Not Allowed with an Allow
+    header, OPTIONS is answered automatically from the registered methods and
+    HEAD is answered by the GET function unless HEAD has one of its own. The
+    server leaves out the body of the response to HEAD, keeping its headers:
+
+    HttpRequestHandler::dir("/")
+        .add_child(HttpRequestHandler::dir("players")
+            .get(my_handlers::list_players)
+            .post(my_handlers::create_player)
+            .add_child(HttpRequestHandler::dir(":id")
+                .get(my_handlers::get_player)
+                .put(my_handlers::update_player)
+                .delete(my_handlers::delete_player)
+            )
+        )
+
+    Every handler function takes the same arguments, and fails with a
+    StatusCode, an HttpError or anything else convertible into HttpError:
+    fn(&mut World, &Request<Bytes>, &HttpPathParams) -> Result<Response<Bytes>, StatusCode>
+
+    Handlers can fail with an HttpError instead of a bare StatusCode, adding a
+    message and headers to the error response. Common errors such as I/O and
+    parse errors convert into an HttpError with `?`, and with the "serde_json"
+    cargo feature enabled, so do serde_json errors:
+
+    fn player(world: &mut World, request: &Request<Bytes>, params: &HttpPathParams) -> Result<Response<Bytes>, HttpError> {
+        let id: u32 = params.get("id").unwrap().parse()?; // 400 Bad Request
+        let player = my_players::find(world, id).ok_or(HttpError::new(StatusCode::NOT_FOUND).with_message("no such player"))?;
+        ...
+    }
+
+    Breaking change: Handler::call(), IntoResponse::into_response() and
+    HttpRequestHandler::handle() now return Err(HttpError) instead of
+    Err(StatusCode). Handler implementations return Err(status.into()), and
+    callers use error.status(), or compare the HttpError with a StatusCode.
+    Handler functions returning Result<Response<Bytes>, StatusCode> are not
+    affected, but a closure that never returns an error must now name the
+    error type, e.g. Ok::<_, StatusCode>(response), as it can not be inferred.
+
+    Closures with this signature can be used to capture configuration,
+    and any type implementing the Handler trait can be used as a handler:
+
+    let base_dir = PathBuf::from("assets/www");
+    HttpRequestHandler::new("/", my_handlers::root)
+        .add_child(HttpRequestHandler::new("static", move |world: &mut World, request: &Request<Bytes>, params: &HttpPathParams| {
+            my_handlers::serve_file(&base_dir, world, request, params)
+        }))
+        .add_child(HttpRequestHandler::new("scores", ScoreBoard { limit: 10 }))
+
+    Instead of building a SystemState by hand from &mut World, a handler can be
+    written like a Bevy system. The first argument is a request extractor
+    (HttpRequest, HttpPathParams, Request<Bytes> or Bytes), the rest are
+    SystemParams, and the return value is anything implementing IntoResponse.
+    The SystemState is cached per handler and Commands are applied afterwards:
+
+    fn player(request: HttpRequest, players: Query<&Player>, score: Res<Score>) -> impl IntoResponse {
+        // ...
+    }
+
+    HttpRequestHandler::dir("/")
+        .add_child(HttpRequestHandler::dir("players")
+            .add_child(HttpRequestHandler::dir(":id").get(player))
+        )
+
+    Middleware runs before and after the handlers and can answer a request
+    itself, e.g. to check credentials. Middleware added to the plugin wraps
+    every request, middleware added to a handler only wraps its own branch.
+    HttpRequestHandler::respond() applies it when calling the handler tree
+    directly, handle() does not. Middleware is not available with
+    HttpRequestMode::Entities:
+
+    struct RequireToken;
+
+    impl Middleware for RequireToken {
+        fn before(&self, world: &mut World, request: &Request<Bytes>) -> Result<Option<Response<Bytes>>, HttpError> {
+            if my_auth::is_valid(world, request) { return Ok(None); }
+            return Err(HttpError::new(StatusCode::UNAUTHORIZED).with_header("WWW-Authenticate", "Bearer"));
+        }
+    }
+
+    App::new()
+        .add_plugin(HttpServerPlugin::new(addr, HttpRequestHandler::dir("/")
+            .add_child(HttpRequestHandler::dir("admin").with_middleware(RequireToken)
+                .add_child(HttpRequestHandler::dir("players").get(my_handlers::list_players))
+            )
+        ).with_middleware(my_middleware::AccessLog));
+
+    A handler can also leave the request unanswered and let a later system or
+    frame respond, see HttpResponseSender. Middleware after() does not run for
+    such responses. Requests not answered within the response timeout get
+    504 Gateway Timeout:
+
+    App::new()
+        .add_plugin(HttpServerPlugin::new(addr, root).with_response_timeout(Duration::from_secs(5)));
+
+    Large bodies can be streamed chunk by chunk instead, from later systems or
+    a background task, see HttpBodySender. At most `capacity` chunks are queued
+    so a slow client does not cause unbounded buffering:
+
+    fn export(world: &mut World, request: &Request<Bytes>, params: &HttpPathParams) -> Result<Response<Bytes>, StatusCode> {
+        let (response, sender) = HttpBodySender::response(16);
+        world.spawn(sender); // Some other system calls .try_send() until done, then despawns it
+        return Ok(response);
+    }
+
+    Live game state can be pushed to browsers as Server-Sent Events. A route
+    added with HttpRequestHandler::sse() subscribes to a named channel, and any
+    system can publish to it through the HttpSseChannels resource. Clients that
+    reconnect with Last-Event-ID get the recent events they missed:
+
+    HttpRequestHandler::dir("/")
+        .add_child(HttpRequestHandler::sse("chat", "chat"))
+
+    fn chat(mut messages: EventReader<ChatMessage>, mut channels: ResMut<HttpSseChannels>) {
+        for message in messages.iter() {
+            channels.publish("chat", HttpSseEvent::new(&message.text).with_event("message"));
+        }
+    }
+
+    Browsers can also open a WebSocket on a route added with
+    HttpRequestHandler::websocket(). Each socket is an entity with an
+    HttpWebSocket component; messages arrive as WsMessageReceived events and
+    are sent with WsSend events. Pings are answered by the server, and a
+    WsClosed event is sent when the socket closes and its entity is despawned:
+
+    HttpRequestHandler::dir("/")
+        .add_child(HttpRequestHandler::websocket("play"))
+
+    fn play(mut received: EventReader<WsMessageReceived>, mut send: EventWriter<WsSend>) {
+        for event in received.iter() {
+            send.send(WsSend { entity: event.entity, message: WsMessage::text("pong") });
+        }
+    }
+
+    Request bodies are read in full before the handler is called, whether sent
+    with Content-Length or Transfer-Encoding: chunked. Large uploads can be
+    handed to the handler as they arrive instead, see HttpBodyReceiver:
+
+    App::new()
+        .add_plugin(HttpServerPlugin::new(addr, root).with_settings(HttpServerSettings::new()
+            .with_max_body_bytes(256 * 1024 * 1024)
+            .with_stream_body_threshold(64 * 1024)
+        ));
+
+    Timeouts, request size limits and the number of requests per keep-alive
+    connection can be adjusted with HttpServerSettings. Clients that are too
+    slow sending a request, or idle for too long between requests, are
+    disconnected. Requests over the size limits get 413, 414 or 431:
+
+    App::new()
+        .add_plugin(HttpServerPlugin::new(addr, root).with_settings(HttpServerSettings::new()
+            .with_header_timeout(Duration::from_secs(5))
+            .with_idle_timeout(Duration::from_secs(15))
+            .with_max_requests(100)
+            .with_max_body_bytes(64 * 1024)
+        ));
+
+    Clients may pipeline requests on a keep-alive connection, sending several
+    without waiting for the responses. Up to max_pipeline_depth of them are
+    handed to the App at once, possibly handled in the same frame, and the
+    responses are always written in request order:
+
+    App::new()
+        .add_plugin(HttpServerPlugin::new(addr, root).with_settings(HttpServerSettings::new()
+            .with_max_pipeline_depth(16)
+        ));
+
+    The number of open connections can be limited, in total and per client IP.
+    By default, connections over the limit wait in the listen backlog; they can
+    be answered with 503 Service Unavailable instead. At most
+    max_accepts_per_frame connections are accepted in one frame:
+
+    App::new()
+        .add_plugin(HttpServerPlugin::new(addr, root).with_settings(HttpServerSettings::new()
+            .with_max_connections(500)
+            .with_max_connections_per_ip(10)
+            .with_overload_policy(HttpOverloadPolicy::Reject { retry_after: Duration::from_secs(5) })
+        ));
+
+    The server shuts down gracefully on AppExit or when an HttpServerCommand::Shutdown
+    event is sent: the listener is closed, requests in flight are answered with
+    Connection: close, and connections still open after the grace period are dropped:
+
+    App::new()
+        .add_plugin(HttpServerPlugin::new(addr, root).with_settings(HttpServerSettings::new()
+            .with_shutdown_grace_period(Duration::from_secs(2))
+        ));
+
+    The listeners can be started, stopped and moved to other addresses while
+    the App runs, and their current state is available, see HttpServerControl:
+
+    App::new()
+        .add_plugin(HttpServerPlugin::new(addr, root).with_autostart(false))
+        .add_system(my_systems::settings_menu);
+
+    fn settings_menu(mut control: ResMut<HttpServerControl>) {
+        if let Some(HttpServerState::Failed(reason)) = control.state(HttpServerName::DEFAULT) { ... }
+        control.rebind(HttpServerName::DEFAULT, vec!["[::]:8080".parse().unwrap()]);
+        control.start(HttpServerName::DEFAULT);
+    }
+
+    Several independent servers can be added, each with its own name, addresses,
+    handler tree and settings. Connections are tagged with the HttpServerName
+    of the server that accepted them:
+
+    App::new()
+        .add_plugin(HttpServerPlugin::new("[::]:80".parse().unwrap(), public_root)
+            .with_name("public"))
+        .add_plugin(HttpServerPlugin::new("127.0.0.1:8080".parse().unwrap(), admin_root)
+            .with_name("admin")
+            .with_bind_address("[::1]:8080".parse().unwrap()));
+
+    Failures to bind or accept are sent as HttpServerError events rather than
+    crashing the App, and the HttpErrorPolicy decides what happens next.
+    Malformed requests are answered with e.g. 400 Bad Request and reported
+    as HttpServerError::BadRequest with the address of the client:
+
+    App::new()
+        .add_plugin(HttpServerPlugin::new(addr, root).with_settings(HttpServerSettings::new()
+            .with_error_policy(HttpErrorPolicy::Retry { backoff: Duration::from_secs(5) })
+        ))
+        .add_system(my_systems::log_http_errors);
+
+    Alternatively, requests can be spawned as entities and answered by
+    ordinary systems running in parallel, see HttpPendingRequest:
+
+    App::new()
+        .add_plugin(HttpServerPlugin::new(addr, root).with_request_mode(HttpRequestMode::Entities))
+        .add_system(my_systems::answer_requests);
+
+    Tools running on the same machine can connect through a Unix domain socket
+    instead of TCP. The socket file is removed when the listener is closed, and
+    HttpClientAddress::Unix is used for the connections it accepts:
+
+    App::new()
+        .add_plugin(HttpServerPlugin::new(HttpBindAddress::unix("/run/game/http.sock"), root)
+            .with_bind_address("127.0.0.1:8080".parse().unwrap()));
+
+    With the "tls" cargo feature enabled, a server instance can speak HTTPS.
+    Certificates are loaded from PEM files or bytes, and a clone of the
+    HttpTlsConfig can be used to swap in a renewed certificate at runtime:
+
+    let tls = HttpTlsConfig::from_pem_files("cert.pem", "key.pem").unwrap();
+    App::new()
+        .add_plugin(HttpServerPlugin::new("[::]:443".parse().unwrap(), root).with_tls(tls.clone()))
+        .insert_resource(MyCertificates(tls));
+
+    fn renew_certificate(certificates: Res<MyCertificates>) {
+        if let Err(error) = certificates.0.reload_pem_files("cert.pem", "key.pem") { ... }
+    }
 
     The built-in handler used by HttpServerPlugin::default() is shown below.
 
  */
 
 
+// The code base spells out returns, matches and boolean asserts, and Bevy
+// systems take long parameter types
+#![allow(clippy::needless_return, clippy::single_match, clippy::unnecessary_map_or, clippy::type_complexity)]
+#![allow(clippy::bool_assert_comparison, clippy::assertions_on_constants)]
+
 use bevy::prelude::*;
 
-pub use vebb::{Request, Response, StatusCode, Method, HeaderName, HeaderValue, HeaderMap, Uri, Bytes};
+pub use http::{Request, Response, StatusCode, Method, HeaderName, HeaderValue, HeaderMap, Uri};
+pub use bytes::Bytes;
 
 mod http_path;
+mod http_path_params;
+mod http_handler;
+mod http_error;
+mod http_middleware;
+mod http_request;
+mod http_into_response;
+mod http_system_handler;
+mod http_bind_address;
 mod http_client_address;
 mod http_client_connection;
+mod http_listener;
+mod http_protocol;
+mod http_connection_error;
 mod http_connection_server;
 mod http_connection_task;
+mod http_response_sender;
+mod http_body_sender;
+mod http_body_receiver;
+mod http_pending_request;
 mod http_request_handler;
+mod http_server_command;
+mod http_server_control;
+mod http_server_error;
+mod http_server_instance;
+mod http_server_name;
 mod http_server_resource;
+mod http_server_settings;
 mod http_server_plugin;
+mod http_sse_channels;
+mod http_sse_event;
+mod http_sse_handler;
 mod http_systems;
+mod http_websocket;
+mod http_websocket_protocol;
+mod http_websocket_server;
+mod http_ws_events;
+mod http_ws_message;
+#[cfg(feature = "tls")]
+mod http_tls_config;
 
+pub use http_path_params::*;
+pub use http_handler::*;
+pub use http_error::*;
+pub use http_middleware::*;
+pub use http_request::*;
+pub use http_into_response::*;
+pub use http_system_handler::*;
+pub use http_bind_address::*;
 pub use http_client_address::*;
 pub use http_client_connection::*;
+pub use http_listener::*;
+pub use http_connection_error::*;
 pub use http_connection_server::*;
 pub use http_connection_task::*;
+pub use http_response_sender::*;
+pub use http_body_sender::*;
+pub use http_body_receiver::*;
+pub use http_pending_request::*;
 pub use http_request_handler::*;
+pub use http_server_command::*;
+pub use http_server_control::*;
+pub use http_server_error::*;
+pub use http_server_instance::*;
+pub use http_server_name::*;
 pub use http_server_resource::*;
+pub use http_server_settings::*;
 pub use http_server_plugin::*;
+pub use http_sse_channels::*;
+pub use http_sse_event::*;
+pub use http_sse_handler::*;
 pub use http_systems::*;
+pub use http_websocket::*;
+pub use http_ws_events::*;
+pub use http_ws_message::*;
+#[cfg(feature = "tls")]
+pub use http_tls_config::*;
 
 
 pub fn example_handler_fn(
     _world: &mut World, 
-    _request: &Request<Bytes>
+    _request: &Request<Bytes>,
+    _params: &HttpPathParams,
 ) -> Result<Response<Bytes>, StatusCode> {
 
     /*